@@ -0,0 +1,88 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{fs, path::PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ouster_parser::{
+    ouster::CoordinateFrame,
+    sink::{Frame, FrameSink, PcdFormat, PcdSink},
+    uring::UringPcdSink,
+};
+
+/// Frames of an OS1-64 at 1024x10, 1 MiB of points each.
+fn frames() -> Vec<Frame> {
+    (0..20)
+        .map(|id| Frame {
+            id,
+            frame_id: id as u16,
+            timestamp: id as u64 * 100_000_000,
+            end_timestamp: id as u64 * 100_000_000 + 99_000_000,
+            coordinate_frame: CoordinateFrame::Lidar,
+            name: PathBuf::from(format!("{:04}", id)),
+            rgb: false,
+            beam_angles: false,
+            range_difference: false,
+            completeness: 1.0,
+            segment: 0,
+            point_stride: 1,
+            sampling: None,
+            column_timestamps: Vec::new(),
+            excluded_range: 0,
+            excluded_reflectivity: 0,
+            discarded: None,
+            points: (0..64 * 1024 * 4).map(|value| value as f32).collect(),
+        })
+        .collect()
+}
+
+/// Write a burst of frames, a write syscall after another and through
+/// io_uring.
+fn write_frames(c: &mut Criterion) {
+    let root = std::env::temp_dir().join(format!("ouster-parser-bench-{}", std::process::id()));
+    let format = PcdFormat {
+        intensity_name: "intensity".to_string(),
+        comments: true,
+        metadata: Vec::new(),
+        ascii: false,
+    };
+    let frames = frames();
+
+    let write = |sink: &mut dyn FrameSink| {
+        for frame in &frames {
+            sink.write_frame(frame).unwrap();
+        }
+        sink.finish().unwrap();
+    };
+
+    // the sinks are set up once, as for a whole capture
+    let mut std = PcdSink::new(&root.join("std"), 1, format.clone());
+    let mut uring = UringPcdSink::new(&root.join("uring"), 1, format, 32).unwrap();
+
+    let mut group = c.benchmark_group("write_frames");
+    group.sample_size(20);
+    group.bench_function("std", |b| b.iter(|| write(&mut std)));
+    group.bench_function("uring", |b| b.iter(|| write(&mut uring)));
+    group.finish();
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+criterion_group!(benches, write_frames);
+criterion_main!(benches);
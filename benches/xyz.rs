@@ -0,0 +1,51 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{f32::consts::PI, hint::black_box};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use ouster_parser::xyz::{BeamGeometry, ColumnXyz};
+
+/// The points of a column of an OS1-128, one beam at a time and in
+/// vector lanes.
+fn column_xyz(c: &mut Criterion) {
+    let azimuths: Vec<f32> = (0..128)
+        .map(|beam| [4.2, 1.4, -1.4, -4.2][beam % 4])
+        .collect();
+    let altitudes: Vec<f32> = (0..128).map(|beam| 21.0 - 0.33 * beam as f32).collect();
+    let mut beam_to_lidar = [0.0; 16];
+    beam_to_lidar[3] = 15.806;
+
+    let geometry = BeamGeometry::new(&azimuths, &altitudes, &beam_to_lidar, [0.0; 3]);
+    let ranges: Vec<f32> = (0..128).map(|beam| 2000.0 + 157.0 * beam as f32).collect();
+    let encoder = 2.0 * PI * (1.0 - 300.0 / 1024.0);
+    let mut xyz = ColumnXyz::default();
+
+    let mut group = c.benchmark_group("column_xyz");
+    group.bench_function("scalar", |b| {
+        b.iter(|| geometry.column_scalar(black_box(encoder), black_box(&ranges), &mut xyz))
+    });
+    group.bench_function("simd", |b| {
+        b.iter(|| geometry.column(black_box(encoder), black_box(&ranges), &mut xyz))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, column_xyz);
+criterion_main!(benches);
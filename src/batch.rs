@@ -0,0 +1,242 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, prelude::*},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// One row of the job list: `input,meta,port,output,args`.
+///
+/// `port` may be left empty to use the metadata default, and `args` holds
+/// extra options passed through to the job as is, separated by spaces.
+struct Job {
+    input: PathBuf,
+    meta: PathBuf,
+    port: Option<u16>,
+    output: PathBuf,
+    args: Vec<String>,
+}
+
+enum Status {
+    Ok,
+    Failed(String),
+    Skipped,
+}
+
+struct JobResult {
+    status: Status,
+    frames: usize,
+    seconds: f64,
+}
+
+/// Runs every job of a csv job list and reports how each one went.
+///
+/// Each job runs this executable in a child process, so a job that panics
+/// or exits doesn't take the others down. Returns whether all jobs succeeded.
+pub fn run(jobs_path: &Path, parallel: usize, keep_going: bool, report: Option<&Path>) -> bool {
+    let jobs = match read_jobs(jobs_path) {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            eprintln!("Invalid job list. ({})", err);
+            return false;
+        }
+    };
+
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<JobResult>>> =
+        Mutex::new((0..jobs.len()).map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..parallel.max(1) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= jobs.len() || stop.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let result = run_job(&jobs[index]);
+                if let Status::Failed(reason) = &result.status {
+                    eprintln!("Job {} failed. ({})", index + 1, reason);
+                    if !keep_going {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                }
+
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    let results: Vec<JobResult> = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or(JobResult {
+                status: Status::Skipped,
+                frames: 0,
+                seconds: 0.0,
+            })
+        })
+        .collect();
+
+    match report {
+        Some(path) => write_report(File::create(path).unwrap(), &jobs, &results).unwrap(),
+        None => write_report(io::stdout().lock(), &jobs, &results).unwrap(),
+    }
+
+    results
+        .iter()
+        .all(|result| matches!(result.status, Status::Ok))
+}
+
+fn read_jobs(path: &Path) -> Result<Vec<Job>, String> {
+    let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+    let mut jobs = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        if jobs.is_empty() && fields[0].eq_ignore_ascii_case("input") {
+            continue;
+        }
+
+        if fields.len() < 4 || fields.len() > 5 {
+            return Err(format!(
+                "line {}: expected input,meta,port,output[,args]",
+                index + 1
+            ));
+        }
+
+        let port = match fields[2] {
+            "" => None,
+            port => Some(
+                port.parse()
+                    .map_err(|_| format!("line {}: bad port {}", index + 1, port))?,
+            ),
+        };
+
+        jobs.push(Job {
+            input: PathBuf::from(fields[0]),
+            meta: PathBuf::from(fields[1]),
+            port,
+            output: PathBuf::from(fields[3]),
+            args: fields
+                .get(4)
+                .map(|args| args.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+        });
+    }
+
+    Ok(jobs)
+}
+
+fn run_job(job: &Job) -> JobResult {
+    let start = Instant::now();
+
+    let result = |status| JobResult {
+        status,
+        frames: count_frames(&job.output),
+        seconds: start.elapsed().as_secs_f64(),
+    };
+
+    if let Err(err) = fs::create_dir_all(&job.output) {
+        return result(Status::Failed(err.to_string()));
+    }
+
+    let mut command = Command::new(env::current_exe().unwrap());
+    command
+        .arg("--input")
+        .arg(&job.input)
+        .arg("--meta")
+        .arg(&job.meta)
+        .arg("--output")
+        .arg(&job.output)
+        .args(&job.args);
+
+    if let Some(port) = job.port {
+        command.arg("--port").arg(port.to_string());
+    }
+
+    match command.status() {
+        Ok(status) if status.success() => result(Status::Ok),
+        Ok(status) => result(Status::Failed(status.to_string())),
+        Err(err) => result(Status::Failed(err.to_string())),
+    }
+}
+
+fn count_frames(dir: &Path) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .flatten()
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                count_frames(&path)
+            } else {
+                (path.extension().is_some_and(|ext| ext == "pcd")) as usize
+            }
+        })
+        .sum()
+}
+
+fn write_report<W: Write>(mut writer: W, jobs: &[Job], results: &[JobResult]) -> io::Result<()> {
+    writeln!(writer, "job,input,output,status,frames,seconds")?;
+
+    for (index, (job, result)) in jobs.iter().zip(results).enumerate() {
+        let status = match &result.status {
+            Status::Ok => "ok".to_string(),
+            Status::Failed(reason) => format!("failed ({})", reason),
+            Status::Skipped => "skipped".to_string(),
+        };
+
+        writeln!(
+            writer,
+            "{},{},{},{},{},{:.3}",
+            index + 1,
+            job.input.display(),
+            job.output.display(),
+            status,
+            result.frames,
+            result.seconds
+        )?;
+    }
+
+    Ok(())
+}
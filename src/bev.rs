@@ -0,0 +1,225 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use crate::sink::{pcd_path, Frame, FrameSink};
+
+/// What a BEV grid cell shows.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum BevMode {
+    Height,
+    Occupancy,
+}
+
+/// Top-down grid around the sensor, x pointing up and y left in the image.
+pub struct BevGrid {
+    mode: BevMode,
+    /// Cell edge length in meters
+    resolution: f32,
+    /// Half width of the grid in meters
+    extent: f32,
+    size: usize,
+}
+
+impl BevGrid {
+    pub fn new(mode: BevMode, resolution: f32, extent: f32) -> Result<Self, String> {
+        if !(resolution > 0.0 && extent > 0.0) {
+            return Err("bev resolution and extent must be positive".to_string());
+        }
+
+        let size = (2.0 * extent / resolution).ceil() as usize;
+        if size > u16::MAX as usize {
+            return Err(format!("bev grid of {} cells per side is too large", size));
+        }
+
+        Ok(Self {
+            mode,
+            resolution,
+            extent,
+            size,
+        })
+    }
+
+    /// Gray value per cell, 0 for empty cells.
+    ///
+    /// Heights are stretched over the range of the frame onto 1 to 255.
+    fn rasterize(&self, frame: &Frame) -> Vec<u8> {
+        let stride = frame.stride();
+        let mut heights = vec![f32::NAN; self.size * self.size];
+
+        for point in frame.points.chunks_exact(stride) {
+            let row = ((self.extent - point[0]) / self.resolution).floor();
+            let col = ((self.extent - point[1]) / self.resolution).floor();
+
+            // also false for NaN
+            if !(row >= 0.0 && col >= 0.0 && row < self.size as f32 && col < self.size as f32) {
+                continue;
+            }
+
+            let cell = &mut heights[row as usize * self.size + col as usize];
+            if cell.is_nan() || point[2] > *cell {
+                *cell = point[2];
+            }
+        }
+
+        let (min, max) = heights
+            .iter()
+            .filter(|height| !height.is_nan())
+            .fold((f32::MAX, f32::MIN), |(min, max), &height| {
+                (min.min(height), max.max(height))
+            });
+        let scale = if max > min { 254.0 / (max - min) } else { 0.0 };
+
+        heights
+            .iter()
+            .map(|&height| match self.mode {
+                _ if height.is_nan() => 0,
+                BevMode::Occupancy => 255,
+                BevMode::Height => 1 + ((height - min) * scale).round() as u8,
+            })
+            .collect()
+    }
+}
+
+/// Writes a grayscale BEV PNG image of each frame next to its PCD file.
+pub struct BevSink {
+    output_path: PathBuf,
+    shards: usize,
+    grid: BevGrid,
+}
+
+impl BevSink {
+    pub fn new(output_path: &Path, shards: usize, grid: BevGrid) -> Self {
+        Self {
+            output_path: output_path.to_path_buf(),
+            shards,
+            grid,
+        }
+    }
+}
+
+impl FrameSink for BevSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.discarded.is_some() {
+            return Ok(());
+        }
+
+        let file_path = self
+            .output_path
+            .join(pcd_path(frame, self.shards))
+            .with_extension("png");
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let pixels = self.grid.rasterize(frame);
+
+        let mut file = BufWriter::new(File::create(&file_path)?);
+        write_png(&mut file, self.grid.size, self.grid.size, &pixels)?;
+        file.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes an 8-bit grayscale PNG image with uncompressed deflate blocks,
+/// which every decoder reads and needs no compression library.
+fn write_png<W: Write>(
+    writer: &mut W,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> io::Result<()> {
+    // every scanline starts with filter type 0, none
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks_exact(width) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream of stored blocks, at most 65535 bytes each
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, grayscale, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"IDAT", &zlib)?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+
+    let crc = crc32(&[&kind[..], data].concat());
+    writer.write_all(&crc.to_be_bytes())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    b << 16 | a
+}
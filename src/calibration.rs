@@ -0,0 +1,222 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{fs, path::Path};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::ouster::OusterError;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalibrationMode {
+    Absolute,
+    Delta,
+}
+
+/// Per-beam angle overrides applied on top of the metadata, in degrees.
+///
+/// Read either from a json file with optional `beam_azimuth_angles` and
+/// `beam_altitude_angles` arrays, or from a csv file with one
+/// `azimuth,altitude` row per beam.
+///
+/// It serializes to its source file, mode and values, to record what a
+/// run applied.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Calibration {
+    #[serde(skip_deserializing)]
+    source: String,
+    #[serde(skip_deserializing, default = "default_mode")]
+    mode: CalibrationMode,
+    #[serde(default)]
+    beam_azimuth_angles: Option<Vec<f32>>,
+    #[serde(default)]
+    beam_altitude_angles: Option<Vec<f32>>,
+}
+
+fn default_mode() -> CalibrationMode {
+    CalibrationMode::Absolute
+}
+
+impl Calibration {
+    pub fn load(path: &Path, mode: CalibrationMode) -> Result<Self, OusterError> {
+        let error = |err: String| OusterError::Config(format!("{}: {}", path.display(), err));
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+
+        let mut calibration = if path.extension().is_some_and(|ext| ext == "csv") {
+            Self::from_csv(&text).map_err(error)?
+        } else {
+            serde_json::from_str(&text).map_err(|err| error(err.to_string()))?
+        };
+
+        calibration.source = path.display().to_string();
+        calibration.mode = mode;
+        Ok(calibration)
+    }
+
+    /// Per-beam altitude deltas in degrees, one per line or separated by
+    /// commas, added to the metadata angles.
+    pub fn load_altitude_correction(path: &Path) -> Result<Self, OusterError> {
+        let error = |err: String| OusterError::Config(format!("{}: {}", path.display(), err));
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+
+        let deltas = text
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse::<f32>()
+                    .map_err(|_| error(format!("malformed altitude correction {:?}", value)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            source: path.display().to_string(),
+            mode: CalibrationMode::Delta,
+            beam_azimuth_angles: None,
+            beam_altitude_angles: Some(deltas),
+        })
+    }
+
+    fn from_csv(text: &str) -> Result<Self, String> {
+        let mut azimuths = Vec::new();
+        let mut altitudes = Vec::new();
+
+        let lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+        for (index, line) in lines.enumerate() {
+            let mut values = line.split(',').map(|value| value.trim().parse::<f32>());
+
+            match (values.next(), values.next()) {
+                (Some(Ok(azimuth)), Some(Ok(altitude))) => {
+                    azimuths.push(azimuth);
+                    altitudes.push(altitude);
+                }
+                // only the first row may be a header
+                _ if index == 0 => (),
+                _ => return Err(format!("malformed calibration row {:?}", line)),
+            }
+        }
+
+        Ok(Self {
+            source: String::new(),
+            mode: CalibrationMode::Absolute,
+            beam_azimuth_angles: Some(azimuths),
+            beam_altitude_angles: Some(altitudes),
+        })
+    }
+
+    /// One line naming the source and mode, for output headers.
+    pub fn describe(&self) -> String {
+        let mode = match self.mode {
+            CalibrationMode::Absolute => "absolute",
+            CalibrationMode::Delta => "delta",
+        };
+
+        format!("calibration: {} ({})", self.source, mode)
+    }
+
+    pub fn apply(&self, azimuths: &mut [f32], altitudes: &mut [f32]) -> Result<(), String> {
+        if let Some(values) = &self.beam_azimuth_angles {
+            self.apply_angles("beam_azimuth_angles", values, azimuths)?;
+        }
+
+        if let Some(values) = &self.beam_altitude_angles {
+            self.apply_angles("beam_altitude_angles", values, altitudes)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_angles(&self, name: &str, values: &[f32], angles: &mut [f32]) -> Result<(), String> {
+        if values.len() != angles.len() {
+            return Err(format!(
+                "Calibration {} has {} beams, metadata has {}",
+                name,
+                values.len(),
+                angles.len()
+            ));
+        }
+
+        for (angle, value) in angles.iter_mut().zip(values) {
+            match self.mode {
+                CalibrationMode::Absolute => *angle = *value,
+                CalibrationMode::Delta => *angle += *value,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_csv_rows_after_a_header() {
+        let calibration = Calibration::from_csv("azimuth,altitude\n1.5,-2\n 3 , 4 \n\n").unwrap();
+
+        assert_eq!(calibration.beam_azimuth_angles, Some(vec![1.5, 3.0]));
+        assert_eq!(calibration.beam_altitude_angles, Some(vec![-2.0, 4.0]));
+    }
+
+    #[test]
+    fn rejects_malformed_rows_past_the_header() {
+        assert!(Calibration::from_csv("azimuth,altitude\nunits,degrees\n1,2\n").is_err());
+        assert!(Calibration::from_csv("1,2\n3\n").is_err());
+    }
+
+    #[test]
+    fn reports_unreadable_files() {
+        let missing = Path::new("/nonexistent/calibration.json");
+
+        assert!(matches!(
+            Calibration::load(missing, CalibrationMode::Delta),
+            Err(OusterError::Config(_))
+        ));
+        assert!(matches!(
+            Calibration::load_altitude_correction(missing),
+            Err(OusterError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn applies_absolute_values_and_deltas() {
+        let mut calibration: Calibration =
+            serde_json::from_str(r#"{"beam_altitude_angles": [1, -1]}"#).unwrap();
+        let mut azimuths = [3.0, 3.0];
+        let mut altitudes = [10.0, 20.0];
+
+        calibration.apply(&mut azimuths, &mut altitudes).unwrap();
+        assert_eq!((azimuths, altitudes), ([3.0, 3.0], [1.0, -1.0]));
+
+        calibration.mode = CalibrationMode::Delta;
+        calibration.apply(&mut azimuths, &mut altitudes).unwrap();
+        assert_eq!((azimuths, altitudes), ([3.0, 3.0], [2.0, -2.0]));
+    }
+
+    #[test]
+    fn rejects_beam_count_mismatch() {
+        let calibration = Calibration::from_csv("1,2\n3,4\n").unwrap();
+
+        assert!(calibration.apply(&mut [0.0; 3], &mut [0.0; 3]).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::io::{self, Read};
+
+use pcap_parser::{
+    pcap,
+    pcapng::{self, Block},
+    Linktype, PcapBlock,
+};
+
+/// A packet record of a pcap/pcapng capture.
+pub struct CapturedPacket<'a> {
+    /// Captured bytes, bounded by `caplen`
+    pub data: &'a [u8],
+    pub caplen: u32,
+    pub origlen: u32,
+    /// Capture time in seconds since the epoch, if the record has one
+    pub timestamp: Option<f64>,
+    /// 1-based number of the packet record, as shown by wireshark
+    pub number: usize,
+}
+
+impl CapturedPacket<'_> {
+    pub fn is_truncated(&self) -> bool {
+        self.caplen < self.origlen
+    }
+}
+
+/// Part of a capture to process, the default being all of it.
+///
+/// Byte offsets count from the start of the file and select whole
+/// blocks; times are capture timestamps in seconds since the epoch.
+#[derive(Default)]
+pub struct CaptureRange {
+    pub skip_bytes: usize,
+    pub limit_bytes: Option<usize>,
+    pub start_time: Option<f64>,
+    pub end_time: Option<f64>,
+}
+
+impl CaptureRange {
+    fn end(&self) -> usize {
+        self.limit_bytes
+            .map_or(usize::MAX, |limit| self.skip_bytes.saturating_add(limit))
+    }
+
+    fn contains(&self, offset: usize, packet: &CapturedPacket) -> bool {
+        if offset < self.skip_bytes {
+            return false;
+        }
+
+        // records without a timestamp can't be placed and are kept
+        match packet.timestamp {
+            Some(timestamp) => {
+                self.start_time.map_or(true, |start| timestamp >= start)
+                    && self.end_time.map_or(true, |end| timestamp < end)
+            }
+            None => true,
+        }
+    }
+}
+
+struct Interface {
+    linktype: Linktype,
+    snaplen: u32,
+    /// Timestamp units per second
+    ts_resolution: f64,
+    ts_offset: i64,
+}
+
+/// Interface ids only mean something within their pcapng section, so
+/// concatenated captures start over at every section header.
+#[derive(Default)]
+struct CaptureState {
+    /// Interfaces of the current pcapng section, indexed by id
+    interfaces: Vec<Interface>,
+    /// Whether pcap records carry nanoseconds instead of microseconds
+    nanosecond: bool,
+    /// Packet records seen, yielded or not, to number them as wireshark does
+    num_packets: usize,
+}
+
+/// Call `process` for every packet record of a pcap or pcapng capture
+/// within `range`.
+///
+/// A malformed block is reported and skipped up to the next plausible
+/// block boundary, so a corrupt or truncated part doesn't cost the rest.
+pub fn for_each_packet<F: FnMut(&CapturedPacket)>(
+    data: &[u8],
+    range: &CaptureRange,
+    mut process: F,
+) {
+    let mut state = CaptureState::default();
+
+    if let Ok((mut rest, header)) = pcap::parse_pcap_header(data) {
+        let big_endian = header.is_bigendian();
+        state.nanosecond = header.is_nanosecond_precision();
+
+        // pcap has no state before the records, so seek straight to the
+        // first record boundary at or past the skipped bytes
+        if range.skip_bytes > data.len() - rest.len() {
+            let at = range.skip_bytes.min(data.len());
+            let skip = (0..data.len() - at)
+                .find(|&skip| {
+                    is_block_boundary(&data[at + skip..], |at| pcap_record_len(at, big_endian))
+                })
+                .unwrap_or(data.len() - at);
+            rest = &data[at + skip..];
+        }
+
+        while !rest.is_empty() && data.len() - rest.len() < range.end() {
+            let offset = data.len() - rest.len();
+
+            let result = if big_endian {
+                pcap::parse_pcap_frame_be(rest)
+            } else {
+                pcap::parse_pcap_frame(rest)
+            };
+
+            match result {
+                Ok((next, block)) => {
+                    if let Some(packet) = state.packet(&PcapBlock::Legacy(&block)) {
+                        if range.contains(offset, &packet) {
+                            process(&packet);
+                        }
+                    }
+                    rest = next;
+                }
+                Err(_) => {
+                    rest = resync(data, rest, 1, |at| {
+                        is_block_boundary(at, |at| pcap_record_len(at, big_endian))
+                    })
+                }
+            }
+        }
+    } else if let Ok((_, shb)) = pcapng::parse_sectionheaderblock(data) {
+        let mut big_endian = shb.big_endian();
+        let mut rest = data;
+
+        // section and interface blocks before the skipped bytes are still
+        // needed, so only the packets there are left out
+        while !rest.is_empty() && data.len() - rest.len() < range.end() {
+            let offset = data.len() - rest.len();
+
+            let result = if big_endian {
+                pcapng::parse_block_be(rest)
+            } else {
+                pcapng::parse_block_le(rest)
+            };
+
+            match result {
+                Ok((next, block)) => {
+                    // each section may have its own byte order
+                    if let Block::SectionHeader(shb) = &block {
+                        big_endian = shb.big_endian();
+                    }
+
+                    if let Some(packet) = state.packet(&PcapBlock::NG(&block)) {
+                        if range.contains(offset, &packet) {
+                            process(&packet);
+                        }
+                    }
+                    rest = next;
+                }
+                // a block without packets only matters for its framing,
+                // so one whose contents don't parse is stepped over whole
+                Err(_) => match packetless_block_len(rest, big_endian) {
+                    Some(len) => rest = &rest[len..],
+                    None => {
+                        rest = resync(data, rest, 4, |at| {
+                            is_block_boundary(at, |at| pcapng_block_len(at, big_endian))
+                        })
+                    }
+                },
+            }
+        }
+    } else {
+        eprintln!("Unrecognized file format. (Neither pcap nor pcapng)");
+    }
+}
+
+/// Largest payload a udp datagram over ipv4 can carry
+const MAX_PAYLOAD_LEN: usize = 65507;
+
+/// Call `process` for every udp payload of a stream without any packet
+/// headers, where each payload is preceded by its length as a
+/// little-endian u32.
+///
+/// The stream may only end between payloads.
+pub fn for_each_payload<R: Read, F: FnMut(&[u8])>(mut reader: R, mut process: F) -> io::Result<()> {
+    let mut len = [0u8; 4];
+    let mut payload = Vec::new();
+
+    loop {
+        match reader.read(&mut len[..1]) {
+            Ok(0) => return Ok(()),
+            Ok(_) => reader.read_exact(&mut len[1..])?,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("length prefix of {} bytes is beyond any udp payload", len),
+            ));
+        }
+
+        payload.resize(len, 0);
+        reader.read_exact(&mut payload)?;
+        process(&payload);
+    }
+}
+
+/// Skip `rest` forward to the next offset accepted by `is_boundary`, or to the end.
+fn resync<'a, F: Fn(&[u8]) -> bool>(
+    data: &[u8],
+    rest: &'a [u8],
+    align: usize,
+    is_boundary: F,
+) -> &'a [u8] {
+    let skip = (align..rest.len())
+        .step_by(align)
+        .find(|&skip| is_boundary(&rest[skip..]))
+        .unwrap_or(rest.len());
+
+    eprintln!(
+        "Skip malformed capture block. (offset {}, {} bytes skipped)",
+        data.len() - rest.len(),
+        skip
+    );
+
+    &rest[skip..]
+}
+
+/// A boundary is trusted if the block there is plausible and is followed
+/// by either the end of the capture or another plausible block.
+fn is_block_boundary<F: Fn(&[u8]) -> Option<usize>>(at: &[u8], block_len: F) -> bool {
+    match block_len(at) {
+        Some(len) => len == at.len() || block_len(&at[len..]).is_some(),
+        None => false,
+    }
+}
+
+fn read_u32(data: &[u8], big_endian: bool) -> u32 {
+    let bytes = [data[0], data[1], data[2], data[3]];
+
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Length of the pcap record at the start of `at`, if its header is sane.
+fn pcap_record_len(at: &[u8], big_endian: bool) -> Option<usize> {
+    // far above any real snaplen
+    const MAX_PACKET_LEN: u32 = 0x40000;
+
+    if at.len() < 16 {
+        return None;
+    }
+
+    let ts_frac = read_u32(&at[4..], big_endian);
+    let caplen = read_u32(&at[8..], big_endian);
+    let origlen = read_u32(&at[12..], big_endian);
+    let len = 16 + caplen as usize;
+
+    let sane = ts_frac < 1_000_000_000
+        && caplen <= origlen
+        && origlen <= MAX_PACKET_LEN
+        && len <= at.len();
+
+    sane.then_some(len)
+}
+
+/// Length of the pcapng block at the start of `at`, if its framing is sane.
+fn pcapng_block_len(at: &[u8], big_endian: bool) -> Option<usize> {
+    const BLOCK_TYPES: [u32; 9] = [
+        0x0A0D_0D0A, // section header
+        0x0000_0001, // interface description
+        0x0000_0002, // obsolete packet
+        0x0000_0003, // simple packet
+        0x0000_0004, // name resolution
+        0x0000_0005, // interface statistics
+        0x0000_0006, // enhanced packet
+        0x0000_0BAD, // custom
+        0x4000_0BAD, // custom, not to be copied
+    ];
+
+    if at.len() < 12 {
+        return None;
+    }
+
+    let block_type = read_u32(at, big_endian);
+    let len = read_u32(&at[4..], big_endian) as usize;
+
+    let sane = BLOCK_TYPES.contains(&block_type)
+        && len >= 12
+        && len % 4 == 0
+        && len <= at.len()
+        && read_u32(&at[len - 4..], big_endian) as usize == len;
+
+    sane.then_some(len)
+}
+
+/// Length of the interface statistics, name resolution or custom block
+/// at the start of `at`, if its framing is sane.
+fn packetless_block_len(at: &[u8], big_endian: bool) -> Option<usize> {
+    const PACKETLESS_TYPES: [u32; 4] = [
+        0x0000_0004, // name resolution
+        0x0000_0005, // interface statistics
+        0x0000_0BAD, // custom
+        0x4000_0BAD, // custom, not to be copied
+    ];
+
+    pcapng_block_len(at, big_endian)
+        .filter(|_| PACKETLESS_TYPES.contains(&read_u32(at, big_endian)))
+}
+
+impl CaptureState {
+    fn packet<'a>(&mut self, block: &PcapBlock<'a>) -> Option<CapturedPacket<'a>> {
+        let (data, caplen, origlen, timestamp) = match block {
+            PcapBlock::Legacy(b) => {
+                let frac = if self.nanosecond { 1e-9 } else { 1e-6 };
+                let timestamp = b.ts_sec as f64 + b.ts_usec as f64 * frac;
+                (b.data, b.caplen, b.origlen, Some(timestamp))
+            }
+            PcapBlock::NG(Block::SectionHeader(_)) => {
+                self.interfaces.clear();
+                return None;
+            }
+            PcapBlock::NG(Block::InterfaceDescription(b)) => {
+                // resolution is a power of ten, or of two if the high bit is set
+                let ts_resolution = if b.if_tsresol & 0x80 == 0 {
+                    10f64.powi(b.if_tsresol as i32)
+                } else {
+                    2f64.powi((b.if_tsresol & 0x7f) as i32)
+                };
+
+                self.interfaces.push(Interface {
+                    linktype: b.linktype,
+                    snaplen: b.snaplen,
+                    ts_resolution,
+                    ts_offset: b.if_tsoffset,
+                });
+                return None;
+            }
+            PcapBlock::NG(Block::EnhancedPacket(b)) if !self.is_ethernet(b.if_id as usize) => {
+                self.num_packets += 1;
+                return None;
+            }
+            PcapBlock::NG(Block::EnhancedPacket(b)) => {
+                let interface = &self.interfaces[b.if_id as usize];
+                let ts = (b.ts_high as u64) << 32 | b.ts_low as u64;
+                let timestamp = ts as f64 / interface.ts_resolution + interface.ts_offset as f64;
+                (b.data, b.caplen, b.origlen, Some(timestamp))
+            }
+            PcapBlock::NG(Block::SimplePacket(b)) => {
+                // simple packets belong to the first interface and
+                // are only as long as its snaplen, 0 meaning unlimited
+                if !self.is_ethernet(0) {
+                    self.num_packets += 1;
+                    return None;
+                }
+
+                let snaplen = match self.interfaces.first() {
+                    Some(interface) if interface.snaplen != 0 => interface.snaplen,
+                    _ => b.origlen,
+                };
+                (b.data, b.origlen.min(snaplen), b.origlen, None)
+            }
+            // statistics, names and custom data carry no packets
+            PcapBlock::NG(Block::InterfaceStatistics(_))
+            | PcapBlock::NG(Block::NameResolution(_))
+            | PcapBlock::NG(Block::Custom(_)) => return None,
+            _ => return None,
+        };
+
+        let len = (caplen.min(origlen) as usize).min(data.len());
+        self.num_packets += 1;
+
+        Some(CapturedPacket {
+            data: &data[..len],
+            caplen,
+            origlen,
+            timestamp,
+            number: self.num_packets,
+        })
+    }
+
+    /// Packets of unknown or non-ethernet interfaces are skipped.
+    fn is_ethernet(&self, if_id: usize) -> bool {
+        self.interfaces
+            .get(if_id)
+            .is_some_and(|interface| interface.linktype == Linktype::ETHERNET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pcapng block around `body`, padded to 32 bits.
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = (body.len() + 3) & !3;
+        let len = (12 + padded) as u32;
+
+        let mut data = block_type.to_le_bytes().to_vec();
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(body);
+        data.resize(8 + padded, 0);
+        data.extend_from_slice(&len.to_le_bytes());
+        data
+    }
+
+    fn section() -> Vec<u8> {
+        let mut body = 0x1A2B_3C4Du32.to_le_bytes().to_vec();
+        body.extend_from_slice(&[1, 0, 0, 0]);
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        block(0x0A0D_0D0A, &body)
+    }
+
+    fn interface(linktype: u16, snaplen: u32) -> Vec<u8> {
+        let mut body = linktype.to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&snaplen.to_le_bytes());
+        block(1, &body)
+    }
+
+    /// An enhanced packet block with a timestamp in microseconds.
+    fn enhanced(if_id: u32, ts: u64, data: &[u8]) -> Vec<u8> {
+        let mut body = if_id.to_le_bytes().to_vec();
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        block(6, &body)
+    }
+
+    /// A little-endian pcap capture of ethernet records.
+    fn pcap(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = 0xa1b2_c3d4u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&[2, 0, 4, 0]);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&0xffffu32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend(records.concat());
+        data
+    }
+
+    /// A pcap record of `data` cut from a packet of `origlen` bytes.
+    fn record(ts_sec: u32, data: &[u8], origlen: u32) -> Vec<u8> {
+        let mut record = ts_sec.to_le_bytes().to_vec();
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&origlen.to_le_bytes());
+        record.extend_from_slice(data);
+        record
+    }
+
+    /// The numbers and bytes of the packets yielded from a capture.
+    fn packets(capture: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let mut packets = Vec::new();
+        for_each_packet(capture, &CaptureRange::default(), |packet| {
+            packets.push((packet.number, packet.data.to_vec()));
+        });
+        packets
+    }
+
+    #[test]
+    fn numbers_every_record_of_concatenated_sections() {
+        let capture = [
+            section(),
+            interface(1, 0),
+            enhanced(0, 1, &[1; 60]),
+            // interface ids start over, and 0 is no longer ethernet
+            section(),
+            interface(0, 0),
+            interface(1, 0),
+            enhanced(0, 2, &[2; 60]),
+            enhanced(1, 3, &[3; 61]),
+        ]
+        .concat();
+
+        assert_eq!(packets(&capture), vec![(1, vec![1; 60]), (3, vec![3; 61])]);
+    }
+
+    #[test]
+    fn bounds_snaplen_truncated_packets_by_caplen() {
+        let capture = pcap(&[record(1, &[1; 64], 1514), record(2, &[2; 60], 60)]);
+
+        let mut packets = Vec::new();
+        for_each_packet(&capture, &CaptureRange::default(), |packet| {
+            packets.push((packet.is_truncated(), packet.caplen, packet.data.len()));
+        });
+
+        assert_eq!(packets, vec![(true, 64, 64), (false, 60, 60)]);
+    }
+
+    #[test]
+    fn reads_simple_packets_up_to_snaplen() {
+        let simple = |data: &[u8]| {
+            let mut body = 1514u32.to_le_bytes().to_vec();
+            body.extend_from_slice(data);
+            block(3, &body)
+        };
+        let capture = [section(), interface(1, 96), simple(&[1; 96])].concat();
+
+        let mut packets = Vec::new();
+        for_each_packet(&capture, &CaptureRange::default(), |packet| {
+            packets.push((
+                packet.number,
+                packet.caplen,
+                packet.origlen,
+                packet.timestamp,
+            ));
+        });
+
+        // no timestamp, and only the snaplen of the first interface captured
+        assert_eq!(packets, vec![(1, 96, 1514, None)]);
+    }
+
+    #[test]
+    fn resyncs_after_corrupt_pcapng_block() {
+        let mut corrupt = enhanced(0, 2, &[2; 60]);
+        let len = corrupt.len();
+        corrupt[len - 4] ^= 0xff;
+
+        let capture = [
+            section(),
+            interface(1, 0),
+            enhanced(0, 1, &[1; 60]),
+            corrupt,
+            enhanced(0, 3, &[3; 60]),
+        ]
+        .concat();
+
+        assert_eq!(packets(&capture), vec![(1, vec![1; 60]), (2, vec![3; 60])]);
+    }
+
+    #[test]
+    fn resyncs_after_corrupt_pcap_record() {
+        // a caplen past the end of the capture can't be parsed
+        let mut corrupt = record(2, &[0xff; 60], 60);
+        corrupt[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+
+        let capture = pcap(&[record(1, &[1; 60], 60), corrupt, record(3, &[3; 60], 60)]);
+
+        assert_eq!(packets(&capture), vec![(1, vec![1; 60]), (2, vec![3; 60])]);
+    }
+
+    #[test]
+    fn bounds_pcap_record_len() {
+        let header = |ts_frac: u32, caplen: u32, origlen: u32| {
+            let mut at = vec![0; 4];
+            at.extend_from_slice(&ts_frac.to_le_bytes());
+            at.extend_from_slice(&caplen.to_le_bytes());
+            at.extend_from_slice(&origlen.to_le_bytes());
+            at.resize(16 + 64, 0);
+            at
+        };
+
+        assert_eq!(pcap_record_len(&header(0, 64, 0x40000), false), Some(80));
+        assert_eq!(pcap_record_len(&header(0, 64, 0x40001), false), None);
+        assert_eq!(pcap_record_len(&header(0, 65, 100), false), None);
+        assert_eq!(pcap_record_len(&header(0, 64, 63), false), None);
+        assert_eq!(pcap_record_len(&header(1_000_000_000, 64, 64), false), None);
+        assert_eq!(pcap_record_len(&[0; 15], false), None);
+    }
+
+    #[test]
+    fn skips_statistics_and_name_blocks_between_packets() {
+        let mut names = 1u16.to_le_bytes().to_vec();
+        names.extend_from_slice(&8u16.to_le_bytes());
+        names.extend_from_slice(&[10, 5, 5, 1, b'o', b's', 0, 0]);
+        names.extend_from_slice(&[0; 4]);
+
+        let mut statistics = 0u32.to_le_bytes().to_vec();
+        statistics.extend_from_slice(&[0; 8]);
+
+        let capture = [
+            section(),
+            interface(1, 0),
+            block(4, &names),
+            enhanced(0, 1, &[1; 60]),
+            block(5, &statistics),
+            enhanced(0, 2, &[2; 60]),
+            block(0xBAD, &[0; 8]),
+            // too short for its fields, but framed well enough to step over
+            block(5, &[0; 4]),
+            enhanced(0, 3, &[3; 60]),
+        ]
+        .concat();
+
+        assert_eq!(
+            packets(&capture),
+            vec![(1, vec![1; 60]), (2, vec![2; 60]), (3, vec![3; 60])]
+        );
+    }
+}
@@ -0,0 +1,627 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::{Parser, Subcommand};
+use memmap2::Mmap;
+
+use crate::{
+    batch,
+    bev::BevMode,
+    calibration::CalibrationMode,
+    capture::CaptureRange,
+    color::Colorize,
+    convert, lock,
+    ouster::{
+        self, CoordinateFrame, DirLayout, IntensityField, SecondReturn, UdpProfile, Validity,
+    },
+    preset::SensorModel,
+    sampling::Sampling,
+    sink::IoBackend,
+    stats, validate,
+};
+
+#[derive(Parser)]
+#[command(name = "ouster_parser")]
+#[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Destination port of udp packets [default: udp_port_lidar in metadata]
+    #[arg(short, long, value_name = "NUM")]
+    pub(crate) port: Option<u16>,
+
+    /// Ouster Lidar metadata json file
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required_unless_present_any = ["benchmark_reassembly", "ports_file", "sensor_model"]
+    )]
+    pub(crate) meta: Option<PathBuf>,
+
+    /// Parse without metadata using the factory-nominal intrinsics of a model, e.g. os1-64:1024x10; approximate, as units are calibrated individually
+    #[arg(
+        long,
+        value_name = "MODEL[:MODE]",
+        conflicts_with_all = ["meta", "ports_file", "validate_only"]
+    )]
+    pub(crate) sensor_model: Option<SensorModel>,
+
+    /// Input pcap/pcapng file
+    #[arg(short, long, value_name = "FILE", required = true)]
+    pub(crate) input: Option<PathBuf>,
+
+    /// Read the input, or stdin for -, as lidar udp payloads each preceded by its u32 little-endian length
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "ports_file", "no_reassembly", "strict", "skip_bytes", "limit_bytes",
+            "start_time", "end_time", "validate_only", "trace_packets",
+            "benchmark_reassembly", "write_defragmented"
+        ]
+    )]
+    pub(crate) raw_payloads: bool,
+
+    /// Output directory
+    #[arg(
+        short,
+        long,
+        value_name = "DIR",
+        required_unless_present_any = ["benchmark_reassembly", "validate_only", "ports_file"]
+    )]
+    pub(crate) output: Option<PathBuf>,
+
+    /// Json file mapping ports to metadata and output directories, to parse several streams at once
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "port", "meta", "output", "validate_only", "scene_out",
+            "build_map", "time_index", "invalid_points"
+        ]
+    )]
+    pub(crate) ports_file: Option<PathBuf>,
+
+    /// Lidar packet layout [default: udp_profile_lidar in metadata, or legacy]
+    #[arg(long, value_name = "PROFILE")]
+    pub(crate) udp_profile: Option<UdpProfile>,
+
+    /// Whether the second returns of dual-return packets are dropped or merged into the same cloud
+    #[arg(long, value_name = "MODE", default_value = "drop")]
+    pub(crate) second_return: SecondReturn,
+
+    /// Add the distance in meters from the first to the second return of each pixel as a field, NaN without a second return
+    #[arg(long)]
+    pub(crate) range_difference: bool,
+
+    /// Digit number of output PCD filenames
+    #[arg(short, long, value_name = "NUM", default_value_t = 4)]
+    pub(crate) digit: usize,
+
+    /// Output PCD filename template, with {id} for the frame number and {completeness} for its received share
+    #[arg(long, value_name = "TEMPLATE", default_value = "{id}")]
+    pub(crate) name: String,
+
+    /// Split output by intensity bands at comma-separated raw thresholds
+    #[arg(long, value_name = "THRESHOLDS", value_delimiter = ',')]
+    pub(crate) split_by_intensity: Vec<f32>,
+
+    /// Packet field written as intensity, normalized by its bit depth
+    #[arg(long, value_name = "FIELD", default_value = "reflectivity")]
+    pub(crate) intensity: IntensityField,
+
+    /// Returns kept as points: range, range-and-reflectivity or custom:MIN_METERS,MIN_REFLECTIVITY
+    #[arg(long, value_name = "POLICY", default_value = "range-and-reflectivity")]
+    pub(crate) validity: Validity,
+
+    /// Add sensor, frame and conversion details as PCD header comments
+    #[arg(long, conflicts_with = "no_comments")]
+    pub(crate) embed_metadata: bool,
+
+    /// Write PCD headers without any comment lines
+    #[arg(long)]
+    pub(crate) no_comments: bool,
+
+    /// Write frame PCD files as ascii rows with 6 decimals instead of binary, for debugging
+    #[arg(long)]
+    pub(crate) ascii: bool,
+
+    /// Name of the intensity field in PCD headers, e.g. i or reflectivity
+    #[arg(long, value_name = "NAME", default_value = "intensity", value_parser = parse_field_name)]
+    pub(crate) intensity_name: String,
+
+    /// Abort on malformed ipv4 fragments instead of dropping them
+    #[arg(long)]
+    pub(crate) strict: bool,
+
+    /// Skip IPv4 reassembly for captures without fragmentation, dropping any fragments
+    #[arg(long)]
+    pub(crate) no_reassembly: bool,
+
+    /// Parse the complete columns received of a datagram that lost a fragment, once a later one completes
+    #[arg(long, requires = "lenient", conflicts_with = "no_reassembly")]
+    pub(crate) recover_partial_datagrams: bool,
+
+    /// Skip packets in the first bytes of the input, seeking past them in pcap files
+    #[arg(long, value_name = "BYTES", default_value_t = 0)]
+    pub(crate) skip_bytes: usize,
+
+    /// Stop after this many bytes of the input past the skipped ones
+    #[arg(long, value_name = "BYTES")]
+    pub(crate) limit_bytes: Option<usize>,
+
+    /// Skip packets captured before this time, in seconds since the epoch
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) start_time: Option<f64>,
+
+    /// Skip packets captured at or after this time, in seconds since the epoch
+    #[arg(long, value_name = "SECONDS")]
+    pub(crate) end_time: Option<f64>,
+
+    /// Only check that a bounded prefix of the capture yields lidar frames and report its health
+    #[arg(long)]
+    pub(crate) validate_only: bool,
+
+    /// Log the handling of the first N packets [default: 100]
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "100")]
+    pub(crate) trace_packets: Option<usize>,
+
+    /// Only run IPv4 reassembly over the capture and report its throughput
+    #[arg(long)]
+    pub(crate) benchmark_reassembly: bool,
+
+    /// Comma-separated coordinate frames to write, each into its own subdirectory if several
+    #[arg(
+        long,
+        value_name = "FRAMES",
+        value_delimiter = ',',
+        default_value = "lidar"
+    )]
+    pub(crate) frame: Vec<CoordinateFrame>,
+
+    /// Per-beam azimuth/altitude overrides in degrees (json or csv), recorded in session.json and the --summary file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) calibration: Option<PathBuf>,
+
+    /// Whether calibration values replace or add to the metadata angles
+    #[arg(long, value_name = "MODE", default_value = "absolute")]
+    pub(crate) calibration_mode: CalibrationMode,
+
+    /// Per-channel altitude deltas in degrees, one per line, added to the metadata angles
+    #[arg(long, value_name = "FILE")]
+    pub(crate) altitude_correction: Option<PathBuf>,
+
+    /// Drop veil points whose surface angle to the beam is below this many degrees
+    #[arg(long, value_name = "DEGREES")]
+    pub(crate) remove_veil: Option<f32>,
+
+    /// Add an rgb field colored by height, ring or intensity, e.g. height:jet [colormaps: viridis, jet, gray]
+    #[arg(long, value_name = "SOURCE[:COLORMAP]")]
+    pub(crate) colorize: Option<Colorize>,
+
+    /// Write an empty PCD file in place of each broken or incomplete frame
+    #[arg(long)]
+    pub(crate) emit_placeholders: bool,
+
+    /// Write out a frame whose frame_id stays the same over two frames' worth of columns
+    #[arg(long)]
+    pub(crate) flush_stuck_frames: bool,
+
+    /// Skip invalid columns, without counting them, and keep the rest of their frame instead of discarding it
+    #[arg(long)]
+    pub(crate) lenient: bool,
+
+    /// Add each point's beam elevation and encoder azimuth in degrees as fields
+    #[arg(long)]
+    pub(crate) beam_angles: bool,
+
+    /// Write the sensor timestamp of every column of a frame into a binary .ts file next to it
+    #[arg(long)]
+    pub(crate) column_timestamps: bool,
+
+    /// Also write a top-down PNG grid of each frame next to it, with the highest point or occupancy per cell
+    #[arg(long, value_name = "MODE")]
+    pub(crate) bev: Option<BevMode>,
+
+    /// Cell edge length of the BEV grid in meters
+    #[arg(long, value_name = "METERS", default_value_t = 0.1)]
+    pub(crate) bev_resolution: f32,
+
+    /// Distance from the sensor to the edges of the BEV grid in meters
+    #[arg(long, value_name = "METERS", default_value_t = 50.0)]
+    pub(crate) bev_extent: f32,
+
+    /// Subtract this point in meters from every point, for a local frame centered on it
+    #[arg(long, value_name = "X,Y,Z", value_parser = parse_origin, allow_hyphen_values = true)]
+    pub(crate) origin: Option<[f32; 3]>,
+
+    /// Layout of the output directory, time placing frames under YYYY-MM-DD/HH/ by their UTC timestamp
+    #[arg(long, value_name = "LAYOUT", default_value = "flat")]
+    pub(crate) dir_layout: DirLayout,
+
+    /// Average every K consecutive columns per beam into one, keeping coverage at a lower resolution
+    #[arg(long, value_name = "K", default_value_t = 1)]
+    pub(crate) merge_columns: usize,
+
+    /// Keep only every Nth point of a frame after filtering, for previews
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub(crate) point_stride: usize,
+
+    /// Subsample frames with more points after all filters down to K, seeded by the frame timestamp
+    #[arg(long, value_name = "K")]
+    pub(crate) max_points_per_frame: Option<usize>,
+
+    /// How frames over --max-points-per-frame are subsampled, farthest covering evenly but slower
+    #[arg(
+        long,
+        value_name = "METHOD",
+        default_value = "random",
+        requires = "max_points_per_frame"
+    )]
+    pub(crate) sampling: Sampling,
+
+    /// Write the channel and measure_id of every return skipped for zero range or reflectivity to a csv file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) invalid_points: Option<PathBuf>,
+
+    /// Also write every lidar datagram into a pcapng file as one unfragmented packet
+    #[arg(long, value_name = "FILE")]
+    pub(crate) write_defragmented: Option<PathBuf>,
+
+    /// Split frames with more points into numbered _partK files of at most N points
+    #[arg(long, value_name = "N")]
+    pub(crate) points_per_file: Option<usize>,
+
+    /// Report frames starting more than this many nominal frame periods after the previous one
+    #[arg(long, value_name = "PERIODS", default_value_t = 3.0)]
+    pub(crate) max_time_gap: f32,
+
+    /// Start a new segment subdirectory at every frame timestamp discontinuity
+    #[arg(long)]
+    pub(crate) split_on_time_gap: bool,
+
+    /// Start a new segment subdirectory whenever the sensor is reinitialized, as seen from the init_id of packets
+    #[arg(long)]
+    pub(crate) split_on_reconfiguration: bool,
+
+    /// Keep only every Nth column in azimuth, as a sensor in a mode with N times fewer columns
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        visible_alias = "azimuth-downsample"
+    )]
+    pub(crate) column_stride: usize,
+
+    /// Spread frames round-robin over this many shard subdirectories
+    #[arg(long, value_name = "NUM", default_value_t = 1)]
+    pub(crate) shards: usize,
+
+    /// How PCD files are written, uring falling back to std without io_uring support
+    #[arg(long, value_name = "BACKEND", default_value = "std")]
+    pub(crate) io_backend: IoBackend,
+
+    /// PCD files written at once with the uring backend
+    #[arg(long, value_name = "NUM", default_value_t = 32, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) queue_depth: u32,
+
+    /// Also merge all frames into one voxelized scene PCD file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) scene_out: Option<PathBuf>,
+
+    /// Also merge all frames moved by their trajectory poses into one voxelized map PCD file
+    #[arg(long, value_name = "FILE", requires = "trajectory")]
+    pub(crate) build_map: Option<PathBuf>,
+
+    /// Sensor poses in TUM (time tx ty tz qx qy qz qw) or KITTI (3x4 matrix per frame) format
+    #[arg(long, value_name = "FILE")]
+    pub(crate) trajectory: Option<PathBuf>,
+
+    /// Largest time between a frame and its nearest TUM pose
+    #[arg(long, value_name = "SECONDS", default_value_t = 0.05)]
+    pub(crate) pose_tolerance: f64,
+
+    /// Only write the map, no per-frame PCD files
+    #[arg(long, requires = "build_map")]
+    pub(crate) map_only: bool,
+
+    /// Voxel edge length of the scene and map clouds in meters
+    #[arg(long, value_name = "METERS", default_value_t = 0.05)]
+    pub(crate) scene_voxel: f32,
+
+    /// Also write a json index of PCD files sorted by timestamp for seeking
+    #[arg(long, value_name = "FILE")]
+    pub(crate) time_index: Option<PathBuf>,
+
+    /// Fail unless this many frames are written per stream, within a tolerance in frames or percent, e.g. 6000:1%
+    #[arg(long, value_name = "N[:TOLERANCE]", value_parser = parse_expected_frames)]
+    pub(crate) expected_frames: Option<(usize, usize)>,
+
+    /// Write frame, loss and packet counts of the run to a json file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) summary: Option<PathBuf>,
+
+    /// Print how the run compares to the summary of a previous one and fail if loss worsened
+    #[arg(long, value_name = "FILE")]
+    pub(crate) compare_summary: Option<PathBuf>,
+
+    /// Largest loss increase over the baseline summary, in percentage points
+    #[arg(
+        long,
+        value_name = "POINTS",
+        default_value_t = 0.5,
+        requires = "compare_summary"
+    )]
+    pub(crate) max_loss_increase: f64,
+
+    /// Re-read all written PCD files at the end and report broken ones
+    #[arg(long)]
+    pub(crate) verify_output: bool,
+
+    /// Print a BLAKE3 hash of the PCD bytes of all frames in frame order, per stream, to check that runs are reproducible
+    #[arg(long)]
+    pub(crate) hash: bool,
+
+    /// Only print the hash, no per-frame PCD files
+    #[arg(long, requires = "hash")]
+    pub(crate) hash_only: bool,
+
+    /// Emit newline-delimited json progress events to a file, or stderr for -
+    #[arg(long, value_name = "FILE")]
+    pub(crate) progress_events: Option<PathBuf>,
+
+    /// Write per-stage timings to a chrome://tracing json file
+    #[arg(long, value_name = "FILE")]
+    pub(crate) profile: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print an annotated breakdown of a single lidar packet
+    Decode {
+        /// Ouster Lidar metadata json file
+        #[arg(short, long, value_name = "FILE")]
+        meta: PathBuf,
+
+        /// UDP payload, as a binary file or a hex string
+        #[arg(value_name = "PAYLOAD")]
+        payload: String,
+    },
+
+    /// Convert every capture listed in a csv job file
+    Batch {
+        /// Job list with input,meta,port,output[,args] rows
+        #[arg(value_name = "JOBS")]
+        jobs: PathBuf,
+
+        /// Number of jobs to run at the same time
+        #[arg(long, value_name = "NUM", default_value_t = 1)]
+        parallel_files: usize,
+
+        /// Continue with the remaining jobs after a failure
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Write the csv job report here instead of stdout
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+    },
+
+    /// Compare two metadata files, exiting with 1 if the point geometry differs
+    MetaDiff {
+        #[arg(value_name = "A")]
+        a: PathBuf,
+
+        #[arg(value_name = "B")]
+        b: PathBuf,
+    },
+
+    /// Report point, bounding box, intensity and timing statistics of written PCD files
+    Stats {
+        /// Output directory or PCD file
+        #[arg(value_name = "PATH")]
+        path: PathBuf,
+    },
+}
+
+/// Run the command line, exiting with its status once done.
+pub fn run(cli: Cli) {
+    match cli.command {
+        Some(Command::Decode { meta, payload }) => {
+            decode_payload(&meta, &payload);
+            return;
+        }
+        Some(Command::Batch {
+            jobs,
+            parallel_files,
+            keep_going,
+            report,
+        }) => {
+            let ok = batch::run(&jobs, parallel_files, keep_going, report.as_deref());
+            process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::MetaDiff { a, b }) => {
+            let read = |path: &Path| match std::fs::read(path) {
+                Ok(data) => data,
+                Err(err) => {
+                    eprintln!("Failed to read metadata. ({}: {})", path.display(), err);
+                    process::exit(2);
+                }
+            };
+            let (a, b) = (read(&a), read(&b));
+
+            match ouster::diff_metadata(&a, &b) {
+                Ok(geometry_differs) => process::exit(if geometry_differs { 1 } else { 0 }),
+                Err(err) => {
+                    eprintln!("Cannot compare metadata. ({})", err);
+                    process::exit(2);
+                }
+            }
+        }
+        Some(Command::Stats { path }) => {
+            let ok = stats::run(&path);
+            process::exit(if ok { 0 } else { 1 });
+        }
+        None => (),
+    }
+
+    if cli.raw_payloads {
+        convert::run(&cli, None);
+        return;
+    }
+
+    let pcap_file = File::open(cli.input.as_ref().unwrap()).unwrap();
+    let mmap = unsafe { Mmap::map(&pcap_file).unwrap() };
+
+    if cli.benchmark_reassembly {
+        validate::benchmark_reassembly(&mmap[..], cli.strict);
+        return;
+    }
+
+    if cli.validate_only {
+        let range = CaptureRange {
+            skip_bytes: cli.skip_bytes,
+            limit_bytes: Some(cli.limit_bytes.unwrap_or(validate::VALIDATE_BYTES)),
+            start_time: cli.start_time,
+            end_time: cli.end_time,
+        };
+
+        let ok = validate::run(
+            &cli.meta.unwrap(),
+            cli.port,
+            cli.udp_profile,
+            &mmap[..],
+            &range,
+            cli.strict,
+        );
+        process::exit(if ok { 0 } else { 1 });
+    }
+
+    convert::run(&cli, Some(&mmap[..]));
+}
+
+/// Exit once the output directory locks are released, as
+/// `process::exit` skips their Drop.
+pub(crate) fn exit(code: i32) -> ! {
+    lock::release_all();
+    process::exit(code)
+}
+
+fn decode_payload(meta: &Path, payload: &str) {
+    let json_file = File::open(meta).unwrap();
+
+    let data = if Path::new(payload).is_file() {
+        std::fs::read(payload).unwrap()
+    } else {
+        match parse_hex(payload) {
+            Some(data) => data,
+            None => {
+                eprintln!("Payload is neither a file nor a hex string.");
+                process::exit(1);
+            }
+        }
+    };
+
+    ouster::print_packet(json_file, &data);
+}
+
+/// Tool version and a hash of the command line the output was made with.
+///
+/// Unlike the std hasher, BLAKE3 is the same across builds and platforms,
+/// so files converted with the same options carry the same hash.
+pub(crate) fn describe_conversion() -> String {
+    let mut hasher = blake3::Hasher::new();
+    for arg in std::env::args().skip(1) {
+        // length-prefixed, so `a b` and `ab` differ
+        hasher.update(&(arg.len() as u64).to_le_bytes());
+        hasher.update(arg.as_bytes());
+    }
+
+    format!(
+        "tool: {} {} (options hash {})",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        &hasher.finalize().to_hex()[..16]
+    )
+}
+
+fn parse_field_name(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err("expected letters, digits or underscores".to_string())
+    }
+}
+
+/// `N` or `N:TOLERANCE`, the tolerance in frames or in percent of `N`.
+fn parse_expected_frames(text: &str) -> Result<(usize, usize), String> {
+    let (count, tolerance) = text.split_once(':').unwrap_or((text, "0"));
+    let count = count.parse::<usize>().map_err(|err| err.to_string())?;
+
+    let tolerance = match tolerance.strip_suffix('%') {
+        Some(percent) => {
+            let percent = percent.parse::<f64>().map_err(|err| err.to_string())?;
+            (count as f64 * percent / 100.0).round() as usize
+        }
+        None => tolerance.parse::<usize>().map_err(|err| err.to_string())?,
+    };
+
+    Ok((count, tolerance))
+}
+
+fn parse_origin(text: &str) -> Result<[f32; 3], String> {
+    let values = text
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?;
+
+    values
+        .try_into()
+        .map_err(|_| "expected three comma-separated coordinates".to_string())
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    // accept wireshark style dumps with separators and 0x prefixes
+    let digits: Vec<u8> = text
+        .split_whitespace()
+        .flat_map(|word| word.split(':'))
+        .map(|word| word.trim_start_matches("0x"))
+        .flat_map(|word| word.bytes())
+        .collect();
+
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return None;
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
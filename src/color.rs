@@ -0,0 +1,110 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::str::FromStr;
+
+/// Per-point value mapped onto the colormap.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorSource {
+    Height,
+    Ring,
+    Intensity,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Colormap {
+    Viridis,
+    Jet,
+    Gray,
+}
+
+/// Parsed `--colorize` option, `<source>[:<colormap>]`.
+#[derive(Clone, Copy)]
+pub struct Colorize {
+    pub source: ColorSource,
+    pub colormap: Colormap,
+}
+
+impl FromStr for Colorize {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (source, colormap) = text.split_once(':').unwrap_or((text, "viridis"));
+
+        let source = match source {
+            "height" => ColorSource::Height,
+            "ring" => ColorSource::Ring,
+            "intensity" => ColorSource::Intensity,
+            _ => {
+                return Err(format!(
+                    "unknown color source {source} (height, ring, intensity)"
+                ))
+            }
+        };
+
+        let colormap = match colormap {
+            "viridis" => Colormap::Viridis,
+            "jet" => Colormap::Jet,
+            "gray" => Colormap::Gray,
+            _ => return Err(format!("unknown colormap {colormap} (viridis, jet, gray)")),
+        };
+
+        Ok(Self { source, colormap })
+    }
+}
+
+// coarse samples of matplotlib's viridis, linearly interpolated
+const VIRIDIS: [[f32; 3]; 9] = [
+    [0.267, 0.005, 0.329],
+    [0.279, 0.175, 0.483],
+    [0.230, 0.322, 0.546],
+    [0.173, 0.449, 0.558],
+    [0.128, 0.567, 0.551],
+    [0.157, 0.684, 0.502],
+    [0.369, 0.789, 0.383],
+    [0.678, 0.864, 0.190],
+    [0.993, 0.906, 0.144],
+];
+
+impl Colormap {
+    /// Packed `0x00RRGGBB` color of `t` in `[0, 1]`, clamped outside of it.
+    pub fn rgb(&self, t: f32) -> u32 {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+
+        let [r, g, b] = match self {
+            Colormap::Viridis => {
+                let x = t * (VIRIDIS.len() - 1) as f32;
+                let i = (x as usize).min(VIRIDIS.len() - 2);
+                let f = x - i as f32;
+                let (lo, hi) = (VIRIDIS[i], VIRIDIS[i + 1]);
+                [0, 1, 2].map(|c| lo[c] + (hi[c] - lo[c]) * f)
+            }
+            Colormap::Jet => [
+                (1.5 - (4.0 * t - 3.0).abs()).clamp(0.0, 1.0),
+                (1.5 - (4.0 * t - 2.0).abs()).clamp(0.0, 1.0),
+                (1.5 - (4.0 * t - 1.0).abs()).clamp(0.0, 1.0),
+            ],
+            Colormap::Gray => [t, t, t],
+        };
+
+        let byte = |v: f32| (v * 255.0).round() as u32;
+
+        byte(r) << 16 | byte(g) << 8 | byte(b)
+    }
+}
@@ -0,0 +1,679 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+    process,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use crate::{
+    bev::{BevGrid, BevSink},
+    calibration::Calibration,
+    capture::{for_each_packet, for_each_payload, CaptureRange, CapturedPacket},
+    cli::{describe_conversion, exit, Cli},
+    defrag::DefragWriter,
+    lock::{self, OutputLock},
+    ouster::{self, Legacy, OusterError},
+    profile::{Profiler, MAIN_THREAD},
+    progress::{Progress, ProgressSink},
+    receiver::Receiver,
+    sequence::IPV4Seq,
+    session::Session,
+    sink::{
+        ColumnTimestampSink, FrameSink, IoBackend, PcdFormat, PcdSink, PcdStreamSink, SceneSink,
+        SharedHash, TimeIndexSink,
+    },
+    streams::{self, Stream},
+    summary::Summary,
+    trajectory::Trajectory,
+    verify,
+};
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use crate::uring::UringPcdSink;
+
+/// Parse the lidar streams of a capture, or of the raw payloads input
+/// if there's none, into their outputs.
+pub fn run(cli: &Cli, capture: Option<&[u8]>) {
+    let streams = match &cli.ports_file {
+        Some(ports_file) => match streams::load(ports_file) {
+            Ok(streams) => streams,
+            Err(err) => {
+                eprintln!("Invalid ports file. ({})", err);
+                process::exit(1);
+            }
+        },
+        None => vec![Stream {
+            port: cli.port,
+            // unused with a sensor model
+            meta: cli.meta.clone().unwrap_or_default(),
+            output: cli.output.clone().unwrap(),
+        }],
+    };
+
+    let mut locks = Vec::new();
+    for stream in &streams {
+        match OutputLock::acquire(&stream.output) {
+            Ok(Some(lock)) => locks.push(lock),
+            Ok(None) => exit(1),
+            Err(err) => {
+                eprintln!(
+                    "Failed to lock output directory. ({}: {})",
+                    stream.output.display(),
+                    err
+                );
+                exit(1);
+            }
+        }
+    }
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    lock::release_on_interrupt(cancel.clone());
+
+    let profiler = match cli.profile.as_deref().map(Profiler::create).transpose() {
+        Ok(profiler) => profiler.unwrap_or_else(Profiler::disabled),
+        Err(err) => {
+            eprintln!("Failed to create profile. ({})", err);
+            exit(1);
+        }
+    };
+    let progress = Progress::new(cli.progress_events.as_deref());
+    progress.run_started(capture.map_or(0, |capture| capture.len()));
+
+    let calibration = cli
+        .calibration
+        .as_ref()
+        .map(|path| Calibration::load(path, cli.calibration_mode));
+    let altitude_correction = cli
+        .altitude_correction
+        .as_deref()
+        .map(Calibration::load_altitude_correction);
+
+    let (calibration, altitude_correction) =
+        match (calibration.transpose(), altitude_correction.transpose()) {
+            (Ok(calibration), Ok(altitude_correction)) => (calibration, altitude_correction),
+            (Err(err), _) | (_, Err(err)) => {
+                eprintln!("Invalid calibration. ({})", err);
+                exit(1);
+            }
+        };
+
+    let mut parsers: Vec<(u16, Legacy)> = Vec::new();
+    let mut hashes = Vec::new();
+
+    for stream in &streams {
+        let hash = cli.hash.then(SharedHash::default);
+        let parser = build_parser(
+            &cli,
+            stream,
+            &profiler,
+            &progress,
+            &cancel,
+            hash.clone(),
+            calibration.as_ref(),
+            altitude_correction.as_ref(),
+        );
+        hashes.extend(hash);
+
+        let port = match stream.port.or(parser.udp_port_lidar()) {
+            Some(port) => port,
+            // raw payloads come without ports
+            None if cli.raw_payloads => 0,
+            None => {
+                eprintln!("No lidar port given. (Neither --port nor udp_port_lidar in metadata)");
+                exit(1);
+            }
+        };
+
+        if parsers.iter().any(|(other, _)| *other == port) {
+            eprintln!("Lidar port given twice. ({})", port);
+            exit(1);
+        }
+
+        parsers.push((port, parser));
+    }
+
+    let range = CaptureRange {
+        skip_bytes: cli.skip_bytes,
+        limit_bytes: cli.limit_bytes,
+        start_time: cli.start_time,
+        end_time: cli.end_time,
+    };
+
+    let mut receiver = Receiver::new(
+        parsers.iter().map(|(port, _)| *port).collect(),
+        IPV4Seq::new(cli.strict).recover_partial(cli.recover_partial_datagrams),
+    )
+    .reassemble(!cli.no_reassembly)
+    .cancel_on(cancel.clone())
+    .trace(cli.trace_packets.unwrap_or(0))
+    .defragmented(
+        cli.write_defragmented
+            .as_ref()
+            .map(|path| DefragWriter::create(path).unwrap()),
+    );
+
+    match capture {
+        Some(capture) => for_each_packet(capture, &range, |packet| {
+            process_packet(&mut receiver, packet, &mut parsers, &profiler, &progress);
+        }),
+        None => read_raw_payloads(cli.input.as_ref().unwrap(), &mut parsers[0].1, &profiler),
+    }
+
+    if let Some(err) = receiver.error() {
+        eprintln!("Abort on malformed ipv4 fragment. ({})", err);
+    }
+
+    if receiver.num_non_udp() > 0 {
+        eprintln!("Skip non-udp packets. ({} packets)", receiver.num_non_udp());
+    }
+
+    if receiver.num_length_mismatches() > 0 {
+        eprintln!(
+            "Bound payloads by their length fields. ({} packets disagreed beyond ethernet padding)",
+            receiver.num_length_mismatches()
+        );
+    }
+
+    if receiver.num_rejected() > 0 {
+        eprintln!(
+            "Drop oversized ipv4 fragments. ({} fragments past 65535 bytes)",
+            receiver.num_rejected()
+        );
+    }
+
+    let num_streams = parsers.len();
+    let mut all_stats = Vec::new();
+    let mut written = true;
+
+    for (port, parser) in parsers {
+        all_stats.push((port, parser.stats(), parser.missing_frames()));
+
+        if let Err(err) = parser.finish() {
+            eprintln!("Failed to write frames. (port {}, {})", port, err);
+            written = false;
+        }
+    }
+
+    // the sinks are done once the parsers have finished
+    for ((port, _, _), hash) in all_stats.iter().zip(&hashes) {
+        if num_streams > 1 {
+            println!("{}  port {}", hash.value(), port);
+        } else {
+            println!("{}", hash.value());
+        }
+    }
+
+    progress.run_finished();
+
+    let mut frames_ok = written && receiver.error().is_none();
+    let mut summary = Summary::new(
+        receiver.num_non_udp(),
+        receiver.num_rejected(),
+        receiver.num_length_mismatches(),
+    );
+
+    let cancelled = receiver.is_cancelled();
+    if cancelled {
+        summary.set_cancelled();
+    }
+
+    let applied: Vec<&Calibration> = calibration.iter().chain(&altitude_correction).collect();
+    for (stream, (port, stats, _)) in streams.iter().zip(&all_stats) {
+        let session = Session::new(*port, &applied, stats.frames, cancelled);
+        if let Err(err) = session.save(&stream.output) {
+            eprintln!(
+                "Failed to write session file. ({}: {})",
+                stream.output.display(),
+                err
+            );
+            frames_ok = false;
+        }
+    }
+
+    for calibration in calibration.iter().chain(&altitude_correction) {
+        summary.add_calibration(calibration);
+    }
+
+    receiver.finish().unwrap();
+
+    for (port, stats, missing_frames) in all_stats {
+        summary.add_stream(&stats, &missing_frames);
+
+        // only named when there are several to tell apart
+        let stream = if num_streams > 1 {
+            format!("port {}, ", port)
+        } else {
+            String::new()
+        };
+
+        // every split closes one segment and opens the next
+        let segments = 1
+            + cli.split_on_time_gap as usize * stats.time_discontinuities
+            + cli.split_on_reconfiguration as usize * stats.reconfigurations;
+
+        if stats.time_discontinuities > 0 {
+            eprintln!(
+                "Frame timestamps were discontinuous. ({}{} jumps, {} segments written)",
+                stream, stats.time_discontinuities, segments
+            );
+        }
+
+        if stats.reconfigurations > 0 {
+            eprintln!(
+                "Sensor was reinitialized during the capture. ({}{} init_id changes, {} segments written)",
+                stream, stats.reconfigurations, segments
+            );
+        }
+
+        if stats.recovered_points > 0 {
+            eprintln!(
+                "Recover columns of datagrams missing fragments. ({}{} points)",
+                stream, stats.recovered_points
+            );
+        }
+
+        if stats.discarded_frames > 0 {
+            let placeholders = if cli.emit_placeholders {
+                stats.discarded_frames
+            } else {
+                0
+            };
+
+            eprintln!(
+                "Discard broken or incomplete frames. ({}{} frames written, {} discarded, {} placeholders)",
+                stream, stats.frames, stats.discarded_frames, placeholders
+            );
+        }
+
+        if let Some((expected, tolerance)) = cli.expected_frames {
+            if stats.frames.abs_diff(expected) > tolerance {
+                frames_ok = false;
+
+                // a long loss report would bury the summary
+                let mut missing: Vec<String> = missing_frames
+                    .iter()
+                    .take(10)
+                    .map(|&(first, last)| {
+                        if first == last {
+                            first.to_string()
+                        } else {
+                            format!("{}-{}", first, last)
+                        }
+                    })
+                    .collect();
+                if missing_frames.len() > 10 {
+                    missing.push(format!("{} more ranges", missing_frames.len() - 10));
+                }
+
+                eprintln!(
+                    "Unexpected number of frames. ({}{} written, {} +-{} expected, missing frame_id {})",
+                    stream,
+                    stats.frames,
+                    expected,
+                    tolerance,
+                    if missing.is_empty() {
+                        "none".to_string()
+                    } else {
+                        missing.join(", ")
+                    }
+                );
+            }
+        }
+    }
+
+    if let Err(err) = profiler.finish() {
+        eprintln!("Failed to write profile. ({})", err);
+    }
+
+    if let Some(path) = &cli.summary {
+        summary.save(path);
+    }
+
+    if let Some(baseline) = &cli.compare_summary {
+        if !summary.compare(baseline, cli.max_loss_increase) {
+            frames_ok = false;
+        }
+    }
+
+    if cli.verify_output {
+        let mut errors = Vec::new();
+        for stream in &streams {
+            errors.append(&mut verify::verify_pcd(&stream.output));
+        }
+        if let Some(scene_out) = &cli.scene_out {
+            errors.append(&mut verify::verify_pcd(scene_out));
+        }
+        if let Some(build_map) = &cli.build_map {
+            errors.append(&mut verify::verify_pcd(build_map));
+        }
+
+        for (path, err) in &errors {
+            eprintln!("Broken output file. ({}: {})", path.display(), err);
+        }
+
+        if !errors.is_empty() {
+            exit(1);
+        }
+    }
+
+    if cancelled {
+        eprintln!("Stop on interrupt. (frames completed before it were written)");
+        exit(130);
+    }
+
+    if !frames_ok {
+        exit(1);
+    }
+}
+
+/// Feed the length-prefixed payloads of a file, or of stdin for -, to a parser.
+fn read_raw_payloads(input: &Path, parser: &mut Legacy, profiler: &Profiler) {
+    let process = |payload: &[u8]| {
+        let _span = profiler.span("lidar", MAIN_THREAD);
+        parser.put(payload);
+    };
+
+    let result = if input == Path::new("-") {
+        for_each_payload(io::stdin().lock(), process)
+    } else {
+        for_each_payload(BufReader::new(File::open(input).unwrap()), process)
+    };
+
+    if let Err(err) = result {
+        eprintln!("Stop reading raw payloads. ({})", err);
+    }
+}
+
+/// Set up the parser of one stream with the options of the command line.
+#[allow(clippy::too_many_arguments)]
+fn build_parser(
+    cli: &Cli,
+    stream: &Stream,
+    profiler: &Profiler,
+    progress: &Progress,
+    cancel: &Arc<AtomicBool>,
+    hash: Option<SharedHash>,
+    calibration: Option<&Calibration>,
+    altitude_correction: Option<&Calibration>,
+) -> Legacy {
+    let meta = match cli.sensor_model {
+        Some(sensor_model) => sensor_model.metadata(),
+        None => std::fs::read(&stream.meta).unwrap(),
+    };
+    let output_path = stream.output.as_path();
+
+    let mut pcd_format = PcdFormat {
+        intensity_name: cli.intensity_name.clone(),
+        comments: !cli.no_comments,
+        metadata: if cli.embed_metadata {
+            let mut metadata = ouster::describe_sensor(&meta);
+            metadata.extend(
+                calibration
+                    .iter()
+                    .chain(&altitude_correction)
+                    .map(|calibration| calibration.describe()),
+            );
+            metadata
+        } else {
+            Vec::new()
+        },
+        ascii: cli.ascii,
+    };
+
+    let mut builder = Legacy::builder().metadata(&meta[..]);
+
+    if let Some(hash) = hash {
+        builder = builder.sink(Box::new(PcdStreamSink::new(hash, pcd_format.clone())));
+    }
+
+    // the options differ between runs that should hash the same, so only
+    // the written files record them
+    if cli.embed_metadata {
+        pcd_format.metadata.push(describe_conversion());
+    }
+
+    if !cli.map_only && !cli.hash_only {
+        builder = builder.sink(pcd_sink(cli, output_path, pcd_format));
+    }
+
+    if cli.column_timestamps {
+        builder = builder.sink(Box::new(ColumnTimestampSink::new(output_path, cli.shards)));
+    }
+
+    if let Some(mode) = cli.bev {
+        let grid = match BevGrid::new(mode, cli.bev_resolution, cli.bev_extent) {
+            Ok(grid) => grid,
+            Err(err) => {
+                eprintln!("Invalid configuration. ({})", err);
+                exit(1);
+            }
+        };
+        builder = builder.sink(Box::new(BevSink::new(output_path, cli.shards, grid)));
+    }
+
+    if let Some(scene_out) = &cli.scene_out {
+        builder = builder.sink(Box::new(SceneSink::new(
+            scene_out,
+            cli.frame[0],
+            cli.scene_voxel,
+            &cli.intensity_name,
+        )));
+    }
+
+    if let Some(invalid_points) = &cli.invalid_points {
+        let file = File::create(invalid_points).unwrap();
+        builder = builder.invalid_points(Box::new(BufWriter::new(file)));
+    }
+
+    if let Some(build_map) = &cli.build_map {
+        let trajectory =
+            match Trajectory::load(cli.trajectory.as_ref().unwrap(), cli.pose_tolerance) {
+                Ok(trajectory) => trajectory,
+                Err(err) => {
+                    eprintln!("Invalid trajectory. ({})", err);
+                    exit(1);
+                }
+            };
+
+        builder = builder.sink(Box::new(
+            SceneSink::new(
+                build_map,
+                cli.frame[0],
+                cli.scene_voxel,
+                &cli.intensity_name,
+            )
+            .trajectory(trajectory),
+        ));
+    }
+
+    if let Some(time_index) = &cli.time_index {
+        builder = builder.sink(Box::new(TimeIndexSink::new(time_index, cli.shards)));
+    }
+
+    let builder = builder
+        .sink(Box::new(ProgressSink::new(progress.clone())))
+        .digit(cli.digit)
+        .name_template(&cli.name)
+        .intensity_bands(cli.split_by_intensity.clone())
+        .intensity_field(cli.intensity)
+        .validity(cli.validity)
+        .udp_profile(cli.udp_profile)
+        .second_return(cli.second_return)
+        .range_difference(cli.range_difference)
+        .frames(cli.frame.clone())
+        .calibration(calibration.cloned())
+        .altitude_correction(altitude_correction.cloned())
+        .veil_angle(cli.remove_veil)
+        .colorize(cli.colorize)
+        .beam_angles(cli.beam_angles)
+        .column_timestamps(cli.column_timestamps)
+        .origin(cli.origin)
+        .dir_layout(cli.dir_layout)
+        .flush_stuck_frames(cli.flush_stuck_frames)
+        .lenient(cli.lenient)
+        .merge_columns(cli.merge_columns)
+        .point_stride(cli.point_stride)
+        .max_points(cli.max_points_per_frame, cli.sampling)
+        .points_per_file(cli.points_per_file)
+        .max_time_gap(cli.max_time_gap)
+        .split_on_time_gap(cli.split_on_time_gap)
+        .split_on_reconfiguration(cli.split_on_reconfiguration)
+        .emit_placeholders(cli.emit_placeholders)
+        .column_stride(cli.column_stride)
+        .profiler(profiler.clone())
+        .cancel_on(cancel.clone());
+
+    match builder.build() {
+        Ok(parser) => parser,
+        Err(err @ OusterError::Config(_)) => {
+            eprintln!("Invalid configuration. ({})", err);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!(
+                "Failed to read metadata, check the --meta file. ({}: {})",
+                stream.meta.display(),
+                err
+            );
+            exit(1);
+        }
+    }
+}
+
+/// The PCD writer of the --io-backend, or the std one where io_uring
+/// isn't available.
+fn pcd_sink(cli: &Cli, output_path: &Path, format: PcdFormat) -> Box<dyn FrameSink + Send> {
+    if cli.io_backend == IoBackend::Uring {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        match UringPcdSink::new(output_path, cli.shards, format.clone(), cli.queue_depth) {
+            Ok(sink) => return Box::new(sink),
+            Err(err) => eprintln!(
+                "Fall back to std file writes. (io_uring unavailable: {})",
+                err
+            ),
+        }
+
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        eprintln!("Fall back to std file writes. (built without io_uring support)");
+    }
+
+    Box::new(PcdSink::new(output_path, cli.shards, format))
+}
+
+/// Turns captured ethernet frames into lidar udp payloads.
+fn process_packet(
+    receiver: &mut Receiver,
+    packet: &CapturedPacket,
+    parsers: &mut [(u16, Legacy)],
+    profiler: &Profiler,
+    progress: &Progress,
+) {
+    // a strict run stops at the first malformed fragment, any run on interrupt
+    if receiver.error().is_some() || receiver.is_cancelled() {
+        return;
+    }
+
+    receiver.start_packet();
+    process_packet_traced(receiver, packet, parsers, profiler, progress);
+
+    if let Some(trail) = receiver.take_trail() {
+        let timestamp = packet
+            .timestamp
+            .map_or(String::new(), |timestamp| format!(" at {:.6}", timestamp));
+        eprintln!("pkt {}{}: {}", packet.number, timestamp, trail.join(", "));
+    }
+}
+
+fn process_packet_traced(
+    receiver: &mut Receiver,
+    packet: &CapturedPacket,
+    parsers: &mut [(u16, Legacy)],
+    profiler: &Profiler,
+    progress: &Progress,
+) {
+    if packet.is_truncated() {
+        receiver.step(|| {
+            format!(
+                "truncated to {} of {} B, skipped",
+                packet.caplen, packet.origlen
+            )
+        });
+
+        if receiver.is_lidar_packet(packet.data) {
+            let message = format!(
+                "Skip truncated lidar packet. ({} of {} bytes captured)",
+                packet.caplen, packet.origlen
+            );
+            eprintln!("{}", message);
+            progress.warning(&message);
+        }
+        return;
+    }
+
+    let span = profiler.span("reassembly", MAIN_THREAD);
+    let data = receiver.receive(packet);
+    drop(span);
+
+    // datagrams given up on were sent before the one completing now
+    for (port, partial) in receiver.take_partials() {
+        if let Some((_, parser)) = parsers.iter_mut().find(|(other, _)| *other == port) {
+            let _span = profiler.span("lidar", MAIN_THREAD);
+            receiver.step(|| format!("{} B recovered from an incomplete datagram", partial.len()));
+            parser.put_partial(&partial);
+        }
+    }
+
+    if let Some((port, data)) = data {
+        let _span = profiler.span("lidar", MAIN_THREAD);
+        let parser = match parsers.iter_mut().find(|(other, _)| *other == port) {
+            Some((_, parser)) => parser,
+            None => return,
+        };
+        let stats = parser.stats();
+        parser.put(&data);
+
+        if receiver.is_tracing() {
+            let after = parser.stats();
+            let expected_len = parser.expected_packet_len();
+
+            receiver.step(|| {
+                if data.len() < expected_len {
+                    format!(
+                        "payload {} B < expected {} B, frame marked broken",
+                        data.len(),
+                        expected_len
+                    )
+                } else if after.invalid_columns > stats.invalid_columns {
+                    format!(
+                        "{} invalid columns, frame marked broken",
+                        after.invalid_columns - stats.invalid_columns
+                    )
+                } else if after.frames > stats.frames {
+                    format!("{} B parsed, previous frame completed", data.len())
+                } else {
+                    format!("{} B parsed", data.len())
+                }
+            });
+        }
+    }
+}
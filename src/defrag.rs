@@ -0,0 +1,141 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+/// IPv4 header fields of the fragments a datagram was received in.
+pub struct DatagramHeader {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub id: u16,
+    pub ttl: u8,
+}
+
+/// Writes lidar datagrams into a pcapng file as single unfragmented
+/// ethernet/IPv4/UDP packets, whatever fragments they arrived in.
+pub struct DefragWriter {
+    writer: BufWriter<File>,
+}
+
+impl DefragWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        // section header, little endian, version 1.0, unknown length
+        write_block(
+            &mut writer,
+            0x0A0D_0D0A,
+            &[
+                &0x1A2B_3C4Du32.to_le_bytes()[..],
+                &1u16.to_le_bytes(),
+                &0u16.to_le_bytes(),
+                &(-1i64).to_le_bytes(),
+            ]
+            .concat(),
+        )?;
+
+        // ethernet interface without snaplen, in the default microseconds
+        write_block(
+            &mut writer,
+            0x0000_0001,
+            &[
+                &1u16.to_le_bytes()[..],
+                &0u16.to_le_bytes(),
+                &0u32.to_le_bytes(),
+            ]
+            .concat(),
+        )?;
+
+        Ok(Self { writer })
+    }
+
+    /// Write `datagram`, a udp header and payload, behind the link header
+    /// of its last fragment and a synthesized IPv4 header.
+    pub fn write(
+        &mut self,
+        link_header: &[u8],
+        header: &DatagramHeader,
+        datagram: &[u8],
+        timestamp: Option<f64>,
+    ) -> io::Result<()> {
+        let total_len = match u16::try_from(20 + datagram.len()) {
+            Ok(total_len) => total_len,
+            Err(_) => return Ok(()),
+        };
+
+        let mut ipv4 = [0u8; 20];
+        ipv4[0] = 0x45;
+        ipv4[2..4].copy_from_slice(&total_len.to_be_bytes());
+        ipv4[4..6].copy_from_slice(&header.id.to_be_bytes());
+        ipv4[8] = header.ttl;
+        ipv4[9] = 17;
+        ipv4[12..16].copy_from_slice(&header.source.octets());
+        ipv4[16..20].copy_from_slice(&header.destination.octets());
+
+        let checksum = ipv4_checksum(&ipv4);
+        ipv4[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        let packet = [link_header, &ipv4, datagram].concat();
+        let ts = timestamp.map_or(0, |timestamp| (timestamp * 1e6).round() as u64);
+
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+
+        write_block(&mut self.writer, 0x0000_0006, &body)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write a pcapng block, padding its body to 32 bits.
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&[0u8; 3][..padding])?;
+    writer.write_all(&len.to_le_bytes())
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
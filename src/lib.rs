@@ -0,0 +1,102 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+//! Parse Ouster lidar data into PCD files.
+//!
+//! The `ouster_parser` binary is a command line over this library, only
+//! parsing its arguments into a `cli::Cli` to hand to `cli::run`. To
+//! parse lidar udp payloads received some other way, e.g. from a live
+//! socket, build a `Legacy` parser with the sensor's metadata and sinks
+//! for the completed frames, and `put` every payload into it:
+//!
+//! ```no_run
+//! use std::{fs::File, path::Path};
+//!
+//! use ouster_parser::{Legacy, PcdFormat, PcdSink};
+//!
+//! let format = PcdFormat {
+//!     intensity_name: "intensity".to_string(),
+//!     comments: true,
+//!     metadata: Vec::new(),
+//!     ascii: false,
+//! };
+//!
+//! let mut parser = Legacy::builder()
+//!     .metadata(File::open("metadata.json").unwrap())
+//!     .sink(Box::new(PcdSink::new(Path::new("out"), 1, format)))
+//!     .build()
+//!     .unwrap();
+//!
+//! # let payloads: Vec<Vec<u8>> = Vec::new();
+//! for payload in payloads {
+//!     parser.put(&payload);
+//! }
+//!
+//! parser.finish().unwrap();
+//! ```
+//!
+//! Ethernet frames, e.g. from a raw socket or a capture, go through a
+//! `Receiver` first, which puts fragmented ipv4 datagrams back together
+//! and hands out the payloads sent to the lidar ports:
+//!
+//! ```no_run
+//! use ouster_parser::{IPV4Seq, Receiver};
+//!
+//! let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false));
+//!
+//! # let frames: Vec<Vec<u8>> = Vec::new();
+//! for frame in frames {
+//!     if let Some((_port, payload)) = receiver.parse_packet(&frame) {
+//!         // put the payload into the parser of its port
+//!     }
+//! }
+//! ```
+
+pub mod batch;
+pub mod bev;
+pub mod calibration;
+pub mod capture;
+pub mod cli;
+pub mod color;
+pub mod convert;
+pub mod defrag;
+pub mod lock;
+pub mod ouster;
+pub mod preset;
+pub mod profile;
+pub mod progress;
+pub mod receiver;
+pub mod sampling;
+pub mod sequence;
+pub mod session;
+pub mod sink;
+pub mod stats;
+pub mod streams;
+pub mod summary;
+pub mod trajectory;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+pub mod validate;
+pub mod verify;
+pub mod xyz;
+
+pub use ouster::{Legacy, LegacyBuilder, OusterError, PointXYZ, Stats};
+pub use receiver::Receiver;
+pub use sequence::IPV4Seq;
+pub use sink::{Frame, FrameSink, PcdFormat, PcdSink};
@@ -0,0 +1,234 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{self, prelude::*, SeekFrom},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Lock files held by this process, emptied when it is interrupted or
+/// exits without dropping its locks.
+static HELD: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Advisory lock on the output directory held for the whole run.
+///
+/// The `.lock` file carries the pid of the holder. The flock itself is
+/// released by the kernel when a process dies, so a pid left in a file
+/// we can lock belongs to a crashed run.
+pub struct OutputLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl OutputLock {
+    /// Lock the output directory, or `None` if another process holds it.
+    pub fn acquire(output_path: &Path) -> io::Result<Option<Self>> {
+        let path = output_path.join(".lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut holder = String::new();
+        file.read_to_string(&mut holder)?;
+        let holder = holder.trim();
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                eprintln!(
+                    "Output directory is locked by another process. (pid {})",
+                    holder
+                );
+                return Ok(None);
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+
+        if !holder.is_empty() {
+            eprintln!("Break stale output directory lock. (pid {})", holder);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+
+        HELD.lock().unwrap().push(path.clone());
+
+        Ok(Some(Self { file, path }))
+    }
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // the file is emptied rather than removed, so a process waiting
+        // on the old inode can't end up locking an unlinked file
+        let _ = self.file.set_len(0);
+
+        let mut held = HELD.lock().unwrap();
+        if let Some(index) = held.iter().position(|path| *path == self.path) {
+            held.swap_remove(index);
+        }
+    }
+}
+
+/// Empty the lock files still held, before an exit skipping their Drop.
+pub fn release_all() {
+    let held = match HELD.lock() {
+        Ok(held) => held,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+
+    for path in held.iter() {
+        if let Ok(file) = OpenOptions::new().write(true).open(path) {
+            let _ = file.set_len(0);
+        }
+    }
+}
+
+/// Set `cancel` on the first SIGINT or SIGTERM, letting the run finish
+/// what it has written, and release the locks held before exiting on
+/// the next one.
+pub fn release_on_interrupt(cancel: Arc<AtomicBool>) {
+    let result = ctrlc::set_handler(move || {
+        if !cancel.swap(true, Ordering::Relaxed) {
+            eprintln!("Stop at the next packet. (interrupt again to exit now)");
+            return;
+        }
+
+        release_all();
+        process::exit(130);
+    });
+
+    if let Err(err) = result {
+        eprintln!("Keep output directory locks on interrupt. ({})", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::BufReader,
+        process::{Command, Stdio},
+    };
+
+    /// Directory the child process of `refuses_lock_held_by_another_process`
+    /// locks, unset when the tests run normally.
+    const CHILD_LOCK_DIR: &str = "OUSTER_PARSER_CHILD_LOCK_DIR";
+
+    fn lock_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ouster-parser-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn refuses_second_acquire() {
+        let dir = lock_dir("contention");
+
+        let lock = OutputLock::acquire(&dir).unwrap().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join(".lock")).unwrap(),
+            process::id().to_string()
+        );
+
+        // flock conflicts between open files, even within one process
+        assert!(OutputLock::acquire(&dir).unwrap().is_none());
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(dir.join(".lock")).unwrap(), "");
+        assert!(OutputLock::acquire(&dir).unwrap().is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_lock_held_by_another_process() {
+        let dir = lock_dir("processes");
+
+        // the test binary itself, running only the child half below
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "lock::tests::holds_lock_for_parent",
+                "--exact",
+                "--nocapture",
+            ])
+            .env(CHILD_LOCK_DIR, &dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // kept open until the child is done, so its last lines can't fail;
+        // the harness prints the test name on the same line
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        assert!(stdout.any(|line| line.unwrap().ends_with("locked")));
+
+        assert!(OutputLock::acquire(&dir).unwrap().is_none());
+        assert!(OutputLock::acquire(&dir).unwrap().is_none());
+        assert_eq!(
+            fs::read_to_string(dir.join(".lock")).unwrap(),
+            child.id().to_string()
+        );
+
+        // closing its stdin lets the child exit
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+
+        assert!(OutputLock::acquire(&dir).unwrap().is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Hold the lock of the parent's directory until stdin closes.
+    #[test]
+    fn holds_lock_for_parent() {
+        let dir = match std::env::var_os(CHILD_LOCK_DIR) {
+            Some(dir) => PathBuf::from(dir),
+            None => return,
+        };
+
+        let _lock = OutputLock::acquire(&dir).unwrap().unwrap();
+        // past the test harness's capture of println!
+        let mut stdout = io::stdout();
+        writeln!(stdout, "locked").unwrap();
+        stdout.flush().unwrap();
+
+        io::stdin().read_to_end(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn reports_missing_directory() {
+        let dir = lock_dir("missing");
+
+        assert!(OutputLock::acquire(&dir.join("absent")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -19,22 +19,214 @@
 
 use core::f32::consts::PI;
 use std::{
+    borrow::Cow,
+    fmt,
     fs::File,
-    io::prelude::*,
+    io::{self, Read, Write},
     path::{Path, PathBuf},
-    sync::mpsc::{self, Sender},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use clap::ValueEnum;
 use serde::Deserialize;
 use serde_json;
 
+use crate::{
+    calibration::Calibration,
+    color::{ColorSource, Colorize},
+    profile::{Profiler, MAIN_THREAD, WRITER_THREAD},
+    progress::ProgressUpdate,
+    sampling::{self, Sampling},
+    sink::{Frame, FrameSink},
+    xyz::{BeamGeometry, ColumnXyz},
+};
+
 #[derive(Deserialize)]
 struct MetaData {
     beam_altitude_angles: Vec<f32>,
     beam_azimuth_angles: Vec<f32>,
     beam_to_lidar_transform: Vec<f32>,
+    #[serde(default)]
+    lidar_to_sensor_transform: Option<Vec<f32>>,
     data_format: DataFormat,
+    #[serde(default)]
+    signal_multiplier: Option<f32>,
+    #[serde(default)]
+    udp_port_lidar: Option<u16>,
+    #[serde(default)]
+    lidar_mode: Option<String>,
+    #[serde(default)]
+    udp_profile_lidar: Option<String>,
+    #[serde(default)]
+    config_params: Option<ConfigParams>,
+}
+
+#[derive(Deserialize)]
+struct ConfigParams {
+    #[serde(default)]
+    signal_multiplier: Option<f32>,
+    #[serde(default)]
+    udp_port_lidar: Option<u16>,
+    #[serde(default)]
+    lidar_mode: Option<String>,
+    #[serde(default)]
+    udp_profile_lidar: Option<String>,
+}
+
+/// Why a parser could not be set up.
+#[derive(Debug)]
+pub enum OusterError {
+    /// Metadata that is not json, e.g. truncated
+    Json(serde_json::Error),
+    /// Json metadata with a missing or mistyped field
+    Field(serde_json::Error),
+    /// A metadata array not matching the beams or transform size
+    Length {
+        field: &'static str,
+        len: usize,
+        expected: usize,
+    },
+    /// Options that are invalid or don't fit the metadata
+    Config(String),
+}
+
+impl fmt::Display for OusterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OusterError::Json(err) => write!(f, "metadata is not valid json: {}", err),
+            OusterError::Field(err) => write!(f, "metadata is not Ouster metadata: {}", err),
+            OusterError::Length {
+                field,
+                len,
+                expected,
+            } => write!(
+                f,
+                "metadata {} has {} values, expected {}",
+                field, len, expected
+            ),
+            OusterError::Config(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for OusterError {}
+
+impl From<serde_json::Error> for OusterError {
+    fn from(err: serde_json::Error) -> Self {
+        match err.classify() {
+            serde_json::error::Category::Data => OusterError::Field(err),
+            _ => OusterError::Json(err),
+        }
+    }
+}
+
+impl From<String> for OusterError {
+    fn from(err: String) -> Self {
+        OusterError::Config(err)
+    }
+}
+
+impl MetaData {
+    fn from_reader<R: Read>(reader: R) -> Result<Self, OusterError> {
+        let metadata: MetaData = serde_json::from_reader(reader)?;
+        metadata.check_lengths()?;
+        Ok(metadata)
+    }
+
+    /// Check the per-beam arrays and transforms, which are indexed
+    /// without bounds checks while parsing.
+    fn check_lengths(&self) -> Result<(), OusterError> {
+        let pixels_per_column = self.data_format.pixels_per_column;
+
+        let arrays = [
+            (
+                "beam_altitude_angles",
+                self.beam_altitude_angles.len(),
+                pixels_per_column,
+            ),
+            (
+                "beam_azimuth_angles",
+                self.beam_azimuth_angles.len(),
+                pixels_per_column,
+            ),
+            (
+                "beam_to_lidar_transform",
+                self.beam_to_lidar_transform.len(),
+                16,
+            ),
+        ];
+
+        let transform = self
+            .lidar_to_sensor_transform
+            .as_ref()
+            .map(|transform| ("lidar_to_sensor_transform", transform.len(), 16));
+
+        for (field, len, expected) in arrays.into_iter().chain(transform) {
+            if len != expected {
+                return Err(OusterError::Length {
+                    field,
+                    len,
+                    expected,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Packet layout of the lidar data, LEGACY when not configured as in
+    /// firmware before 2.x.
+    fn udp_profile(&self) -> Result<UdpProfile, String> {
+        UdpProfile::from_metadata(self.udp_profile_name())
+    }
+
+    fn udp_profile_name(&self) -> &str {
+        self.udp_profile_lidar
+            .as_ref()
+            .or(self
+                .config_params
+                .as_ref()
+                .and_then(|params| params.udp_profile_lidar.as_ref()))
+            .map_or("LEGACY", String::as_str)
+    }
+
+    fn udp_port_lidar(&self) -> Option<u16> {
+        self.udp_port_lidar.or(self
+            .config_params
+            .as_ref()
+            .and_then(|params| params.udp_port_lidar))
+    }
+
+    /// Nominal time between frames in nanoseconds, from a lidar_mode
+    /// such as `1024x10`.
+    fn frame_period(&self) -> Option<u64> {
+        let lidar_mode = self.lidar_mode.as_ref().or(self
+            .config_params
+            .as_ref()
+            .and_then(|params| params.lidar_mode.as_ref()))?;
+
+        match lidar_mode.split_once('x')?.1.parse::<u64>() {
+            Ok(rate) if rate > 0 => Some(1_000_000_000 / rate),
+            _ => None,
+        }
+    }
+
+    fn signal_multiplier(&self) -> f32 {
+        self.signal_multiplier
+            .or(self
+                .config_params
+                .as_ref()
+                .and_then(|params| params.signal_multiplier))
+            .unwrap_or(1.0)
+    }
 }
 
 #[derive(Deserialize)]
@@ -42,280 +234,3179 @@ struct DataFormat {
     columns_per_frame: usize,
     columns_per_packet: usize,
     pixels_per_column: usize,
+    #[serde(default)]
+    column_window: Option<[usize; 2]>,
 }
 
-struct HeaderBlock {
-    timestamp: u64,
-    measure_id: u16,
-    frame_id: u16,
+impl DataFormat {
+    fn in_window(&self, measure_id: usize) -> bool {
+        match self.column_window {
+            Some([first, last]) if first <= last => measure_id >= first && measure_id <= last,
+            Some([first, last]) => measure_id >= first || measure_id <= last,
+            None => true,
+        }
+    }
+
+    /// Number of columns of a frame kept with every `stride`th measure_id.
+    fn active_columns(&self, stride: usize) -> usize {
+        (0..self.columns_per_frame)
+            .filter(|&measure_id| self.in_window(measure_id) && measure_id % stride == 0)
+            .count()
+    }
 }
 
-struct PointXYZ {
-    x: f32,
-    y: f32,
-    z: f32,
-    reflect: f32,
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum CoordinateFrame {
+    Lidar,
+    Sensor,
 }
 
-struct FileData {
-    header: String,
-    data: Vec<u8>,
-    path: PathBuf,
+impl CoordinateFrame {
+    fn dirname(&self) -> &'static str {
+        match self {
+            CoordinateFrame::Lidar => "lidar",
+            CoordinateFrame::Sensor => "sensor",
+        }
+    }
 }
 
-pub struct Legacy<'a> {
-    metadata: MetaData,
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum DirLayout {
+    Flat,
+    Time,
+}
 
-    n: f32,
-    azimuths: Vec<f32>,
-    cos_phis: Vec<f32>,
-    sin_phis: Vec<f32>,
+/// `YYYY-MM-DD/HH` of a timestamp in nanoseconds since the epoch.
+fn time_dirname(timestamp: u64) -> PathBuf {
+    let seconds = timestamp / 1_000_000_000;
+    let days = (seconds / 86400) as i64;
+    let hour = seconds % 86400 / 3600;
 
-    current_frame: u16,
-    current_timestamp: u64,
-    current_points: Vec<f32>,
-    current_num_points: usize,
-    current_broken: bool,
+    // civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
 
-    output_path: &'a Path,
-    id: usize,
-    digit: usize,
+    Path::new(&format!("{:04}-{:02}-{:02}", year, month, day)).join(format!("{:02}", hour))
+}
 
-    sender: Sender<FileData>,
+/// Which returns are kept as points; a zero range, i.e. no return, never is.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Validity {
+    Range,
+    RangeAndReflectivity,
+    /// Minimum range in millimeters and raw reflectivity
+    Custom {
+        min_range: u32,
+        min_reflectivity: u8,
+    },
 }
 
-impl<'a> Legacy<'a> {
-    pub fn new(meta_file: File, output_path: &'a Path, digit: usize) -> Self {
-        let metadata: MetaData = serde_json::from_reader(meta_file).unwrap();
+/// Criterion a return was dropped for.
+enum Exclusion {
+    Range,
+    Reflectivity,
+}
 
-        let beam_to_lidar = &metadata.beam_to_lidar_transform;
-        let beam_azimuth_angles = &metadata.beam_azimuth_angles;
-        let beam_altitude_angles = &metadata.beam_altitude_angles;
+impl Validity {
+    fn exclusion(&self, range: u32, reflectivity: u8) -> Option<Exclusion> {
+        let (min_range, min_reflectivity) = match *self {
+            Validity::Range => (1, 0),
+            Validity::RangeAndReflectivity => (1, 1),
+            Validity::Custom {
+                min_range,
+                min_reflectivity,
+            } => (min_range.max(1), min_reflectivity),
+        };
 
-        let n = (beam_to_lidar[3].powi(2) + beam_to_lidar[11].powi(2)).sqrt();
-        let azimuths: Vec<f32> = beam_azimuth_angles
-            .iter()
-            .map(|x| -2.0 * PI * (x / 360.0))
-            .collect();
-        let cos_phis: Vec<f32> = beam_altitude_angles
-            .iter()
-            .map(|x| (2.0 * PI * (x / 360.0)).cos())
-            .collect();
-        let sin_phis: Vec<f32> = beam_altitude_angles
-            .iter()
-            .map(|x| (2.0 * PI * (x / 360.0)).sin())
-            .collect();
+        if range < min_range {
+            Some(Exclusion::Range)
+        } else if reflectivity < min_reflectivity {
+            Some(Exclusion::Reflectivity)
+        } else {
+            None
+        }
+    }
+}
 
-        let (sender, receiver) = mpsc::channel::<FileData>();
+impl FromStr for Validity {
+    type Err = String;
 
-        std::thread::spawn(move || {
-            for file_data in receiver {
-                let mut file = File::create(file_data.path).unwrap();
-                file.write_all(file_data.header.as_bytes()).unwrap();
-                file.write_all(file_data.data.as_slice()).unwrap();
-            }
-        });
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "range" => return Ok(Validity::Range),
+            "range-and-reflectivity" => return Ok(Validity::RangeAndReflectivity),
+            _ => (),
+        }
 
-        Self {
-            metadata,
-            n,
-            azimuths,
-            cos_phis,
-            sin_phis,
-            current_frame: 0,
-            current_timestamp: 0,
-            current_points: Vec::new(),
-            current_num_points: 0,
-            current_broken: false,
-            output_path,
-            id: 0,
-            digit,
-            sender,
+        let custom = text
+            .strip_prefix("custom:")
+            .and_then(|values| values.split_once(','))
+            .and_then(|(min_range, min_reflectivity)| {
+                Some((
+                    min_range.trim().parse::<f32>().ok()?,
+                    min_reflectivity.trim().parse::<u8>().ok()?,
+                ))
+            });
+
+        match custom {
+            Some((min_range, min_reflectivity)) if min_range >= 0.0 => Ok(Validity::Custom {
+                min_range: (min_range * 1000.0).round() as u32,
+                min_reflectivity,
+            }),
+            _ => Err(format!(
+                "unknown validity {text} (range, range-and-reflectivity, custom:METERS,REFLECTIVITY)"
+            )),
         }
     }
+}
 
-    pub fn put(&mut self, data: &[u8]) {
-        let pixels_per_column = self.metadata.data_format.pixels_per_column;
-        let columns_per_packet = self.metadata.data_format.columns_per_packet;
+/// Layout of lidar udp packets, set by `udp_profile_lidar` in the metadata.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum UdpProfile {
+    Legacy,
+    Rng19Rfl8Sig16Nir16,
+    Rng19Rfl8Sig16Nir16Dual,
+    Rng15Rfl8Nir8,
+}
+
+impl UdpProfile {
+    fn from_metadata(name: &str) -> Result<Self, String> {
+        match name {
+            "LEGACY" => Ok(UdpProfile::Legacy),
+            "RNG19_RFL8_SIG16_NIR16" => Ok(UdpProfile::Rng19Rfl8Sig16Nir16),
+            "RNG19_RFL8_SIG16_NIR16_DUAL" => Ok(UdpProfile::Rng19Rfl8Sig16Nir16Dual),
+            "RNG15_RFL8_NIR8" => Ok(UdpProfile::Rng15Rfl8Nir8),
+            _ => Err(format!("unsupported udp_profile_lidar {}", name)),
+        }
+    }
 
-        let len_column = 20 + pixels_per_column * 12;
-        let len_expected = columns_per_packet * len_column;
+    /// Name of the profile in `udp_profile_lidar`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UdpProfile::Legacy => "LEGACY",
+            UdpProfile::Rng19Rfl8Sig16Nir16 => "RNG19_RFL8_SIG16_NIR16",
+            UdpProfile::Rng19Rfl8Sig16Nir16Dual => "RNG19_RFL8_SIG16_NIR16_DUAL",
+            UdpProfile::Rng15Rfl8Nir8 => "RNG15_RFL8_NIR8",
+        }
+    }
 
-        if data.len() < len_expected {
-            self.current_broken = true;
-            return;
+    /// Bytes before the first column of a packet.
+    fn packet_header_len(&self) -> usize {
+        match self {
+            UdpProfile::Legacy => 0,
+            _ => 32,
         }
+    }
 
-        for offset in (0..data.len()).step_by(len_column) {
-            self.parse_measure_block(&data[offset..offset + len_column]);
+    /// Bytes after the last column of a packet.
+    fn packet_footer_len(&self) -> usize {
+        match self {
+            UdpProfile::Legacy => 0,
+            _ => 32,
         }
     }
 
-    fn parse_measure_block(&mut self, data: &[u8]) {
-        let mut block_status_slice = &data[data.len() - 4..];
-        let block_status = block_status_slice.read_u32::<LittleEndian>().unwrap();
+    fn column_header_len(&self) -> usize {
+        match self {
+            UdpProfile::Legacy => 16,
+            _ => 12,
+        }
+    }
 
-        if block_status != 0xffffffff {
-            self.current_broken = true;
-            return;
+    /// Bytes after the channels of a column, the legacy status word.
+    fn column_footer_len(&self) -> usize {
+        match self {
+            UdpProfile::Legacy => 4,
+            _ => 0,
         }
+    }
 
-        let mut header = HeaderBlock {
-            timestamp: 0,
-            measure_id: 0,
-            frame_id: 0,
-        };
+    /// Bytes of the block of one beam in a column.
+    fn channel_len(&self) -> usize {
+        match self {
+            UdpProfile::Rng19Rfl8Sig16Nir16Dual => 16,
+            UdpProfile::Rng15Rfl8Nir8 => 4,
+            _ => 12,
+        }
+    }
 
-        let mut timestamp_slice = &data[..8];
-        header.timestamp = timestamp_slice.read_u64::<LittleEndian>().unwrap();
+    /// The 24-bit init_id of a packet header, which changes whenever the
+    /// sensor is reinitialized; LEGACY packets carry none.
+    fn init_id(&self, packet_header: &[u8]) -> Option<u32> {
+        match self {
+            UdpProfile::Legacy => None,
+            _ => Some(u32::from_le_bytes([
+                packet_header[4],
+                packet_header[5],
+                packet_header[6],
+                0,
+            ])),
+        }
+    }
 
-        let mut measure_id_slice = &data[8..10];
-        header.measure_id = measure_id_slice.read_u16::<LittleEndian>().unwrap();
+    fn column_len(&self, pixels_per_column: usize) -> usize {
+        self.column_header_len() + pixels_per_column * self.channel_len() + self.column_footer_len()
+    }
 
-        let mut frame_id_slice = &data[10..12];
-        header.frame_id = frame_id_slice.read_u16::<LittleEndian>().unwrap();
+    fn packet_len(&self, data_format: &DataFormat) -> usize {
+        self.packet_header_len()
+            + data_format.columns_per_packet * self.column_len(data_format.pixels_per_column)
+            + self.packet_footer_len()
+    }
 
-        if !self.set_current_state(&header) {
-            return;
+    /// Bits of the range in millimeters in a range word.
+    fn range_mask(&self) -> u32 {
+        match self {
+            UdpProfile::Legacy => 0x000f_ffff,
+            _ => 0x0007_ffff,
         }
+    }
 
-        let mut channel = 0;
+    /// Decode the first and, for dual-return profiles, second return of a
+    /// channel block. A second return with zero range had no second echo.
+    fn returns(&self, block: &[u8]) -> (Return, Option<Return>) {
+        let read_u16 = |at: usize| u16::from_le_bytes([block[at], block[at + 1]]);
+        let read_range = |at: usize| {
+            u32::from_le_bytes(block[at..at + 4].try_into().unwrap()) & self.range_mask()
+        };
 
-        for offset in (16..data.len() - 4).step_by(12) {
-            self.parse_data_block(&data[offset..offset + 12], header.measure_id, channel);
-            channel += 1;
-            self.current_num_points += 1;
+        match self {
+            UdpProfile::Legacy | UdpProfile::Rng19Rfl8Sig16Nir16 => (
+                Return {
+                    range: read_range(0),
+                    reflectivity: block[4],
+                    signal: read_u16(6),
+                    nir: read_u16(8),
+                },
+                None,
+            ),
+            // the reflectivity is the top byte of each range word
+            UdpProfile::Rng19Rfl8Sig16Nir16Dual => (
+                Return {
+                    range: read_range(0),
+                    reflectivity: block[3],
+                    signal: read_u16(8),
+                    nir: read_u16(12),
+                },
+                Some(Return {
+                    range: read_range(4),
+                    reflectivity: block[7],
+                    signal: read_u16(10),
+                    nir: read_u16(12),
+                }),
+            ),
+            UdpProfile::Rng15Rfl8Nir8 => (
+                Return {
+                    // in units of 8 mm
+                    range: (read_u16(0) & 0x7fff) as u32 * 8,
+                    reflectivity: block[2],
+                    signal: 0,
+                    // scaled to the 16-bit nir of the other profiles
+                    nir: (block[3] as u16) << 4,
+                },
+                None,
+            ),
         }
     }
 
-    fn parse_data_block(&mut self, data: &[u8], measure_id: u16, channel: usize) {
-        let mut range_slice = &data[..4];
-        let range = range_slice.read_u32::<LittleEndian>().unwrap() << 12 >> 12;
+    /// Split a column of a packet into its header, status and channels.
+    ///
+    /// Legacy columns carry their frame_id and a trailing status word,
+    /// newer profiles a status bit with the frame_id in the packet header.
+    fn column<'a>(&self, packet_header: &[u8], data: &'a [u8]) -> Column<'a> {
+        let channels = &data[self.column_header_len()..data.len() - self.column_footer_len()];
 
-        let reflect = data[4];
+        match self {
+            UdpProfile::Legacy => Column {
+                header: parse_header_block(data),
+                valid: is_block_valid(data),
+                status: u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap()),
+                channels,
+            },
+            _ => {
+                let status = u16::from_le_bytes([data[10], data[11]]);
 
-        if range == 0 || reflect == 0 {
-            return;
+                Column {
+                    header: HeaderBlock {
+                        timestamp: u64::from_le_bytes(data[..8].try_into().unwrap()),
+                        measure_id: u16::from_le_bytes([data[8], data[9]]),
+                        frame_id: u16::from_le_bytes([packet_header[2], packet_header[3]]),
+                    },
+                    valid: status & 1 != 0,
+                    status: status as u32,
+                    channels,
+                }
+            }
         }
+    }
+}
+
+/// One column of a lidar packet.
+struct Column<'a> {
+    header: HeaderBlock,
+    valid: bool,
+    /// Raw status word or bits, for display
+    status: u32,
+    /// Channel blocks, one per beam
+    channels: &'a [u8],
+}
 
-        let point = self.calculate_xyz(range as f32, reflect as f32, measure_id as f32, channel);
+/// One return of a beam, with the range in millimeters.
+struct Return {
+    range: u32,
+    reflectivity: u8,
+    signal: u16,
+    nir: u16,
+}
+
+/// What becomes of the second returns of dual-return profiles.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum SecondReturn {
+    Drop,
+    Merge,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum IntensityField {
+    Reflectivity,
+    Signal,
+    Nir,
+}
 
-        self.current_points.push(point.x);
-        self.current_points.push(point.y);
-        self.current_points.push(point.z);
-        self.current_points.push(point.reflect);
+impl IntensityField {
+    fn bit_depth(&self) -> u32 {
+        match self {
+            IntensityField::Reflectivity => 8,
+            IntensityField::Signal | IntensityField::Nir => 16,
+        }
     }
 
-    fn set_current_state(&mut self, header: &HeaderBlock) -> bool {
-        let columns_per_frame = self.metadata.data_format.columns_per_frame;
-        let pixels_per_column = self.metadata.data_format.pixels_per_column;
+    fn max_value(&self) -> f32 {
+        ((1u32 << self.bit_depth()) - 1) as f32
+    }
 
-        if self.current_broken {
-            if header.frame_id != self.current_frame {
-                self.current_broken = false;
-                self.current_points.clear();
-                self.current_num_points = 0;
-                return self.set_current_state(&header);
-            } else {
-                return false;
-            }
-        } else {
-            if header.frame_id != self.current_frame {
-                if self.current_num_points >= columns_per_frame * pixels_per_column {
-                    self.save_pcd();
-                }
+    fn read(&self, ret: &Return) -> u16 {
+        match self {
+            IntensityField::Reflectivity => ret.reflectivity as u16,
+            IntensityField::Signal => ret.signal,
+            IntensityField::Nir => ret.nir,
+        }
+    }
+}
 
-                self.current_points.clear();
-                self.current_num_points = 0;
-                self.current_frame = header.frame_id;
-                self.current_timestamp = header.timestamp;
-            } else {
-                if header.timestamp < self.current_timestamp {
-                    self.current_timestamp = header.timestamp;
-                }
-            }
+struct Pixel {
+    measure_id: u16,
+    channel: u16,
+    range: u32,
+}
+
+/// Sums of the returns of one beam over a group of merged columns.
+#[derive(Clone, Copy, Default)]
+struct MergedPixel {
+    range: u64,
+    intensity: u64,
+    count: u32,
+}
+
+struct HeaderBlock {
+    timestamp: u64,
+    measure_id: u16,
+    frame_id: u16,
+}
+
+/// Assembly state of the frame being received.
+#[derive(Default)]
+struct FrameState {
+    frame_id: u16,
+    timestamp: u64,
+    end_timestamp: u64,
+    points: Vec<f32>,
+    pixels: Vec<Pixel>,
+    num_points: usize,
+    /// Points of invalid columns skipped in lenient mode, only used to
+    /// tell when the frame has been received
+    skipped_points: usize,
+    /// Returns of the column group being merged, per beam
+    merged: Vec<MergedPixel>,
+    /// Sensor timestamp of each column by measure_id, 0 if not received
+    column_timestamps: Vec<u64>,
+    /// Returns dropped by the validity criteria
+    excluded_range: usize,
+    excluded_reflectivity: usize,
+    /// Seed the frame was subsampled with, if it was over the point cap
+    sampling_seed: Option<u64>,
+    merge_group: Option<usize>,
+    broken: bool,
+    stuck: bool,
+}
 
-            true
+impl FrameState {
+    /// Reserve room for a complete frame up front, so that steady-state
+    /// frames never reallocate.
+    fn reserve(&mut self, frame_points: usize, stride: usize, pixels: bool) {
+        self.points.reserve_exact(frame_points * stride);
+        if pixels {
+            self.pixels.reserve_exact(frame_points);
         }
     }
 
-    fn calculate_xyz(&self, range: f32, reflect: f32, measure_id: f32, channel: usize) -> PointXYZ {
-        let mut point = PointXYZ {
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
-            reflect: 0.0,
-        };
+    fn buffer_bytes(&self) -> usize {
+        self.points.capacity() * std::mem::size_of::<f32>()
+            + self.pixels.capacity() * std::mem::size_of::<Pixel>()
+    }
+
+    /// Start over on a new frame, keeping the buffers' capacity.
+    fn reset(&mut self, header: &HeaderBlock) {
+        // destructured so a new field can't be left out of the reset
+        let FrameState {
+            frame_id,
+            timestamp,
+            end_timestamp,
+            points,
+            pixels,
+            num_points,
+            skipped_points,
+            merged,
+            merge_group,
+            column_timestamps,
+            excluded_range,
+            excluded_reflectivity,
+            sampling_seed,
+            broken,
+            stuck,
+        } = self;
+
+        *frame_id = header.frame_id;
+        *timestamp = header.timestamp;
+        *end_timestamp = header.timestamp;
+        points.clear();
+        pixels.clear();
+        *num_points = 0;
+        *skipped_points = 0;
+        merged.fill(MergedPixel::default());
+        *merge_group = None;
+        column_timestamps.fill(0);
+        *excluded_range = 0;
+        *excluded_reflectivity = 0;
+        *sampling_seed = None;
+        *broken = false;
+        *stuck = false;
+    }
+}
+
+/// Packet and frame counts of a parser so far.
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub packets: usize,
+    /// Packets too short or with an invalid column
+    pub broken_packets: usize,
+    /// Invalid columns breaking their frame, uncounted in lenient mode
+    pub invalid_columns: usize,
+    pub frames: usize,
+    /// Frames dropped as broken or incomplete
+    pub discarded_frames: usize,
+    /// Largest size of the frame assembly buffers in bytes
+    pub peak_frame_bytes: usize,
+    /// Frame starts going backward or leaping past the allowed gap
+    pub time_discontinuities: usize,
+    /// Changes of the init_id in packet headers, as the sensor restarts
+    pub reconfigurations: usize,
+    /// Returns dropped for their range or reflectivity
+    pub excluded_range: usize,
+    pub excluded_reflectivity: usize,
+    /// Returns of complete columns parsed from datagrams that lost their tail
+    pub recovered_points: usize,
+}
+
+pub struct PointXYZ {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub reflect: f32,
+}
+
+/// Parser of lidar packets in the LEGACY and the single, dual-return and low
+/// data rate udp profiles.
+///
+/// It owns everything it needs, so it can be moved to another thread.
+pub struct Legacy {
+    metadata: MetaData,
+
+    geometry: BeamGeometry,
+    /// Ranges of the first and any second returns of the column being
+    /// parsed, by channel
+    column_ranges: [Vec<f32>; 2],
+    /// Points of `column_ranges`
+    column_xyz: [ColumnXyz; 2],
+
+    current: FrameState,
 
-        let column_per_frame = self.metadata.data_format.columns_per_frame as f32;
-        let beam_to_lidar = &self.metadata.beam_to_lidar_transform;
+    id: usize,
+    stats: Stats,
+    digit: usize,
+    name_template: String,
+    intensity_bands: Vec<f32>,
+    intensity_field: IntensityField,
+    validity: Validity,
+    udp_profile: UdpProfile,
+    second_return: SecondReturn,
+    range_difference: bool,
+    frames: Vec<CoordinateFrame>,
+    veil_angle: Option<f32>,
+    colorize: Option<Colorize>,
+    beam_angles: bool,
+    column_timestamps: bool,
+    dir_layout: DirLayout,
+    flush_stuck_frames: bool,
+    emit_placeholders: bool,
+    lenient: bool,
+    column_stride: usize,
+    merge_columns: usize,
+    point_stride: usize,
+    /// Most points kept of a frame, subsampled with `sampling`
+    max_points: Option<usize>,
+    sampling: Sampling,
+    /// Most points written into one file, 0 for no limit
+    points_per_file: usize,
+    /// CSV of the pixels without a valid return
+    invalid_points: Option<Box<dyn Write + Send>>,
+    /// Largest gap between frame starts, in nominal frame periods
+    max_time_gap: f32,
+    split_on_time_gap: bool,
+    split_on_reconfiguration: bool,
+    /// init_id of the previous packet, in profiles that carry one
+    init_id: Option<u32>,
+    /// Start of the previous frame
+    last_frame_start: Option<u64>,
+    /// frame_id of every frame written, in order
+    written_frame_ids: Vec<u16>,
+    /// Number of the current continuous stretch of frames
+    segment: usize,
+    /// Pixels of a complete frame, given the window and column stride
+    frame_points: usize,
+    /// Most points a pixel yields, two when merging second returns
+    returns_per_pixel: usize,
+    /// Size of the buffers reserved for a complete frame
+    frame_bytes: usize,
 
-        let encoder = 2.0 * PI * (1.0 - measure_id / column_per_frame);
+    profiler: Profiler,
+    xyz_time: Duration,
 
-        point.x =
-            ((range - self.n) * (encoder + self.azimuths[channel]).cos() * self.cos_phis[channel]
-                + beam_to_lidar[3] * encoder.cos())
-                / 1000.0;
+    /// Set to stop parsing, leaving the frame being assembled unwritten
+    cancel: Option<Arc<AtomicBool>>,
+    /// Lidar payload bytes put so far
+    bytes_processed: usize,
+    /// Frames written by every sink, counted by the writer thread
+    frames_written: Arc<AtomicUsize>,
+    /// Callback given the progress at most once per interval
+    on_progress: Option<(Duration, ProgressCallback)>,
+    last_progress: Instant,
 
-        point.y =
-            ((range - self.n) * (encoder + self.azimuths[channel]).sin() * self.cos_phis[channel]
-                + beam_to_lidar[3] * encoder.sin())
-                / 1000.0;
+    sender: Sender<Frame>,
+    /// Writer thread, stopped at the first error of a sink
+    writer: JoinHandle<io::Result<()>>,
+}
 
-        point.z = ((range - self.n) * self.sin_phis[channel] + beam_to_lidar[11]) / 1000.0;
+/// Configuration of a `Legacy` parser, validated by `build`.
+///
+/// Only the metadata is required. Without sinks, frames are parsed but
+/// not written anywhere.
+pub struct LegacyBuilder {
+    metadata: Option<Result<MetaData, OusterError>>,
+    sinks: Vec<Box<dyn FrameSink + Send>>,
+    digit: usize,
+    name_template: String,
+    intensity_bands: Vec<f32>,
+    intensity_field: IntensityField,
+    validity: Validity,
+    udp_profile: Option<UdpProfile>,
+    second_return: SecondReturn,
+    range_difference: bool,
+    frames: Vec<CoordinateFrame>,
+    calibration: Option<Calibration>,
+    altitude_correction: Option<Calibration>,
+    veil_angle: Option<f32>,
+    colorize: Option<Colorize>,
+    beam_angles: bool,
+    column_timestamps: bool,
+    origin: Option<[f32; 3]>,
+    dir_layout: DirLayout,
+    flush_stuck_frames: bool,
+    emit_placeholders: bool,
+    lenient: bool,
+    column_stride: usize,
+    merge_columns: usize,
+    point_stride: usize,
+    max_points: Option<usize>,
+    sampling: Sampling,
+    points_per_file: Option<usize>,
+    invalid_points: Option<Box<dyn Write + Send>>,
+    max_time_gap: f32,
+    split_on_time_gap: bool,
+    split_on_reconfiguration: bool,
+    profiler: Profiler,
+    cancel: Option<Arc<AtomicBool>>,
+    on_progress: Option<(Duration, ProgressCallback)>,
+}
 
-        point.reflect = reflect / u8::MAX as f32;
+type ProgressCallback = Box<dyn FnMut(ProgressUpdate) + Send>;
 
-        point
+impl LegacyBuilder {
+    /// Sensor metadata json.
+    pub fn metadata<R: Read>(mut self, reader: R) -> Self {
+        self.metadata = Some(MetaData::from_reader(reader));
+        self
     }
 
-    fn save_pcd(&mut self) {
-        //// safe but slow
-        // let buffer: Vec<u8> = self
-        //     .current_points
-        //     .iter()
-        //     .flat_map(|x| x.to_le_bytes().to_vec())
-        //     .collect();
+    /// Adds a sink receiving every completed frame, in the order added.
+    pub fn sink(mut self, sink: Box<dyn FrameSink + Send>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
 
-        // unsafe little endian in x86
-        let buffer = unsafe {
-            std::slice::from_raw_parts(
-                self.current_points.as_ptr() as *const u8,
-                self.current_points.len() * std::mem::size_of::<f32>(),
-            )
-        };
+    /// Minimum number of digits of the frame number in output names.
+    pub fn digit(mut self, digit: usize) -> Self {
+        self.digit = digit;
+        self
+    }
 
-        let pcd_header = format!(
-            "# .PCD v.7 - Point Cloud Data file format\n\
-             # timestamp: {}\n\
-             VERSION .7\n\
-             FIELDS x y z intensity\n\
-             SIZE 4 4 4 4\n\
-             TYPE F F F F\n\
-             COUNT 1 1 1 1\n\
-             WIDTH {}\n\
-             HEIGHT 1\n\
-             VIEWPOINT 0 0 0 1 0 0 0\n\
-             POINTS {}\n\
-             DATA binary\n",
-            self.current_timestamp,
-            self.current_points.len() / 4,
-            self.current_points.len() / 4
-        );
+    /// Output file name, with `{id}` for the frame number and
+    /// `{completeness}` for the received share of the frame.
+    pub fn name_template(mut self, name_template: &str) -> Self {
+        self.name_template = name_template.to_string();
+        self
+    }
 
-        let width = self.digit;
+    /// Raw intensity thresholds splitting each frame into bands.
+    pub fn intensity_bands(mut self, intensity_bands: Vec<f32>) -> Self {
+        self.intensity_bands = intensity_bands;
+        self
+    }
+
+    pub fn intensity_field(mut self, intensity_field: IntensityField) -> Self {
+        self.intensity_field = intensity_field;
+        self
+    }
+
+    /// Packet layout, overriding `udp_profile_lidar` in the metadata.
+    pub fn udp_profile(mut self, udp_profile: Option<UdpProfile>) -> Self {
+        self.udp_profile = udp_profile;
+        self
+    }
+
+    /// Whether second returns of dual-return profiles become points too.
+    pub fn second_return(mut self, second_return: SecondReturn) -> Self {
+        self.second_return = second_return;
+        self
+    }
+
+    /// Add the distance in meters from the first to the second return of
+    /// each pixel of dual-return profiles, NaN without a second return.
+    pub fn range_difference(mut self, range_difference: bool) -> Self {
+        self.range_difference = range_difference;
+        self
+    }
+
+    /// Which returns are kept as points.
+    pub fn validity(mut self, validity: Validity) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Coordinate frames each frame is written in.
+    pub fn frames(mut self, frames: Vec<CoordinateFrame>) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    pub fn calibration(mut self, calibration: Option<Calibration>) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    /// Altitude deltas applied on top of the metadata and any calibration.
+    pub fn altitude_correction(mut self, altitude_correction: Option<Calibration>) -> Self {
+        self.altitude_correction = altitude_correction;
+        self
+    }
+
+    /// Veil filter threshold in degrees.
+    pub fn veil_angle(mut self, veil_angle: Option<f32>) -> Self {
+        self.veil_angle = veil_angle;
+        self
+    }
+
+    pub fn colorize(mut self, colorize: Option<Colorize>) -> Self {
+        self.colorize = colorize;
+        self
+    }
+
+    /// Add the elevation and encoder azimuth of each point's beam in degrees.
+    pub fn beam_angles(mut self, beam_angles: bool) -> Self {
+        self.beam_angles = beam_angles;
+        self
+    }
+
+    /// Keep the timestamp of every column with the frame.
+    pub fn column_timestamps(mut self, column_timestamps: bool) -> Self {
+        self.column_timestamps = column_timestamps;
+        self
+    }
+
+    /// Local origin in meters in the lidar frame, subtracted from every point.
+    pub fn origin(mut self, origin: Option<[f32; 3]>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Subdirectories the frames are placed in.
+    pub fn dir_layout(mut self, dir_layout: DirLayout) -> Self {
+        self.dir_layout = dir_layout;
+        self
+    }
+
+    pub fn flush_stuck_frames(mut self, flush_stuck_frames: bool) -> Self {
+        self.flush_stuck_frames = flush_stuck_frames;
+        self
+    }
+
+    /// Write an empty file in place of each discarded frame.
+    pub fn emit_placeholders(mut self, emit_placeholders: bool) -> Self {
+        self.emit_placeholders = emit_placeholders;
+        self
+    }
+
+    /// Skip invalid columns instead of discarding their whole frame,
+    /// leaving them out of the invalid columns and broken packets counted.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Average every `merge_columns` consecutive measure_ids into one column.
+    pub fn merge_columns(mut self, merge_columns: usize) -> Self {
+        self.merge_columns = merge_columns;
+        self
+    }
+
+    /// Keep only every `point_stride`th point of a frame after filtering.
+    pub fn point_stride(mut self, point_stride: usize) -> Self {
+        self.point_stride = point_stride;
+        self
+    }
+
+    /// Subsample frames with more points down to `max_points`, after
+    /// all filters.
+    pub fn max_points(mut self, max_points: Option<usize>, sampling: Sampling) -> Self {
+        self.max_points = max_points;
+        self.sampling = sampling;
+        self
+    }
+
+    /// Split frames into `_part{k}` files of at most this many points.
+    pub fn points_per_file(mut self, points_per_file: Option<usize>) -> Self {
+        self.points_per_file = points_per_file;
+        self
+    }
+
+    /// Write the pixels without a valid return as CSV rows, for debugging.
+    pub fn invalid_points(mut self, invalid_points: Box<dyn Write + Send>) -> Self {
+        self.invalid_points = Some(invalid_points);
+        self
+    }
+
+    /// Largest gap between consecutive frame starts, in nominal frame
+    /// periods, before it is reported as a discontinuity.
+    pub fn max_time_gap(mut self, max_time_gap: f32) -> Self {
+        self.max_time_gap = max_time_gap;
+        self
+    }
+
+    /// Start a new segment subdirectory at every timestamp discontinuity.
+    pub fn split_on_time_gap(mut self, split_on_time_gap: bool) -> Self {
+        self.split_on_time_gap = split_on_time_gap;
+        self
+    }
+
+    /// Start a new segment subdirectory whenever the init_id of the packets
+    /// changes, so each segment holds a single sensor configuration.
+    pub fn split_on_reconfiguration(mut self, split_on_reconfiguration: bool) -> Self {
+        self.split_on_reconfiguration = split_on_reconfiguration;
+        self
+    }
+
+    /// Keep only every `column_stride`th measure_id.
+    pub fn column_stride(mut self, column_stride: usize) -> Self {
+        self.column_stride = column_stride;
+        self
+    }
+
+    pub fn profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Stop parsing once `cancel` is set. Frames already completed are
+    /// still written by `finish`, the one being assembled is dropped.
+    pub fn cancel_on(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
 
-        let filename = format!("{:0width$}.pcd", self.id);
-        let file_path = self.output_path.join(filename);
+    /// Call `callback` with the progress at most once per `interval`
+    /// while putting packets, and once more from `finish`.
+    pub fn on_progress<F>(mut self, interval: Duration, callback: F) -> Self
+    where
+        F: FnMut(ProgressUpdate) + Send + 'static,
+    {
+        self.on_progress = Some((interval, Box::new(callback)));
+        self
+    }
 
-        let file_data = FileData {
-            header: pcd_header,
-            data: buffer.to_vec(),
-            path: file_path,
+    /// Validates the configuration and starts the writer thread.
+    pub fn build(mut self) -> Result<Legacy, OusterError> {
+        let metadata = match self.metadata.take() {
+            Some(metadata) => metadata?,
+            None => return Err(OusterError::Config("no metadata given".to_string())),
         };
 
-        self.sender.send(file_data).unwrap();
+        Ok(self.build_with(metadata)?)
+    }
 
-        self.id += 1;
+    fn build_with(self, mut metadata: MetaData) -> Result<Legacy, String> {
+        let LegacyBuilder {
+            metadata: _,
+            mut sinks,
+            digit,
+            name_template,
+            mut intensity_bands,
+            intensity_field,
+            validity,
+            udp_profile,
+            second_return,
+            range_difference,
+            mut frames,
+            calibration,
+            altitude_correction,
+            veil_angle,
+            colorize,
+            beam_angles,
+            column_timestamps,
+            origin,
+            dir_layout,
+            flush_stuck_frames,
+            emit_placeholders,
+            lenient,
+            column_stride,
+            merge_columns,
+            point_stride,
+            max_points,
+            sampling,
+            points_per_file,
+            mut invalid_points,
+            max_time_gap,
+            split_on_time_gap,
+            split_on_reconfiguration,
+            profiler,
+            cancel,
+            on_progress,
+        } = self;
+
+        let udp_profile = match udp_profile {
+            Some(udp_profile) => udp_profile,
+            None => metadata.udp_profile()?,
+        };
+
+        for calibration in calibration.iter().chain(&altitude_correction) {
+            calibration.apply(
+                &mut metadata.beam_azimuth_angles,
+                &mut metadata.beam_altitude_angles,
+            )?;
+        }
+
+        if frames.is_empty() {
+            return Err("no coordinate frame given".to_string());
+        }
+
+        frames.dedup();
+
+        if frames.contains(&CoordinateFrame::Sensor) && metadata.lidar_to_sensor_transform.is_none()
+        {
+            return Err(
+                "sensor frame requested but metadata has no lidar_to_sensor_transform".to_string(),
+            );
+        }
+
+        if origin.is_some() && frames.contains(&CoordinateFrame::Sensor) {
+            return Err("origin is given in the lidar frame, not the sensor frame".to_string());
+        }
+
+        if origin.is_some_and(|origin| origin.iter().any(|value| !value.is_finite())) {
+            return Err("origin is not a finite point".to_string());
+        }
+
+        if let Some(veil_angle) = veil_angle {
+            if !(veil_angle > 0.0 && veil_angle < 90.0) {
+                return Err(format!(
+                    "veil angle must be between 0 and 90 degrees, got {}",
+                    veil_angle
+                ));
+            }
+        }
+
+        if !(max_time_gap > 1.0) {
+            return Err(format!(
+                "max time gap must be more than 1 frame period, got {}",
+                max_time_gap
+            ));
+        }
+
+        if column_stride == 0 {
+            return Err("column stride must be at least 1".to_string());
+        }
+
+        if max_points == Some(0) {
+            return Err("max points per frame must be at least 1".to_string());
+        }
+
+        if points_per_file == Some(0) {
+            return Err("points per file must be at least 1".to_string());
+        }
+
+        if point_stride == 0 {
+            return Err("point stride must be at least 1".to_string());
+        }
+
+        if merge_columns == 0 {
+            return Err("merged columns must be at least 1".to_string());
+        }
+
+        // both rely on the original spacing of the columns
+        if merge_columns > 1 && (column_stride > 1 || veil_angle.is_some()) {
+            return Err(
+                "merged columns can't be combined with a column stride or veil filter".to_string(),
+            );
+        }
+
+        if udp_profile == UdpProfile::Rng15Rfl8Nir8 && intensity_field == IntensityField::Signal {
+            return Err("the RNG15_RFL8_NIR8 profile has no signal field".to_string());
+        }
+
+        // averaging a beam over its columns mixes up unrelated echoes
+        if merge_columns > 1
+            && second_return == SecondReturn::Merge
+            && udp_profile == UdpProfile::Rng19Rfl8Sig16Nir16Dual
+        {
+            return Err("merged columns can't keep second returns".to_string());
+        }
+
+        if range_difference && udp_profile != UdpProfile::Rng19Rfl8Sig16Nir16Dual {
+            return Err("range difference needs the dual-return udp profile".to_string());
+        }
+
+        if range_difference && merge_columns > 1 {
+            return Err("merged columns can't keep the range difference".to_string());
+        }
+
+        let frame_points = metadata.data_format.active_columns(column_stride)
+            * metadata.data_format.pixels_per_column;
+        let returns_per_pixel = if udp_profile == UdpProfile::Rng19Rfl8Sig16Nir16Dual
+            && second_return == SecondReturn::Merge
+        {
+            2
+        } else {
+            1
+        };
+
+        // names must stay unique, and a typo would otherwise end up verbatim
+        if !name_template.contains("{id}") {
+            return Err(format!("name template {:?} has no {{id}}", name_template));
+        }
+
+        let unknown = name_template
+            .replace("{id}", "")
+            .replace("{completeness}", "");
+        if unknown.contains(['{', '}', '/', '\\']) {
+            return Err(format!(
+                "name template {:?} has an unknown placeholder or a path separator",
+                name_template
+            ));
+        }
+
+        if intensity_bands.iter().any(|threshold| threshold.is_nan()) {
+            return Err("intensity band threshold is not a number".to_string());
+        }
+
+        if let Some(invalid_points) = &mut invalid_points {
+            writeln!(
+                invalid_points,
+                "frame_id,measure_id,channel,range,reflectivity"
+            )
+            .map_err(|err| format!("can't write invalid points: {}", err))?;
+        }
+
+        let geometry = BeamGeometry::new(
+            &metadata.beam_azimuth_angles,
+            &metadata.beam_altitude_angles,
+            &metadata.beam_to_lidar_transform,
+            origin.unwrap_or([0.0; 3]),
+        );
+
+        intensity_bands.sort_by(|a, b| a.total_cmp(b));
+
+        let (sender, receiver) = mpsc::channel::<Frame>();
+
+        let writer_profiler = profiler.clone();
+        let frames_written = Arc::new(AtomicUsize::new(0));
+        let writer_frames_written = frames_written.clone();
+
+        let writer = std::thread::spawn(move || {
+            for frame in receiver {
+                let _span = writer_profiler.span("write", WRITER_THREAD);
+
+                for sink in sinks.iter_mut() {
+                    sink.write_frame(&frame).map_err(|err| {
+                        io::Error::new(err.kind(), format!("{}: {}", frame.name.display(), err))
+                    })?;
+                }
+
+                writer_frames_written.fetch_add(1, Ordering::Relaxed);
+            }
+
+            for sink in sinks.iter_mut() {
+                sink.finish()?;
+            }
+
+            Ok(())
+        });
+
+        let mut legacy = Legacy {
+            metadata,
+            geometry,
+            column_ranges: Default::default(),
+            column_xyz: Default::default(),
+            current: FrameState::default(),
+            id: 0,
+            stats: Stats::default(),
+            digit,
+            name_template,
+            intensity_bands,
+            intensity_field,
+            validity,
+            udp_profile,
+            second_return,
+            range_difference,
+            frames,
+            veil_angle: veil_angle.map(f32::to_radians),
+            colorize,
+            beam_angles,
+            column_timestamps,
+            dir_layout,
+            flush_stuck_frames,
+            emit_placeholders,
+            lenient,
+            column_stride,
+            merge_columns,
+            point_stride,
+            max_points,
+            sampling,
+            points_per_file: points_per_file.unwrap_or(0),
+            invalid_points,
+            max_time_gap,
+            split_on_time_gap,
+            split_on_reconfiguration,
+            init_id: None,
+            last_frame_start: None,
+            written_frame_ids: Vec::new(),
+            segment: 0,
+            frame_points,
+            returns_per_pixel,
+            frame_bytes: 0,
+            profiler,
+            xyz_time: Duration::ZERO,
+            cancel,
+            bytes_processed: 0,
+            frames_written,
+            on_progress,
+            last_progress: Instant::now(),
+            sender,
+            writer,
+        };
+
+        // a frame holds a point per pixel of its active columns for each
+        // return kept
+        let stride = legacy.stride();
+        legacy
+            .current
+            .reserve(legacy.max_frame_points(), stride, veil_angle.is_some());
+        if column_timestamps {
+            let columns_per_frame = legacy.metadata.data_format.columns_per_frame;
+            legacy.current.column_timestamps = vec![0; columns_per_frame];
+        }
+
+        if merge_columns > 1 {
+            let pixels_per_column = legacy.metadata.data_format.pixels_per_column;
+            legacy.current.merged = vec![MergedPixel::default(); pixels_per_column];
+        }
+        legacy.frame_bytes = legacy.current.buffer_bytes();
+        legacy.stats.peak_frame_bytes = legacy.frame_bytes;
+
+        Ok(legacy)
+    }
+}
+
+impl Legacy {
+    pub fn builder() -> LegacyBuilder {
+        LegacyBuilder {
+            metadata: None,
+            sinks: Vec::new(),
+            digit: 4,
+            name_template: "{id}".to_string(),
+            intensity_bands: Vec::new(),
+            intensity_field: IntensityField::Reflectivity,
+            validity: Validity::RangeAndReflectivity,
+            udp_profile: None,
+            second_return: SecondReturn::Drop,
+            range_difference: false,
+            frames: vec![CoordinateFrame::Lidar],
+            calibration: None,
+            altitude_correction: None,
+            veil_angle: None,
+            colorize: None,
+            beam_angles: false,
+            column_timestamps: false,
+            origin: None,
+            dir_layout: DirLayout::Flat,
+            flush_stuck_frames: false,
+            emit_placeholders: false,
+            lenient: false,
+            column_stride: 1,
+            merge_columns: 1,
+            point_stride: 1,
+            max_points: None,
+            sampling: Sampling::Random,
+            points_per_file: None,
+            invalid_points: None,
+            max_time_gap: 3.0,
+            split_on_time_gap: false,
+            split_on_reconfiguration: false,
+            profiler: Profiler::disabled(),
+            cancel: None,
+            on_progress: None,
+        }
+    }
+
+    /// Parser writing into `sinks`, with the options of early versions.
+    #[deprecated(note = "use Legacy::builder")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        meta_file: File,
+        sinks: Vec<Box<dyn FrameSink + Send>>,
+        digit: usize,
+        intensity_bands: Vec<f32>,
+        intensity_field: IntensityField,
+        frames: Vec<CoordinateFrame>,
+        calibration: Option<Calibration>,
+        profiler: Profiler,
+    ) -> Result<Self, OusterError> {
+        let mut builder = Legacy::builder()
+            .metadata(meta_file)
+            .digit(digit)
+            .intensity_bands(intensity_bands)
+            .intensity_field(intensity_field)
+            .frames(frames)
+            .calibration(calibration)
+            .profiler(profiler);
+
+        for sink in sinks {
+            builder = builder.sink(sink);
+        }
+
+        builder.build()
+    }
+
+    /// Length of a complete lidar packet payload in bytes.
+    pub fn expected_packet_len(&self) -> usize {
+        self.udp_profile.packet_len(&self.metadata.data_format)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Ranges of frame_ids missing between the first and last frame
+    /// written, whether lost or discarded.
+    pub fn missing_frames(&self) -> Vec<(u16, u16)> {
+        self.written_frame_ids
+            .windows(2)
+            .filter(|pair| pair[1].wrapping_sub(pair[0]) > 1)
+            .map(|pair| (pair[0].wrapping_add(1), pair[1].wrapping_sub(1)))
+            .collect()
+    }
+
+    /// Lidar udp port configured in the metadata, if any.
+    pub fn udp_port_lidar(&self) -> Option<u16> {
+        self.metadata.udp_port_lidar()
+    }
+
+    /// Packet layout parsed, from the metadata unless overridden.
+    pub fn udp_profile(&self) -> UdpProfile {
+        self.udp_profile
+    }
+
+    /// Flush pending frames and wait for the writer thread to exit,
+    /// returning the error that stopped it, if any.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+
+        let written = match self.writer.join() {
+            Ok(written) => written,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+
+        if let Some((_, mut callback)) = self.on_progress {
+            callback(ProgressUpdate {
+                bytes_processed: self.bytes_processed,
+                frames_written: self.frames_written.load(Ordering::Relaxed),
+            });
+        }
+
+        if let Some(mut invalid_points) = self.invalid_points {
+            invalid_points.flush()?;
+        }
+
+        written
+    }
+
+    /// Parse the complete columns at the start of a datagram that lost
+    /// its tail, the missing columns counting as invalid ones.
+    pub fn put_partial(&mut self, data: &[u8]) {
+        let pixels_per_column = self.metadata.data_format.pixels_per_column;
+        let columns_per_packet = self.metadata.data_format.columns_per_packet;
+
+        let len_column = self.udp_profile.column_len(pixels_per_column);
+        let (packet_header, columns) =
+            data.split_at(self.udp_profile.packet_header_len().min(data.len()));
+        let num_columns = if packet_header.len() == self.udp_profile.packet_header_len() {
+            (columns.len() / len_column).min(columns_per_packet)
+        } else {
+            0
+        };
+
+        if self.is_cancelled() {
+            return;
+        }
+
+        self.stats.packets += 1;
+        self.stats.broken_packets += 1;
+        self.bytes_processed += data.len();
+        self.report_progress();
+
+        if num_columns > 0 {
+            if let Some(init_id) = self.udp_profile.init_id(packet_header) {
+                let first = &columns[..len_column];
+                let header = self.udp_profile.column(packet_header, first).header;
+                self.check_init_id(init_id, &header);
+            }
+        }
+
+        for column in columns.chunks_exact(len_column).take(num_columns) {
+            let column = self.udp_profile.column(packet_header, column);
+            self.stats.recovered_points += self.parse_measure_block(column);
+        }
+
+        let num_missing = columns_per_packet - num_columns;
+        if self.lenient {
+            self.current.skipped_points += num_missing * pixels_per_column;
+        } else if num_missing > 0 {
+            self.current.broken = true;
+            self.stats.invalid_columns += num_missing;
+        }
+    }
+
+    /// Parse one lidar udp payload, completing the current frame and
+    /// sending it to the sinks when the payload starts a new one.
+    pub fn put(&mut self, data: &[u8]) {
+        let pixels_per_column = self.metadata.data_format.pixels_per_column;
+        let columns_per_packet = self.metadata.data_format.columns_per_packet;
+
+        let len_column = self.udp_profile.column_len(pixels_per_column);
+        let len_expected = self.udp_profile.packet_len(&self.metadata.data_format);
+
+        if self.is_cancelled() {
+            return;
+        }
+
+        self.stats.packets += 1;
+        self.bytes_processed += data.len();
+        self.report_progress();
+
+        if data.len() < len_expected {
+            self.current.broken = true;
+            self.stats.broken_packets += 1;
+            return;
+        }
+
+        let start = Instant::now();
+        let invalid_columns = self.stats.invalid_columns;
+
+        let (packet_header, columns) = data.split_at(self.udp_profile.packet_header_len());
+
+        if let Some(init_id) = self.udp_profile.init_id(packet_header) {
+            let first = &columns[..len_column];
+            let header = self.udp_profile.column(packet_header, first).header;
+            self.check_init_id(init_id, &header);
+        }
+
+        for column in columns.chunks_exact(len_column).take(columns_per_packet) {
+            let column = self.udp_profile.column(packet_header, column);
+            self.parse_measure_block(column);
+        }
+
+        if self.stats.invalid_columns > invalid_columns {
+            self.stats.broken_packets += 1;
+        }
+
+        // xyz time is summed over the packet's columns to keep the trace small
+        if self.profiler.is_enabled() {
+            let xyz_time = std::mem::take(&mut self.xyz_time);
+            self.profiler.record("xyz", MAIN_THREAD, start, xyz_time);
+        }
+    }
+
+    /// Whether the flag given to `LegacyBuilder::cancel_on` is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    fn report_progress(&mut self) {
+        if let Some((interval, callback)) = &mut self.on_progress {
+            if self.last_progress.elapsed() >= *interval {
+                self.last_progress = Instant::now();
+                callback(ProgressUpdate {
+                    bytes_processed: self.bytes_processed,
+                    frames_written: self.frames_written.load(Ordering::Relaxed),
+                });
+            }
+        }
+    }
+
+    /// Decode the points of a single lidar packet.
+    ///
+    /// Unlike `put`, this leaves the frame assembly state untouched and
+    /// writes nothing, which makes it usable on any standalone payload.
+    pub fn parse_packet_to_points(&self, data: &[u8]) -> Vec<PointXYZ> {
+        let pixels_per_column = self.metadata.data_format.pixels_per_column;
+        let columns_per_packet = self.metadata.data_format.columns_per_packet;
+
+        let len_column = self.udp_profile.column_len(pixels_per_column);
+        let len_expected = self.udp_profile.packet_len(&self.metadata.data_format);
+
+        let mut points = Vec::new();
+
+        if data.len() < len_expected {
+            return points;
+        }
+
+        let (packet_header, columns) = data.split_at(self.udp_profile.packet_header_len());
+
+        for column in columns.chunks_exact(len_column).take(columns_per_packet) {
+            let column = self.udp_profile.column(packet_header, column);
+            let measure_id = column.header.measure_id;
+
+            if !self.metadata.data_format.in_window(measure_id as usize) || !column.valid {
+                continue;
+            }
+
+            let channel_len = self.udp_profile.channel_len();
+            for (channel, block) in column.channels.chunks_exact(channel_len).enumerate() {
+                let (first, second) = self.udp_profile.returns(block);
+                points.extend(self.decode_point(&first, measure_id, channel));
+
+                if let Some(second) = self.kept_second_return(second) {
+                    points.extend(self.decode_point(&second, measure_id, channel));
+                }
+            }
+        }
+
+        points
+    }
+
+    /// Parse one column into the current frame, returning the number of
+    /// points it added.
+    fn parse_measure_block(&mut self, column: Column) -> usize {
+        let header = column.header;
+
+        // columns outside the window are sent with an invalid status
+        if !self
+            .metadata
+            .data_format
+            .in_window(header.measure_id as usize)
+        {
+            return 0;
+        }
+
+        if header.measure_id as usize % self.column_stride != 0 {
+            return 0;
+        }
+
+        if !column.valid {
+            // the header of an invalid column can't be trusted, so a
+            // skipped column counts towards the frame being received,
+            // though not towards the invalid columns reported
+            if self.lenient {
+                self.current.skipped_points += self.metadata.data_format.pixels_per_column;
+            } else {
+                self.current.broken = true;
+                self.stats.invalid_columns += 1;
+            }
+            return 0;
+        }
+
+        if !self.set_current_state(&header) {
+            return 0;
+        }
+
+        if self.column_timestamps {
+            let columns_per_frame = self.metadata.data_format.columns_per_frame;
+            self.current.column_timestamps[header.measure_id as usize % columns_per_frame] =
+                header.timestamp;
+        }
+
+        if self.merge_columns > 1 {
+            let group = header.measure_id as usize / self.merge_columns;
+            if self.current.merge_group != Some(group) {
+                self.flush_merged();
+                self.current.merge_group = Some(group);
+            }
+        }
+
+        let xyz_start = self.profiler.is_enabled().then(Instant::now);
+
+        // merged columns only get their points once averaged
+        if self.merge_columns == 1 {
+            self.calculate_column_xyz(column.channels, header.measure_id);
+        }
+
+        let mut channel = 0;
+
+        for block in column.channels.chunks_exact(self.udp_profile.channel_len()) {
+            self.parse_data_block(block, header.measure_id, channel);
+            channel += 1;
+            self.current.num_points += 1;
+        }
+
+        if let Some(xyz_start) = xyz_start {
+            self.xyz_time += xyz_start.elapsed();
+        }
+
+        self.check_stuck_frame(&header);
+
+        channel
+    }
+
+    /// Catch a frame_id that never advances, which would otherwise grow
+    /// the frame without bound and never write anything.
+    fn check_stuck_frame(&mut self, header: &HeaderBlock) {
+        let pixels_per_column = self.metadata.data_format.pixels_per_column;
+
+        if self.current.num_points < 2 * self.frame_points {
+            return;
+        }
+
+        if !self.current.stuck {
+            eprintln!(
+                "Frame boundaries are not advancing. (frame_id {} over {} columns, wrong metadata or stuck sensor)",
+                self.current.frame_id,
+                self.current.num_points / pixels_per_column
+            );
+        }
+
+        if self.flush_stuck_frames {
+            self.save_frame();
+            self.current.reset(header);
+        }
+
+        self.current.stuck = true;
+    }
+
+    fn parse_data_block(&mut self, data: &[u8], measure_id: u16, channel: usize) {
+        let (first, second) = self.udp_profile.returns(data);
+
+        if self.invalid_points.is_some() {
+            self.record_invalid_point(&first, measure_id, channel);
+        }
+
+        // only a pixel with both echoes has a distance between them
+        let range_difference = match &second {
+            Some(second) if first.range != 0 && second.range != 0 => {
+                (second.range as f32 - first.range as f32) / 1000.0
+            }
+            _ => f32::NAN,
+        };
+
+        self.parse_return(&first, 0, measure_id, channel, range_difference);
+
+        if let Some(second) = self.kept_second_return(second) {
+            self.parse_return(&second, 1, measure_id, channel, range_difference);
+        }
+    }
+
+    /// Compute the points of every return kept of a column at once, as
+    /// its beams share the encoder angle.
+    fn calculate_column_xyz(&mut self, channels: &[u8], measure_id: u16) {
+        let [first_ranges, second_ranges] = &mut self.column_ranges;
+        first_ranges.clear();
+        second_ranges.clear();
+
+        for block in channels.chunks_exact(self.udp_profile.channel_len()) {
+            let (first, second) = self.udp_profile.returns(block);
+            first_ranges.push(first.range as f32);
+
+            if self.returns_per_pixel > 1 {
+                second_ranges.push(second.map_or(0.0, |second| second.range as f32));
+            }
+        }
+
+        let encoder = self.encoder(measure_id as f32);
+        for (ranges, xyz) in self.column_ranges.iter().zip(&mut self.column_xyz) {
+            self.geometry.column(encoder, ranges, xyz);
+        }
+    }
+
+    /// The second return of a beam to keep as a point, if any.
+    fn kept_second_return(&self, second: Option<Return>) -> Option<Return> {
+        // a zero range means there was a single echo, not a dropped return
+        second.filter(|second| self.second_return == SecondReturn::Merge && second.range != 0)
+    }
+
+    /// Add the `nth` return of a pixel, 0 for the first one.
+    fn parse_return(
+        &mut self,
+        ret: &Return,
+        nth: usize,
+        measure_id: u16,
+        channel: usize,
+        range_difference: f32,
+    ) {
+        match self.validity.exclusion(ret.range, ret.reflectivity) {
+            Some(Exclusion::Range) => {
+                self.current.excluded_range += 1;
+                self.stats.excluded_range += 1;
+                return;
+            }
+            Some(Exclusion::Reflectivity) => {
+                self.current.excluded_reflectivity += 1;
+                self.stats.excluded_reflectivity += 1;
+                return;
+            }
+            None => (),
+        }
+
+        if self.merge_columns > 1 {
+            self.merge_data_block(ret, channel);
+            return;
+        }
+
+        let xyz = &self.column_xyz[nth];
+        let point = PointXYZ {
+            x: xyz.x[channel],
+            y: xyz.y[channel],
+            z: xyz.z[channel],
+            reflect: self.scale_intensity(self.intensity_field.read(ret) as f32),
+        };
+
+        self.push_point(
+            &point,
+            measure_id as f32,
+            channel,
+            ret.range,
+            range_difference,
+        );
+    }
+
+    /// Add a row for a pixel skipped for its zero range or reflectivity.
+    fn record_invalid_point(&mut self, ret: &Return, measure_id: u16, channel: usize) {
+        let (range, reflect) = (ret.range, ret.reflectivity);
+
+        if self.validity.exclusion(range, reflect).is_none() {
+            return;
+        }
+
+        let frame_id = self.current.frame_id;
+
+        if let Some(invalid_points) = &mut self.invalid_points {
+            writeln!(
+                invalid_points,
+                "{},{},{},{},{}",
+                frame_id, measure_id, channel, range, reflect
+            )
+            .unwrap_or_else(|err| panic!("Failed to write invalid points. ({})", err));
+        }
+    }
+
+    fn push_point(
+        &mut self,
+        point: &PointXYZ,
+        measure_id: f32,
+        channel: usize,
+        range: u32,
+        range_difference: f32,
+    ) {
+        self.current.points.push(point.x);
+        self.current.points.push(point.y);
+        self.current.points.push(point.z);
+        self.current.points.push(point.reflect);
+
+        // the color source value is mapped to rgb once the frame is complete
+        if let Some(colorize) = self.colorize {
+            self.current.points.push(match colorize.source {
+                ColorSource::Height => point.z,
+                ColorSource::Ring => channel as f32,
+                ColorSource::Intensity => point.reflect,
+            });
+        }
+
+        if self.beam_angles {
+            let columns_per_frame = self.metadata.data_format.columns_per_frame as f32;
+            self.current
+                .points
+                .push(self.metadata.beam_altitude_angles[channel]);
+            self.current
+                .points
+                .push(360.0 * (1.0 - measure_id / columns_per_frame));
+        }
+
+        if self.range_difference {
+            self.current.points.push(range_difference);
+        }
+
+        if self.veil_angle.is_some() {
+            self.current.pixels.push(Pixel {
+                measure_id: measure_id as u16,
+                channel: channel as u16,
+                range,
+            });
+        }
+    }
+
+    /// Add a valid return to the sums of its beam in the current column group.
+    fn merge_data_block(&mut self, ret: &Return, channel: usize) {
+        let merged = &mut self.current.merged[channel];
+        merged.range += ret.range as u64;
+        merged.intensity += self.intensity_field.read(ret) as u64;
+        merged.count += 1;
+    }
+
+    /// Emit the averaged returns of the current column group, placed at
+    /// the middle of its measure_ids.
+    fn flush_merged(&mut self) {
+        let group = match self.current.merge_group.take() {
+            Some(group) => group,
+            None => return,
+        };
+
+        let measure_id =
+            (group * self.merge_columns) as f32 + (self.merge_columns - 1) as f32 / 2.0;
+
+        for channel in 0..self.current.merged.len() {
+            let merged = std::mem::take(&mut self.current.merged[channel]);
+
+            if merged.count == 0 {
+                continue;
+            }
+
+            let range = (merged.range / merged.count as u64) as u32;
+            let intensity = merged.intensity as f32 / merged.count as f32;
+
+            let point = self.calculate_xyz(range as f32, intensity, measure_id, channel);
+            // merged columns never keep the range difference
+            self.push_point(&point, measure_id, channel, range, f32::NAN);
+        }
+    }
+
+    fn decode_point(&self, ret: &Return, measure_id: u16, channel: usize) -> Option<PointXYZ> {
+        if self
+            .validity
+            .exclusion(ret.range, ret.reflectivity)
+            .is_some()
+        {
+            return None;
+        }
+
+        let intensity = self.intensity_field.read(ret);
+
+        Some(self.calculate_xyz(
+            ret.range as f32,
+            intensity as f32,
+            measure_id as f32,
+            channel,
+        ))
+    }
+
+    fn set_current_state(&mut self, header: &HeaderBlock) -> bool {
+        if header.frame_id != self.current.frame_id {
+            self.end_frame();
+            self.check_time_gap(header);
+            self.current.reset(header);
+        } else if self.current.broken {
+            return false;
+        } else {
+            if header.timestamp < self.current.timestamp {
+                self.current.timestamp = header.timestamp;
+            }
+            if header.timestamp > self.current.end_timestamp {
+                self.current.end_timestamp = header.timestamp;
+            }
+        }
+
+        true
+    }
+
+    /// Write out the frame being received if complete, or drop it.
+    fn end_frame(&mut self) {
+        let received = self.current.num_points + self.current.skipped_points;
+
+        if !self.current.broken && received >= self.frame_points {
+            self.save_frame();
+        } else if self.current.broken {
+            self.discard_frame("broken packet or column");
+        } else if self.current.num_points > 0 {
+            let reason = format!(
+                "incomplete, {} of {} points",
+                self.current.num_points, self.frame_points
+            );
+            self.discard_frame(&reason);
+        }
+
+        self.check_capacity();
+    }
+
+    /// Report a sensor reinitialization, and end the frame and segment
+    /// before the packet when splitting on them.
+    fn check_init_id(&mut self, init_id: u32, header: &HeaderBlock) {
+        let last_init_id = match self.init_id.replace(init_id) {
+            Some(last_init_id) if last_init_id != init_id => last_init_id,
+            _ => return,
+        };
+
+        self.stats.reconfigurations += 1;
+
+        eprintln!(
+            "Sensor was reinitialized. (init_id {} to {}, frame_id {})",
+            last_init_id, init_id, header.frame_id
+        );
+
+        if !self.split_on_reconfiguration {
+            return;
+        }
+
+        self.end_frame();
+        self.segment += 1;
+
+        // the new configuration starts its own timeline
+        self.last_frame_start = Some(header.timestamp);
+        self.current.reset(header);
+    }
+
+    /// Report a new frame starting before the previous one or too long
+    /// after it, e.g. on a PTP resync, sensor reboot or recorder gap.
+    fn check_time_gap(&mut self, header: &HeaderBlock) {
+        let last_frame_start = self.last_frame_start.replace(header.timestamp);
+
+        let last_frame_start = match last_frame_start {
+            Some(last_frame_start) => last_frame_start,
+            None => return,
+        };
+
+        let jump = header.timestamp as i64 - last_frame_start as i64;
+
+        // without a lidar_mode only backward jumps can be told apart
+        let leap = self
+            .metadata
+            .frame_period()
+            .is_some_and(|period| jump as f64 > period as f64 * self.max_time_gap as f64);
+
+        if jump >= 0 && !leap {
+            return;
+        }
+
+        self.stats.time_discontinuities += 1;
+
+        eprintln!(
+            "Frame timestamps are discontinuous. (frame_id {}, jump of {:+.3} s)",
+            header.frame_id,
+            jump as f64 * 1e-9
+        );
+
+        if self.split_on_time_gap {
+            self.segment += 1;
+        }
+    }
+
+    /// Most points a complete frame holds, with every return kept.
+    fn max_frame_points(&self) -> usize {
+        self.frame_points * self.returns_per_pixel
+    }
+
+    /// Track the peak buffer size of the frame just finished.
+    fn check_capacity(&mut self) {
+        let bytes = self.current.buffer_bytes();
+
+        // only oversized frames, e.g. of a stuck frame_id, may grow them;
+        // num_points counts pixels, which the buffers hold every return of
+        debug_assert!(
+            bytes == self.frame_bytes || self.current.num_points > self.frame_points,
+            "frame buffers of {} points reallocated at {} of {} pixels",
+            self.max_frame_points(),
+            self.current.num_points,
+            self.frame_points
+        );
+
+        self.stats.peak_frame_bytes = self.stats.peak_frame_bytes.max(bytes);
+    }
+
+    fn calculate_xyz(&self, range: f32, reflect: f32, measure_id: f32, channel: usize) -> PointXYZ {
+        let [x, y, z] = self
+            .geometry
+            .point(self.encoder(measure_id), channel, range);
+
+        PointXYZ {
+            x,
+            y,
+            z,
+            reflect: self.scale_intensity(reflect),
+        }
+    }
+
+    /// Encoder angle in radians of a measure_id, which may fall between
+    /// columns when they are merged.
+    fn encoder(&self, measure_id: f32) -> f32 {
+        let columns_per_frame = self.metadata.data_format.columns_per_frame as f32;
+        2.0 * PI * (1.0 - measure_id / columns_per_frame)
+    }
+
+    fn scale_intensity(&self, reflect: f32) -> f32 {
+        let reflect = reflect / self.intensity_field.max_value();
+
+        // signal is amplified by the sensor's signal_multiplier
+        if self.intensity_field == IntensityField::Signal {
+            reflect / self.metadata.signal_multiplier()
+        } else {
+            reflect
+        }
+    }
+
+    /// Number of values per point: `x y z intensity`, plus `rgb` if
+    /// colorized, `elevation azimuth` with beam angles and
+    /// `range_difference` if kept.
+    fn stride(&self) -> usize {
+        4 + self.colorize.is_some() as usize
+            + 2 * self.beam_angles as usize
+            + self.range_difference as usize
+    }
+
+    fn save_frame(&mut self) {
+        self.flush_merged();
+
+        let mut points = match self.veil_angle {
+            Some(veil_angle) => Cow::Owned(self.remove_veil(veil_angle)),
+            None => Cow::Borrowed(&self.current.points),
+        };
+
+        if let Some(colorize) = self.colorize {
+            self.apply_colors(points.to_mut(), colorize);
+        }
+
+        // restarted on every frame, so the kept points don't drift
+        if self.point_stride > 1 {
+            let stride = self.stride();
+            points = Cow::Owned(
+                points
+                    .chunks_exact(stride)
+                    .step_by(self.point_stride)
+                    .flatten()
+                    .copied()
+                    .collect(),
+            );
+        }
+
+        // seeded by the frame timestamp, so reruns keep the same points
+        if let Some(max_points) = self.max_points {
+            let stride = self.stride();
+            if points.len() > max_points * stride {
+                let seed = self.current.timestamp;
+                points = Cow::Owned(sampling::sample(
+                    &points,
+                    stride,
+                    max_points,
+                    self.sampling,
+                    seed,
+                ));
+                self.current.sampling_seed = Some(seed);
+            }
+        }
+
+        for frame in &self.frames {
+            let points = match frame {
+                CoordinateFrame::Lidar => Cow::Borrowed(&points[..]),
+                CoordinateFrame::Sensor => Cow::Owned(self.lidar_to_sensor(&points)),
+            };
+
+            // several frames are written into one subdirectory each
+            let dirname = if self.frames.len() > 1 {
+                Path::new(frame.dirname())
+            } else {
+                Path::new("")
+            };
+
+            self.save_bands(&points, *frame, dirname, None);
+        }
+
+        self.id += 1;
+        self.stats.frames += 1;
+        self.written_frame_ids.push(self.current.frame_id);
+    }
+
+    /// Count a dropped frame, and keep the numbering dense with empty
+    /// placeholder files carrying the reason if asked to.
+    fn discard_frame(&mut self, reason: &str) {
+        self.stats.discarded_frames += 1;
+
+        if !self.emit_placeholders {
+            return;
+        }
+
+        for frame in &self.frames {
+            let dirname = if self.frames.len() > 1 {
+                Path::new(frame.dirname())
+            } else {
+                Path::new("")
+            };
+
+            self.save_bands(&[], *frame, dirname, Some(reason));
+        }
+
+        self.id += 1;
+    }
+
+    fn save_bands(
+        &self,
+        points: &[f32],
+        coordinate_frame: CoordinateFrame,
+        dirname: &Path,
+        discarded: Option<&str>,
+    ) {
+        let name = self.frame_name();
+
+        let mut prefix = PathBuf::new();
+
+        if self.dir_layout == DirLayout::Time {
+            prefix.push(time_dirname(self.current.timestamp));
+        }
+
+        if self.split_on_time_gap || self.split_on_reconfiguration {
+            prefix.push(format!("segment{}", self.segment));
+        }
+
+        let dirname = prefix.join(dirname);
+
+        if self.intensity_bands.is_empty() {
+            self.send_frame(points, coordinate_frame, dirname.join(name), discarded);
+        } else {
+            let mut bands = vec![Vec::new(); self.intensity_bands.len() + 1];
+
+            for point in points.chunks_exact(self.stride()) {
+                let reflect = (point[3] * self.intensity_field.max_value()).round();
+                let band = self
+                    .intensity_bands
+                    .iter()
+                    .filter(|&&threshold| reflect >= threshold)
+                    .count();
+                bands[band].extend_from_slice(point);
+            }
+
+            for (band, points) in bands.iter().enumerate() {
+                let filename = format!("{}_band{}", name, band);
+                self.send_frame(points, coordinate_frame, dirname.join(filename), discarded);
+            }
+        }
+    }
+
+    /// File name of the current frame from the name template.
+    fn frame_name(&self) -> String {
+        let width = self.digit;
+
+        self.name_template
+            .replace("{id}", &format!("{:0width$}", self.id))
+            .replace("{completeness}", &format!("{:.3}", self.completeness()))
+    }
+
+    /// Share of the expected points received in valid columns.
+    fn completeness(&self) -> f32 {
+        self.current.num_points as f32 / self.frame_points as f32
+    }
+
+    /// Drop veil points hanging between foreground and background.
+    ///
+    /// For each neighbor in the same ring, the angle between the beam and
+    /// the segment to that neighbor is computed from the two ranges. A
+    /// point is dropped when this angle is below the threshold on every
+    /// side it has a neighbor, i.e. it lies on no surface facing the beam.
+    fn remove_veil(&self, veil_angle: f32) -> Vec<f32> {
+        let columns = self.metadata.data_format.columns_per_frame;
+        let rows = self.metadata.data_format.pixels_per_column;
+
+        let mut grid = vec![0u32; columns * rows];
+        for pixel in &self.current.pixels {
+            grid[pixel.channel as usize * columns + pixel.measure_id as usize % columns] =
+                pixel.range;
+        }
+
+        // neighbors are one kept column apart
+        let step = self.column_stride;
+        let (sin, cos) = (2.0 * PI * step as f32 / columns as f32).sin_cos();
+
+        let mut points = Vec::with_capacity(self.current.points.len());
+        let mut num_removed = 0;
+
+        for (pixel, point) in self
+            .current
+            .pixels
+            .iter()
+            .zip(self.current.points.chunks_exact(self.stride()))
+        {
+            let ring = &grid[pixel.channel as usize * columns..][..columns];
+            let column = pixel.measure_id as usize % columns;
+            let range = pixel.range as f32;
+
+            let neighbors = [
+                ring[(column + columns - step) % columns],
+                ring[(column + step) % columns],
+            ];
+
+            let mut angles = neighbors
+                .iter()
+                .filter(|&&neighbor| neighbor != 0)
+                .map(|&neighbor| {
+                    let neighbor = neighbor as f32;
+                    (neighbor * sin).atan2((range - neighbor * cos).abs())
+                })
+                .peekable();
+
+            let is_veil = angles.peek().is_some() && angles.all(|angle| angle < veil_angle);
+
+            if is_veil {
+                num_removed += 1;
+            } else {
+                points.extend_from_slice(point);
+            }
+        }
+
+        eprintln!(
+            "Remove veil points. (frame {}, {} of {} points)",
+            self.id,
+            num_removed,
+            self.current.pixels.len()
+        );
+
+        points
+    }
+
+    /// Replace the color source values by packed rgb colors.
+    ///
+    /// Heights are stretched over the range of the frame, rings over the
+    /// beams and intensities are already normalized.
+    fn apply_colors(&self, points: &mut [f32], colorize: Colorize) {
+        let stride = self.stride();
+
+        let (min, max) = match colorize.source {
+            ColorSource::Height => points
+                .chunks_exact(stride)
+                .fold((f32::MAX, f32::MIN), |(min, max), point| {
+                    (min.min(point[4]), max.max(point[4]))
+                }),
+            ColorSource::Ring => (
+                0.0,
+                (self.metadata.data_format.pixels_per_column - 1) as f32,
+            ),
+            ColorSource::Intensity => (0.0, 1.0),
+        };
+
+        let scale = if max > min { 1.0 / (max - min) } else { 0.0 };
+
+        for point in points.chunks_exact_mut(stride) {
+            // PCD stores packed rgb as the bits of a float
+            point[4] = f32::from_bits(colorize.colormap.rgb((point[4] - min) * scale));
+        }
+    }
+
+    fn lidar_to_sensor(&self, points: &[f32]) -> Vec<f32> {
+        let m = self.metadata.lidar_to_sensor_transform.as_ref().unwrap();
+
+        let mut sensor_points = Vec::with_capacity(points.len());
+
+        for point in points.chunks_exact(self.stride()) {
+            let (x, y, z) = (point[0], point[1], point[2]);
+
+            // translation is in millimeters
+            sensor_points.push(m[0] * x + m[1] * y + m[2] * z + m[3] / 1000.0);
+            sensor_points.push(m[4] * x + m[5] * y + m[6] * z + m[7] / 1000.0);
+            sensor_points.push(m[8] * x + m[9] * y + m[10] * z + m[11] / 1000.0);
+            sensor_points.extend_from_slice(&point[3..]);
+        }
+
+        sensor_points
+    }
+
+    /// Send the points as one file, or as numbered parts of at most
+    /// `points_per_file` points each.
+    fn send_frame(
+        &self,
+        points: &[f32],
+        coordinate_frame: CoordinateFrame,
+        name: PathBuf,
+        discarded: Option<&str>,
+    ) {
+        let part_len = self.points_per_file * self.stride();
+
+        if part_len == 0 || points.len() <= part_len {
+            self.send_file(points, coordinate_frame, name, discarded);
+            return;
+        }
+
+        for (part, points) in points.chunks(part_len).enumerate() {
+            let mut part_name = name.clone().into_os_string();
+            part_name.push(format!("_part{}", part));
+            self.send_file(points, coordinate_frame, part_name.into(), discarded);
+        }
+    }
+
+    fn send_file(
+        &self,
+        points: &[f32],
+        coordinate_frame: CoordinateFrame,
+        name: PathBuf,
+        discarded: Option<&str>,
+    ) {
+        let _span = self.profiler.span("serialize", MAIN_THREAD);
+
+        let frame = Frame {
+            id: self.id,
+            frame_id: self.current.frame_id,
+            timestamp: self.current.timestamp,
+            end_timestamp: self.current.end_timestamp,
+            coordinate_frame,
+            name,
+            rgb: self.colorize.is_some(),
+            beam_angles: self.beam_angles,
+            range_difference: self.range_difference,
+            column_timestamps: self.current.column_timestamps.clone(),
+            excluded_range: self.current.excluded_range,
+            excluded_reflectivity: self.current.excluded_reflectivity,
+            completeness: self.completeness(),
+            segment: self.segment,
+            point_stride: self.point_stride,
+            sampling: self.current.sampling_seed.map(|seed| (self.sampling, seed)),
+            discarded: discarded.map(str::to_string),
+            points: points.to_vec(),
+        };
+
+        // a closed channel means the writer stopped at an error, which
+        // `finish` returns; the frames after it are dropped
+        let _ = self.sender.send(frame);
+    }
+}
+
+/// Describe the sensor of a metadata json as `key: value` lines.
+///
+/// Keys are looked up at the top level as in older firmware, then in the
+/// `sensor_info` and `config_params` sections of newer metadata.
+pub fn describe_sensor(meta: &[u8]) -> Vec<String> {
+    let value: serde_json::Value = match serde_json::from_slice(meta) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+
+    let find = |key: &str| {
+        [&value, &value["sensor_info"], &value["config_params"]]
+            .iter()
+            .find_map(|section| match &section[key] {
+                serde_json::Value::String(text) => Some(text.clone()),
+                serde_json::Value::Number(number) => Some(number.to_string()),
+                _ => None,
+            })
+    };
+
+    [
+        ("sensor_serial", "prod_sn"),
+        ("prod_line", "prod_line"),
+        ("firmware", "build_rev"),
+        ("lidar_mode", "lidar_mode"),
+    ]
+    .iter()
+    .filter_map(|(name, key)| find(key).map(|value| format!("{}: {}", name, value)))
+    .collect()
+}
+
+/// Differences below this are json round-off, not a change.
+const DIFF_TOLERANCE: f32 = 1e-6;
+
+/// Print the differences between two metadata jsons field by field,
+/// returning whether any field the point geometry depends on differs.
+pub fn diff_metadata(a: &[u8], b: &[u8]) -> Result<bool, OusterError> {
+    let (meta_a, meta_b) = (MetaData::from_reader(a)?, MetaData::from_reader(b)?);
+
+    let mut geometry_differs = false;
+
+    let mut diff_values = |name: &str, a: &[f32], b: &[f32], unit: &str| {
+        if a.len() != b.len() {
+            println!("{}: {} values -> {} values", name, a.len(), b.len());
+            geometry_differs = true;
+            return;
+        }
+
+        let deltas: Vec<f32> = a.iter().zip(b).map(|(a, b)| (b - a).abs()).collect();
+        let max = deltas.iter().copied().fold(0.0, f32::max);
+
+        if max > DIFF_TOLERANCE {
+            let mean = deltas.iter().sum::<f32>() / deltas.len() as f32;
+            let num_changed = deltas
+                .iter()
+                .filter(|&&delta| delta > DIFF_TOLERANCE)
+                .count();
+            println!(
+                "{}: {} of {} values differ, max {:.6}{}, mean {:.6}{}",
+                name,
+                num_changed,
+                deltas.len(),
+                max,
+                unit,
+                mean,
+                unit
+            );
+            geometry_differs = true;
+        }
+    };
+
+    diff_values(
+        "beam_altitude_angles",
+        &meta_a.beam_altitude_angles,
+        &meta_b.beam_altitude_angles,
+        " deg",
+    );
+    diff_values(
+        "beam_azimuth_angles",
+        &meta_a.beam_azimuth_angles,
+        &meta_b.beam_azimuth_angles,
+        " deg",
+    );
+    diff_values(
+        "beam_to_lidar_transform",
+        &meta_a.beam_to_lidar_transform,
+        &meta_b.beam_to_lidar_transform,
+        "",
+    );
+
+    match (
+        &meta_a.lidar_to_sensor_transform,
+        &meta_b.lidar_to_sensor_transform,
+    ) {
+        (Some(a), Some(b)) => diff_values("lidar_to_sensor_transform", a, b, ""),
+        (None, None) => (),
+        (a, b) => {
+            println!(
+                "lidar_to_sensor_transform: {} -> {}",
+                if a.is_some() { "present" } else { "missing" },
+                if b.is_some() { "present" } else { "missing" }
+            );
+            geometry_differs = true;
+        }
+    }
+
+    let format_a = &meta_a.data_format;
+    let format_b = &meta_b.data_format;
+
+    for (name, a, b) in [
+        (
+            "columns_per_frame",
+            format_a.columns_per_frame,
+            format_b.columns_per_frame,
+        ),
+        (
+            "columns_per_packet",
+            format_a.columns_per_packet,
+            format_b.columns_per_packet,
+        ),
+        (
+            "pixels_per_column",
+            format_a.pixels_per_column,
+            format_b.pixels_per_column,
+        ),
+    ] {
+        if a != b {
+            println!("data_format.{}: {} -> {}", name, a, b);
+            geometry_differs = true;
+        }
+    }
+
+    if format_a.column_window != format_b.column_window {
+        println!(
+            "data_format.column_window: {:?} -> {:?}",
+            format_a.column_window, format_b.column_window
+        );
+        geometry_differs = true;
+    }
+
+    // reported, but the points don't depend on them
+    if meta_a.signal_multiplier() != meta_b.signal_multiplier() {
+        println!(
+            "signal_multiplier: {} -> {}",
+            meta_a.signal_multiplier(),
+            meta_b.signal_multiplier()
+        );
+    }
+
+    if meta_a.udp_port_lidar() != meta_b.udp_port_lidar() {
+        println!(
+            "udp_port_lidar: {:?} -> {:?}",
+            meta_a.udp_port_lidar(),
+            meta_b.udp_port_lidar()
+        );
+    }
+
+    if meta_a.udp_profile_name() != meta_b.udp_profile_name() {
+        println!(
+            "udp_profile_lidar: {} -> {}",
+            meta_a.udp_profile_name(),
+            meta_b.udp_profile_name()
+        );
+    }
+
+    let (sensor_a, sensor_b) = (describe_sensor(a), describe_sensor(b));
+    for line in &sensor_a {
+        let key = line.split(':').next().unwrap();
+        match sensor_b
+            .iter()
+            .find(|other| other.split(':').next() == Some(key))
+        {
+            Some(other) if other != line => println!("{} ->{}", line, &other[key.len() + 1..]),
+            Some(_) => (),
+            None => println!("{} -> missing", line),
+        }
+    }
+    for line in &sensor_b {
+        let key = line.split(':').next();
+        if !sensor_a.iter().any(|other| other.split(':').next() == key) {
+            println!(
+                "{}: missing ->{}",
+                key.unwrap(),
+                &line[key.unwrap().len() + 1..]
+            );
+        }
+    }
+
+    println!(
+        "Geometry: {}",
+        if geometry_differs { "DIFFERS" } else { "SAME" }
+    );
+
+    Ok(geometry_differs)
+}
+
+// compile-time check that the parser stays Send
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Legacy>();
+};
+
+fn parse_header_block(data: &[u8]) -> HeaderBlock {
+    let mut header = HeaderBlock {
+        timestamp: 0,
+        measure_id: 0,
+        frame_id: 0,
+    };
+
+    let mut timestamp_slice = &data[..8];
+    header.timestamp = timestamp_slice.read_u64::<LittleEndian>().unwrap();
+
+    let mut measure_id_slice = &data[8..10];
+    header.measure_id = measure_id_slice.read_u16::<LittleEndian>().unwrap();
+
+    let mut frame_id_slice = &data[10..12];
+    header.frame_id = frame_id_slice.read_u16::<LittleEndian>().unwrap();
+
+    header
+}
+
+fn is_block_valid(data: &[u8]) -> bool {
+    let mut block_status_slice = &data[data.len() - 4..];
+    let block_status = block_status_slice.read_u32::<LittleEndian>().unwrap();
+
+    block_status == 0xffffffff
+}
+
+/// Print every header and channel field of one lidar packet.
+pub fn print_packet(meta_file: File, data: &[u8]) {
+    let metadata = match MetaData::from_reader(meta_file) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            println!("!! {}", err);
+            return;
+        }
+    };
+    let data_format = &metadata.data_format;
+
+    let udp_profile = match metadata.udp_profile() {
+        Ok(udp_profile) => udp_profile,
+        Err(err) => {
+            println!("!! {}", err);
+            return;
+        }
+    };
+
+    let pixels_per_column = data_format.pixels_per_column;
+    let columns_per_packet = data_format.columns_per_packet;
+
+    let len_column = udp_profile.column_len(pixels_per_column);
+    let len_expected = udp_profile.packet_len(data_format);
+
+    println!(
+        "Packet: {} bytes, {} columns x {} channels (expected {} bytes)",
+        data.len(),
+        columns_per_packet,
+        pixels_per_column,
+        len_expected
+    );
+
+    if data.len() < len_expected {
+        println!("!! packet is shorter than expected, decoding complete columns only");
+    }
+
+    let packet_header_len = udp_profile.packet_header_len();
+    if data.len() < packet_header_len {
+        println!("!! packet is shorter than its header");
+        return;
+    }
+    let (packet_header, columns) = data.split_at(packet_header_len);
+
+    // packet_type, frame_id, init_id and serial_no, in 2, 2, 3 and 5 bytes
+    if packet_header_len > 0 {
+        let read = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .rev()
+                .fold(0u64, |value, &byte| value << 8 | byte as u64)
+        };
+
+        println!(
+            "Packet header: packet_type {}, frame_id {}, init_id {}, serial_no {}",
+            read(&packet_header[0..2]),
+            read(&packet_header[2..4]),
+            read(&packet_header[4..7]),
+            read(&packet_header[7..12])
+        );
+    }
+
+    for (column_index, column) in columns
+        .chunks_exact(len_column)
+        .take(columns_per_packet)
+        .enumerate()
+    {
+        let Column {
+            header,
+            valid,
+            status,
+            channels,
+        } = udp_profile.column(packet_header, column);
+
+        let mut notes = Vec::new();
+        if !valid {
+            notes.push("invalid status");
+        }
+        if header.measure_id as usize >= data_format.columns_per_frame {
+            notes.push("measure_id out of range");
+        }
+        if !data_format.in_window(header.measure_id as usize) {
+            notes.push("outside column window");
+        }
+
+        println!();
+        println!(
+            "Column {}: timestamp {}, measure_id {}, frame_id {}, status {:#010x}{}",
+            column_index,
+            header.timestamp,
+            header.measure_id,
+            header.frame_id,
+            status,
+            flags_to_string(&notes)
+        );
+        println!(
+            "  {:>7} {:>10} {:>12} {:>8} {:>8}",
+            "channel", "range_mm", "reflectivity", "signal", "nir"
+        );
+
+        for (channel, block) in channels.chunks_exact(udp_profile.channel_len()).enumerate() {
+            let mut range_slice = &block[..4];
+            let raw_range = range_slice.read_u32::<LittleEndian>().unwrap();
+            let (first, second) = udp_profile.returns(block);
+
+            // the single-return profile has a padding byte after it
+            let reflectivity = match udp_profile {
+                UdpProfile::Legacy => u16::from_le_bytes([block[4], block[5]]),
+                _ => first.reflectivity as u16,
+            };
+
+            let mut notes = Vec::new();
+            // other profiles pack more fields into the first word
+            let whole_word = matches!(
+                udp_profile,
+                UdpProfile::Legacy | UdpProfile::Rng19Rfl8Sig16Nir16
+            );
+            if whole_word && raw_range != first.range {
+                notes.push("unused range bits set");
+            }
+            if first.range == 0 {
+                notes.push("no return");
+            }
+            if reflectivity > u8::MAX as u16 {
+                notes.push("reflectivity above 8 bits");
+            }
+
+            println!(
+                "  {:>7} {:>10} {:>12} {:>8} {:>8}{}",
+                channel,
+                first.range,
+                reflectivity,
+                first.signal,
+                first.nir,
+                flags_to_string(&notes)
+            );
+
+            if let Some(second) = second {
+                let notes = if second.range == 0 {
+                    vec!["no second return"]
+                } else {
+                    Vec::new()
+                };
+
+                println!(
+                    "  {:>7} {:>10} {:>12} {:>8} {:>8}{}",
+                    format!("{}.2", channel),
+                    second.range,
+                    second.reflectivity,
+                    second.signal,
+                    second.nir,
+                    flags_to_string(&notes)
+                );
+            }
+        }
+    }
+}
+
+fn flags_to_string(notes: &[&str]) -> String {
+    if notes.is_empty() {
+        String::new()
+    } else {
+        format!("  !! {}", notes.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::calibration::CalibrationMode;
+
+    /// Metadata of a sensor with two level beams pointing straight ahead,
+    /// four columns a frame and two a packet.
+    fn metadata(udp_profile: UdpProfile) -> String {
+        format!(
+            r#"{{
+                "beam_altitude_angles": [0, 0],
+                "beam_azimuth_angles": [0, 0],
+                "beam_to_lidar_transform": [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1],
+                "data_format": {{
+                    "columns_per_frame": 4,
+                    "columns_per_packet": 2,
+                    "pixels_per_column": 2
+                }},
+                "udp_profile_lidar": "{}"
+            }}"#,
+            udp_profile.name()
+        )
+    }
+
+    /// Range in millimeters, reflectivity, signal and nir of one return.
+    type Echo = (u32, u8, u16, u16);
+
+    /// A channel block carrying `first` and, in dual-return packets, `second`.
+    fn block(udp_profile: UdpProfile, first: Echo, second: Echo) -> Vec<u8> {
+        let mut block = vec![0; udp_profile.channel_len()];
+        let mut put = |at: usize, bytes: &[u8]| block[at..at + bytes.len()].copy_from_slice(bytes);
+
+        match udp_profile {
+            UdpProfile::Legacy | UdpProfile::Rng19Rfl8Sig16Nir16 => {
+                put(0, &first.0.to_le_bytes());
+                put(4, &[first.1]);
+                put(6, &first.2.to_le_bytes());
+                put(8, &first.3.to_le_bytes());
+            }
+            UdpProfile::Rng19Rfl8Sig16Nir16Dual => {
+                put(0, &(first.0 | (first.1 as u32) << 24).to_le_bytes());
+                put(4, &(second.0 | (second.1 as u32) << 24).to_le_bytes());
+                put(8, &first.2.to_le_bytes());
+                put(10, &second.2.to_le_bytes());
+                put(12, &first.3.to_le_bytes());
+            }
+            UdpProfile::Rng15Rfl8Nir8 => {
+                put(0, &((first.0 / 8) as u16).to_le_bytes());
+                put(2, &[first.1, (first.3 >> 4) as u8]);
+            }
+        }
+
+        block
+    }
+
+    /// A packet of frame `frame_id` with the two columns from `measure_id`,
+    /// every channel carrying `block`.
+    fn packet(udp_profile: UdpProfile, frame_id: u16, measure_id: u16, block: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; udp_profile.packet_header_len()];
+        if udp_profile != UdpProfile::Legacy {
+            packet[2..4].copy_from_slice(&frame_id.to_le_bytes());
+        }
+
+        for measure_id in measure_id..measure_id + 2 {
+            let timestamp = (frame_id as u64 * 4 + measure_id as u64) * 25_000_000;
+            packet.extend_from_slice(&timestamp.to_le_bytes());
+            packet.extend_from_slice(&measure_id.to_le_bytes());
+
+            if udp_profile == UdpProfile::Legacy {
+                packet.extend_from_slice(&frame_id.to_le_bytes());
+                packet.extend_from_slice(&[0; 4]);
+            } else {
+                packet.extend_from_slice(&1u16.to_le_bytes());
+            }
+
+            packet.extend_from_slice(&block.repeat(2));
+
+            if udp_profile == UdpProfile::Legacy {
+                packet.extend_from_slice(&[0xff; 4]);
+            }
+        }
+
+        packet.resize(packet.len() + udp_profile.packet_footer_len(), 0);
+        packet
+    }
+
+    /// Points, completeness and segment of the frames written.
+    #[derive(Clone, Default)]
+    struct Frames {
+        points: Arc<Mutex<Vec<Vec<f32>>>>,
+        completeness: Arc<Mutex<Vec<f32>>>,
+        segments: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl FrameSink for Frames {
+        fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+            self.points.lock().unwrap().push(frame.points.clone());
+            self.completeness.lock().unwrap().push(frame.completeness);
+            self.segments.lock().unwrap().push(frame.segment);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn parser(builder: LegacyBuilder, udp_profile: UdpProfile) -> (Legacy, Frames) {
+        parser_with(builder, &metadata(udp_profile))
+    }
+
+    fn parser_with(builder: LegacyBuilder, metadata: &str) -> (Legacy, Frames) {
+        let frames = Frames::default();
+        let parser = builder
+            .metadata(metadata.as_bytes())
+            .sink(Box::new(frames.clone()))
+            .build()
+            .unwrap();
+
+        (parser, frames)
+    }
+
+    /// Put two complete frames of `block` everywhere, and the start of a
+    /// third one to complete the second.
+    fn put_frames(parser: &mut Legacy, udp_profile: UdpProfile, block: &[u8]) {
+        for frame_id in 0..2 {
+            parser.put(&packet(udp_profile, frame_id, 0, block));
+            parser.put(&packet(udp_profile, frame_id, 2, block));
+        }
+        parser.put(&packet(udp_profile, 2, 0, block));
+    }
+
+    /// Points of the frames written from `block` everywhere.
+    fn parse(builder: LegacyBuilder, udp_profile: UdpProfile, block: &[u8]) -> Vec<Vec<f32>> {
+        let (mut parser, frames) = parser(builder, udp_profile);
+        put_frames(&mut parser, udp_profile, block);
+        parser.finish().unwrap();
+
+        let points = frames.points.lock().unwrap();
+        points.clone()
+    }
+
+    #[test]
+    fn reserves_frame_buffers_for_every_return_kept() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16Dual;
+        let block = block(udp_profile, (1000, 10, 0, 0), (2000, 20, 0, 0));
+        let builder = Legacy::builder().second_return(SecondReturn::Merge);
+
+        let (mut parser, frames) = parser(builder, udp_profile);
+        let frame_bytes = parser.frame_bytes;
+        put_frames(&mut parser, udp_profile, &block);
+
+        // two points per pixel, which fit the buffers reserved up front
+        assert_eq!(parser.stats().frames, 2);
+        assert_eq!(parser.stats().peak_frame_bytes, frame_bytes);
+        parser.finish().unwrap();
+
+        for points in frames.points.lock().unwrap().iter() {
+            assert_eq!(points.len(), 2 * 8 * 4);
+        }
+    }
+
+    /// A sink on a full disk.
+    struct FullDisk;
+
+    impl FrameSink for FullDisk {
+        fn write_frame(&mut self, _frame: &Frame) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "no space left"))
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn returns_first_write_error_from_finish() {
+        let udp_profile = UdpProfile::Legacy;
+        let block = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+        let mut parser = Legacy::builder()
+            .metadata(metadata(udp_profile).as_bytes())
+            .sink(Box::new(FullDisk))
+            .build()
+            .unwrap();
+
+        // frames keep coming after the writer stopped
+        put_frames(&mut parser, udp_profile, &block);
+        put_frames(&mut parser, udp_profile, &block);
+
+        let err = parser.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(err.to_string(), "0000: no space left");
+    }
+
+    #[test]
+    fn diffs_metadata_through_checked_loader() {
+        let legacy = metadata(UdpProfile::Legacy);
+        let dual = metadata(UdpProfile::Rng19Rfl8Sig16Nir16Dual);
+        let tilted = legacy.replace(
+            r#""beam_altitude_angles": [0, 0]"#,
+            r#""beam_altitude_angles": [0, 1]"#,
+        );
+        let short = legacy.replace(
+            r#""beam_altitude_angles": [0, 0]"#,
+            r#""beam_altitude_angles": [0]"#,
+        );
+
+        assert!(!diff_metadata(legacy.as_bytes(), legacy.as_bytes()).unwrap());
+        assert!(diff_metadata(legacy.as_bytes(), tilted.as_bytes()).unwrap());
+
+        // the packet layout is reported, but doesn't move any point
+        assert!(!diff_metadata(legacy.as_bytes(), dual.as_bytes()).unwrap());
+
+        assert!(matches!(
+            diff_metadata(legacy.as_bytes(), short.as_bytes()),
+            Err(OusterError::Length {
+                field: "beam_altitude_angles",
+                len: 1,
+                expected: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn shifts_points_by_altitude_correction() {
+        let path = std::env::temp_dir().join(format!(
+            "ouster-parser-altitude-correction-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&path, "5\n-10\n").unwrap();
+        let altitude_correction = Calibration::load_altitude_correction(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let udp_profile = UdpProfile::Legacy;
+        let block = block(udp_profile, (10_000, 10, 0, 0), (0, 0, 0, 0));
+
+        let level = parse(Legacy::builder(), udp_profile, &block);
+        let corrected = parse(
+            Legacy::builder().altitude_correction(Some(altitude_correction)),
+            udp_profile,
+            &block,
+        );
+
+        // the first column points straight ahead, its beams now tilted
+        let z = |points: &[f32], channel: usize| points[channel * 4 + 2];
+        let x = |points: &[f32], channel: usize| points[channel * 4];
+
+        assert!(z(&level[0], 0).abs() < 1e-5 && z(&level[0], 1).abs() < 1e-5);
+        assert!((z(&corrected[0], 0) - 10.0 * 5f32.to_radians().sin()).abs() < 1e-4);
+        assert!((z(&corrected[0], 1) - 10.0 * (-10f32).to_radians().sin()).abs() < 1e-4);
+        assert!((x(&corrected[0], 1) - 10.0 * 10f32.to_radians().cos()).abs() < 1e-4);
+    }
+
+    #[test]
+    fn shifts_points_by_delta_calibration_file() {
+        let udp_profile = UdpProfile::Legacy;
+        let block = block(udp_profile, (10_000, 10, 0, 0), (0, 0, 0, 0));
+
+        // the same deltas through both formats of --calibration
+        let files = [
+            ("csv", "azimuth,altitude\n0,5\n30,-10\n"),
+            (
+                "json",
+                r#"{"beam_azimuth_angles": [0, 30], "beam_altitude_angles": [5, -10]}"#,
+            ),
+        ];
+
+        for (extension, text) in files {
+            let path = std::env::temp_dir().join(format!(
+                "ouster-parser-calibration-{}.{}",
+                std::process::id(),
+                extension
+            ));
+            std::fs::write(&path, text).unwrap();
+            let calibration = Calibration::load(&path, CalibrationMode::Delta).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            let points = &parse(
+                Legacy::builder().calibration(Some(calibration)),
+                udp_profile,
+                &block,
+            )[0];
+            let point = |channel: usize| &points[channel * 4..channel * 4 + 3];
+
+            // the first column points straight ahead, level beams now
+            // tilted and the second one turned away from the x axis
+            let straight = point(0);
+            assert!((straight[2] - 10.0 * 5f32.to_radians().sin()).abs() < 1e-4);
+            assert!((straight[0] - 10.0 * 5f32.to_radians().cos()).abs() < 1e-4);
+            assert!(straight[1].abs() < 1e-4);
+
+            let turned = point(1);
+            let planar = 10.0 * 10f32.to_radians().cos();
+            assert!((turned[2] - 10.0 * (-10f32).to_radians().sin()).abs() < 1e-4);
+            assert!((turned[0].hypot(turned[1]) - planar).abs() < 1e-4);
+            assert!((turned[1].abs() - planar * 30f32.to_radians().sin()).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn downsampled_columns_match_native_lower_mode() {
+        for udp_profile in PROFILES {
+            let block = block(udp_profile, (5000, 50, 500, 50), (6000, 60, 600, 0));
+
+            // every other column of four matches the two columns of a sensor
+            // set to half the resolution, which sends all of them in one packet
+            let (mut downsampled, downsampled_frames) =
+                parser(Legacy::builder().column_stride(2), udp_profile);
+            put_frames(&mut downsampled, udp_profile, &block);
+            downsampled.finish().unwrap();
+
+            let native_metadata = metadata(udp_profile)
+                .replace(r#""columns_per_frame": 4"#, r#""columns_per_frame": 2"#);
+            let (mut native, native_frames) = parser_with(Legacy::builder(), &native_metadata);
+            for frame_id in 0..3 {
+                native.put(&packet(udp_profile, frame_id, 0, &block));
+            }
+            native.finish().unwrap();
+
+            let downsampled_points = downsampled_frames.points.lock().unwrap();
+            let native_points = native_frames.points.lock().unwrap();
+            assert_eq!(downsampled_points.len(), 2);
+            for (downsampled, native) in downsampled_points.iter().zip(native_points.iter()) {
+                assert_eq!(downsampled.len(), 4 * 4);
+                assert_eq!(downsampled.len(), native.len());
+                for (a, b) in downsampled.iter().zip(native) {
+                    assert!((a - b).abs() < 1e-4, "{a} != {b}");
+                }
+            }
+
+            assert_eq!(*downsampled_frames.completeness.lock().unwrap(), [1.0, 1.0]);
+            assert_eq!(*native_frames.completeness.lock().unwrap(), [1.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn adds_range_difference_of_dual_returns() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16Dual;
+        let builder = || {
+            Legacy::builder()
+                .range_difference(true)
+                .second_return(SecondReturn::Merge)
+        };
+
+        let both = block(udp_profile, (1000, 10, 0, 0), (2500, 20, 0, 0));
+        for points in parse(builder(), udp_profile, &both) {
+            // both returns of a pixel carry the same difference
+            assert_eq!(points.len(), 2 * 8 * 5);
+            for point in points.chunks_exact(5) {
+                assert_eq!(point[4], 1.5);
+            }
+        }
+
+        let single = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+        for points in parse(builder(), udp_profile, &single) {
+            assert_eq!(points.len(), 8 * 5);
+            for point in points.chunks_exact(5) {
+                assert!(point[4].is_nan());
+            }
+        }
+
+        let single_return = Legacy::builder()
+            .range_difference(true)
+            .metadata(metadata(UdpProfile::Rng19Rfl8Sig16Nir16).as_bytes())
+            .build();
+        assert!(matches!(single_return, Err(OusterError::Config(_))));
+    }
+
+    #[test]
+    fn splits_segments_at_init_id_change() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16;
+        let block = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+        let packet = |init_id: u32, frame_id, measure_id| {
+            let mut packet = packet(udp_profile, frame_id, measure_id, &block);
+            packet[4..7].copy_from_slice(&init_id.to_le_bytes()[..3]);
+            packet
+        };
+
+        let segments = |split_on_reconfiguration| {
+            let builder = Legacy::builder().split_on_reconfiguration(split_on_reconfiguration);
+            let (mut parser, frames) = parser(builder, udp_profile);
+
+            // the sensor restarts halfway through frame 6, counting
+            // frames from 0 again
+            parser.put(&packet(1, 5, 0));
+            parser.put(&packet(1, 5, 2));
+            parser.put(&packet(1, 6, 0));
+            for frame_id in 0..2 {
+                parser.put(&packet(2, frame_id, 0));
+                parser.put(&packet(2, frame_id, 2));
+            }
+            parser.put(&packet(2, 2, 0));
+
+            let stats = parser.stats();
+            assert_eq!(stats.reconfigurations, 1);
+            parser.finish().unwrap();
+
+            let segments = frames.segments.lock().unwrap();
+            (segments.clone(), stats.discarded_frames)
+        };
+
+        assert_eq!(segments(true), (vec![0, 1, 1], 1));
+        assert_eq!(segments(false), (vec![0, 0, 0], 1));
+    }
+
+    #[test]
+    fn skips_invalid_columns_uncounted_in_lenient_mode() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16;
+        let block = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+
+        // the second column of the first packet has a cleared status
+        let status = udp_profile.packet_header_len() + udp_profile.column_len(2) + 10;
+        let mut invalid = packet(udp_profile, 0, 0, &block);
+        invalid[status..status + 2].fill(0);
+
+        for lenient in [false, true] {
+            let (mut parser, frames) = parser(Legacy::builder().lenient(lenient), udp_profile);
+            parser.put(&invalid);
+            parser.put(&packet(udp_profile, 0, 2, &block));
+            parser.put(&packet(udp_profile, 1, 0, &block));
+
+            let stats = parser.stats();
+            parser.finish().unwrap();
+
+            if lenient {
+                assert_eq!((stats.invalid_columns, stats.broken_packets), (0, 0));
+                assert_eq!(*frames.completeness.lock().unwrap(), [0.75]);
+            } else {
+                assert_eq!((stats.invalid_columns, stats.broken_packets), (1, 1));
+                assert!(frames.completeness.lock().unwrap().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn writes_only_completed_frames_once_cancelled() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16;
+        let block = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let updates = Arc::new(Mutex::new(Vec::new()));
+
+        let builder = Legacy::builder()
+            .cancel_on(cancel.clone())
+            .on_progress(Duration::ZERO, {
+                let updates = updates.clone();
+                move |update: ProgressUpdate| {
+                    let update = (update.bytes_processed, update.frames_written);
+                    updates.lock().unwrap().push(update);
+                }
+            });
+        let (mut parser, frames) = parser(builder, udp_profile);
+        put_frames(&mut parser, udp_profile, &block);
+
+        // the frame started last is dropped with the packets that follow
+        cancel.store(true, Ordering::Relaxed);
+        parser.put(&packet(udp_profile, 2, 2, &block));
+        parser.put(&packet(udp_profile, 3, 0, &block));
+
+        let stats = parser.stats();
+        parser.finish().unwrap();
+
+        let len = packet(udp_profile, 0, 0, &block).len();
+        let updates = updates.lock().unwrap();
+        assert_eq!((stats.packets, stats.frames), (5, 2));
+        assert_eq!(frames.points.lock().unwrap().len(), 2);
+        assert_eq!(updates.len(), 6);
+        assert_eq!(updates[0].0, len);
+        assert_eq!(updates.last(), Some(&(5 * len, 2)));
+    }
+
+    #[test]
+    fn recovers_only_points_parsed_from_partial_packets() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16;
+        let block = block(udp_profile, (1000, 10, 0, 0), (0, 0, 0, 0));
+        let packet = |init_id: u32, frame_id, measure_id| {
+            let mut packet = packet(udp_profile, frame_id, measure_id, &block);
+            packet[4..7].copy_from_slice(&init_id.to_le_bytes()[..3]);
+            packet
+        };
+        let footer = udp_profile.packet_footer_len();
+        let one_column = udp_profile.packet_header_len() + udp_profile.column_len(2);
+
+        let (mut parser, _) = parser(Legacy::builder().column_stride(2), udp_profile);
+        parser.put(&packet(1, 0, 0));
+
+        // both columns survive the lost footer, but the stride skips one
+        let whole = packet(2, 0, 2);
+        parser.put_partial(&whole[..whole.len() - footer]);
+        assert_eq!(parser.stats().recovered_points, 2);
+        assert_eq!(parser.stats().reconfigurations, 1);
+
+        // the second column is cut, which breaks frame 1
+        parser.put_partial(&packet(2, 1, 0)[..one_column + 4]);
+        assert_eq!(parser.stats().recovered_points, 4);
+
+        // and nothing more is taken from the broken frame
+        let whole = packet(2, 1, 2);
+        parser.put_partial(&whole[..whole.len() - footer]);
+        assert_eq!(parser.stats().recovered_points, 4);
+
+        parser.finish().unwrap();
+    }
+
+    const PROFILES: [UdpProfile; 4] = [
+        UdpProfile::Legacy,
+        UdpProfile::Rng19Rfl8Sig16Nir16,
+        UdpProfile::Rng19Rfl8Sig16Nir16Dual,
+        UdpProfile::Rng15Rfl8Nir8,
+    ];
+
+    #[test]
+    fn decodes_returns_of_every_profile() {
+        for udp_profile in PROFILES {
+            let (first, second) = udp_profile.returns(&block(
+                udp_profile,
+                (8000, 200, 3000, 4000),
+                (9000, 100, 5000, 0),
+            ));
+            let first = (first.range, first.reflectivity, first.signal, first.nir);
+            let second = second.map(|second| (second.range, second.reflectivity, second.signal));
+
+            match udp_profile {
+                UdpProfile::Rng15Rfl8Nir8 => {
+                    // no signal, and nir only kept to 8 bits
+                    assert_eq!(first, (8000, 200, 0, 4000 >> 4 << 4));
+                    assert_eq!(second, None);
+                }
+                UdpProfile::Rng19Rfl8Sig16Nir16Dual => {
+                    assert_eq!(first, (8000, 200, 3000, 4000));
+                    assert_eq!(second, Some((9000, 100, 5000)));
+                }
+                _ => {
+                    assert_eq!(first, (8000, 200, 3000, 4000));
+                    assert_eq!(second, None);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reads_reflectivity_at_profile_offset() {
+        // signal and nir disagree with the reflectivity, so reading
+        // either instead would show
+        for udp_profile in PROFILES {
+            let block = block(udp_profile, (8000, 200, 0xa5a5, 0x5a50), (0, 0, 0, 0));
+            let frames = parse(Legacy::builder(), udp_profile, &block);
+
+            assert_eq!(frames.len(), 2);
+            for points in &frames {
+                assert_eq!(points.len(), 8 * 4);
+                for point in points.chunks_exact(4) {
+                    assert_eq!(point[3], 200.0 / 255.0);
+                    assert!((point[0].hypot(point[1]) - 8.0).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normalizes_intensity_by_bit_depth() {
+        let udp_profile = UdpProfile::Rng19Rfl8Sig16Nir16;
+        let intensity = |intensity_field: IntensityField, echo: Echo| {
+            let block = block(udp_profile, echo, (0, 0, 0, 0));
+            let builder = Legacy::builder().intensity_field(intensity_field);
+            parse(builder, udp_profile, &block)[0][3]
+        };
+
+        assert_eq!(
+            intensity(IntensityField::Reflectivity, (1000, 255, 0, 0)),
+            1.0
+        );
+        assert_eq!(
+            intensity(IntensityField::Reflectivity, (1000, 51, 0, 0)),
+            0.2
+        );
+        assert_eq!(intensity(IntensityField::Signal, (1000, 1, 65535, 0)), 1.0);
+        assert_eq!(
+            intensity(IntensityField::Signal, (1000, 1, 255, 0)),
+            255.0 / 65535.0
+        );
+        assert_eq!(intensity(IntensityField::Nir, (1000, 1, 0, 65535)), 1.0);
+        assert_eq!(intensity(IntensityField::Nir, (1000, 1, 0, 13107)), 0.2);
     }
 }
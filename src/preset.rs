@@ -0,0 +1,149 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::str::FromStr;
+
+use serde_json::json;
+
+/// Columns of a packet in every lidar mode
+const COLUMNS_PER_PACKET: usize = 16;
+
+/// Default destination port of lidar packets
+const UDP_PORT_LIDAR: u16 = 7502;
+
+/// A sensor model and lidar mode parsed with factory-nominal intrinsics,
+/// for captures whose metadata was lost.
+///
+/// Beams are spread evenly over the vertical field of view without any
+/// azimuth offsets, so points are only approximately where a calibrated
+/// unit's metadata would put them.
+#[derive(Clone, Copy)]
+pub struct SensorModel {
+    family: Family,
+    channels: usize,
+    columns_per_frame: usize,
+    rate: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Family {
+    Os0,
+    Os1,
+    Os2,
+}
+
+impl Family {
+    fn vertical_fov(&self) -> f32 {
+        match self {
+            Family::Os0 => 90.0,
+            Family::Os1 => 45.0,
+            Family::Os2 => 22.5,
+        }
+    }
+
+    fn lidar_origin_to_beam_origin_mm(&self) -> f32 {
+        match self {
+            Family::Os0 => 27.67,
+            Family::Os1 => 15.806,
+            Family::Os2 => 13.762,
+        }
+    }
+}
+
+impl FromStr for SensorModel {
+    type Err = String;
+
+    /// `<family>-<channels>[:<columns>x<rate>]`, e.g. `os1-64:2048x10`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (model, mode) = text.split_once(':').unwrap_or((text, "1024x10"));
+
+        let (family, channels) = model
+            .to_ascii_lowercase()
+            .replace("os-", "os")
+            .split_once('-')
+            .map(|(family, channels)| (family.to_string(), channels.to_string()))
+            .ok_or(format!("unknown sensor model {} (e.g. os1-64)", model))?;
+
+        let family = match family.as_str() {
+            "os0" => Family::Os0,
+            "os1" => Family::Os1,
+            "os2" => Family::Os2,
+            _ => return Err(format!("unknown sensor family {} (os0, os1, os2)", family)),
+        };
+
+        let channels = match channels.parse() {
+            Ok(channels @ (32 | 64 | 128)) => channels,
+            _ => return Err(format!("unknown channel count {} (32, 64, 128)", channels)),
+        };
+
+        let (columns_per_frame, rate) = match mode {
+            "512x10" => (512, 10),
+            "512x20" => (512, 20),
+            "1024x10" => (1024, 10),
+            "1024x20" => (1024, 20),
+            "2048x10" => (2048, 10),
+            _ => {
+                return Err(format!(
+                    "unknown lidar mode {} (512x10, 512x20, 1024x10, 1024x20, 2048x10)",
+                    mode
+                ))
+            }
+        };
+
+        Ok(Self {
+            family,
+            channels,
+            columns_per_frame,
+            rate,
+        })
+    }
+}
+
+impl SensorModel {
+    /// Metadata json with the nominal intrinsics of the model.
+    pub fn metadata(&self) -> Vec<u8> {
+        let fov = self.family.vertical_fov();
+        let step = fov / (self.channels - 1) as f32;
+
+        let altitudes: Vec<f32> = (0..self.channels)
+            .map(|channel| fov / 2.0 - step * channel as f32)
+            .collect();
+
+        let mut beam_to_lidar = [0.0f32; 16];
+        for i in 0..4 {
+            beam_to_lidar[i * 5] = 1.0;
+        }
+        beam_to_lidar[3] = self.family.lidar_origin_to_beam_origin_mm();
+
+        let metadata = json!({
+            "beam_altitude_angles": altitudes,
+            "beam_azimuth_angles": vec![0.0f32; self.channels],
+            "beam_to_lidar_transform": beam_to_lidar,
+            "data_format": {
+                "columns_per_frame": self.columns_per_frame,
+                "columns_per_packet": COLUMNS_PER_PACKET,
+                "pixels_per_column": self.channels,
+            },
+            "lidar_mode": format!("{}x{}", self.columns_per_frame, self.rate),
+            "udp_port_lidar": UDP_PORT_LIDAR,
+        });
+
+        serde_json::to_vec(&metadata).unwrap()
+    }
+}
@@ -0,0 +1,249 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "profile")]
+use std::{
+    fs::File,
+    io::{prelude::*, BufWriter},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "profile")]
+use serde::Serialize;
+
+pub const MAIN_THREAD: u32 = 1;
+pub const WRITER_THREAD: u32 = 2;
+
+#[cfg(feature = "profile")]
+#[derive(Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    ts: u64,
+    dur: u64,
+    pid: u32,
+    tid: u32,
+}
+
+/// Trace file written as events come in, so a long capture doesn't keep
+/// them all in memory.
+#[cfg(feature = "profile")]
+struct Output {
+    writer: BufWriter<File>,
+    num_events: usize,
+    /// First write error, after which events are dropped
+    error: Option<io::Error>,
+}
+
+#[cfg(feature = "profile")]
+struct Recorder {
+    epoch: Instant,
+    output: Mutex<Output>,
+}
+
+/// Records per-stage timings as chrome://tracing complete events.
+///
+/// Events are streamed in the json array format, which trace viewers load
+/// even without the closing bracket of an interrupted run. Built without
+/// the `profile` feature, the profiler is empty and every call compiles
+/// to nothing.
+#[derive(Clone)]
+pub struct Profiler {
+    #[cfg(feature = "profile")]
+    recorder: Option<Arc<Recorder>>,
+}
+
+pub struct Span {
+    #[cfg(feature = "profile")]
+    profiler: Profiler,
+    #[cfg(feature = "profile")]
+    name: &'static str,
+    #[cfg(feature = "profile")]
+    tid: u32,
+    #[cfg(feature = "profile")]
+    start: Instant,
+}
+
+impl Profiler {
+    /// A profiler recording nothing.
+    pub fn disabled() -> Self {
+        Self {
+            #[cfg(feature = "profile")]
+            recorder: None,
+        }
+    }
+
+    /// A profiler streaming its events into a new file at `path`.
+    #[cfg(feature = "profile")]
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"[\n")?;
+
+        Ok(Self {
+            recorder: Some(Arc::new(Recorder {
+                epoch: Instant::now(),
+                output: Mutex::new(Output {
+                    writer,
+                    num_events: 0,
+                    error: None,
+                }),
+            })),
+        })
+    }
+
+    #[cfg(not(feature = "profile"))]
+    pub fn create(_path: &Path) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without the profile feature",
+        ))
+    }
+
+    #[inline]
+    pub fn span(&self, name: &'static str, tid: u32) -> Option<Span> {
+        #[cfg(feature = "profile")]
+        {
+            self.recorder.as_ref()?;
+
+            Some(Span {
+                profiler: self.clone(),
+                name,
+                tid,
+                start: Instant::now(),
+            })
+        }
+
+        #[cfg(not(feature = "profile"))]
+        {
+            let _ = (name, tid);
+            None
+        }
+    }
+
+    #[inline]
+    pub fn record(&self, name: &'static str, tid: u32, start: Instant, dur: Duration) {
+        #[cfg(feature = "profile")]
+        if let Some(recorder) = &self.recorder {
+            let event = TraceEvent {
+                name,
+                ph: "X",
+                ts: start.duration_since(recorder.epoch).as_micros() as u64,
+                dur: dur.as_micros() as u64,
+                pid: 1,
+                tid,
+            };
+
+            let mut output = recorder.output.lock().unwrap();
+            if output.error.is_some() {
+                return;
+            }
+
+            let separator: &[u8] = if output.num_events > 0 { b",\n" } else { b"" };
+            let result = output.writer.write_all(separator).and_then(|()| {
+                serde_json::to_writer(&mut output.writer, &event).map_err(io::Error::from)
+            });
+
+            match result {
+                Ok(()) => output.num_events += 1,
+                Err(err) => output.error = Some(err),
+            }
+        }
+
+        #[cfg(not(feature = "profile"))]
+        let _ = (name, tid, start, dur);
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(feature = "profile")]
+        return self.recorder.is_some();
+
+        #[cfg(not(feature = "profile"))]
+        false
+    }
+
+    /// Close the trace, reporting the first error writing it.
+    pub fn finish(&self) -> io::Result<()> {
+        #[cfg(feature = "profile")]
+        if let Some(recorder) = &self.recorder {
+            let mut output = recorder.output.lock().unwrap();
+            if let Some(err) = output.error.take() {
+                return Err(err);
+            }
+
+            output.writer.write_all(b"\n]\n")?;
+            output.writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        #[cfg(feature = "profile")]
+        self.profiler
+            .record(self.name, self.tid, self.start, self.start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_profiler_records_nothing() {
+        let profiler = Profiler::disabled();
+
+        assert!(!profiler.is_enabled());
+        assert!(profiler.span("lidar", MAIN_THREAD).is_none());
+        profiler.finish().unwrap();
+
+        // without the feature nothing is left to check at runtime
+        #[cfg(not(feature = "profile"))]
+        assert_eq!(std::mem::size_of::<Profiler>(), 0);
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn streams_events_as_json_array() {
+        let path =
+            std::env::temp_dir().join(format!("ouster-parser-profile-{}.json", std::process::id()));
+        let profiler = Profiler::create(&path).unwrap();
+
+        drop(profiler.span("lidar", MAIN_THREAD));
+        profiler.record("write", WRITER_THREAD, Instant::now(), Duration::ZERO);
+
+        profiler.finish().unwrap();
+
+        let events: Vec<serde_json::Value> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["name"], "lidar");
+        assert_eq!(events[1]["tid"], WRITER_THREAD);
+    }
+}
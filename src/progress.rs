@@ -0,0 +1,196 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::File,
+    io::{self, prelude::*, LineWriter},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::sink::{Frame, FrameSink};
+
+/// Bumped whenever an event changes in a way readers have to handle.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Minimum gap between two frame-written events.
+const FRAME_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event<'a> {
+    RunStarted {
+        total_bytes: usize,
+    },
+    FrameWritten {
+        index: usize,
+        frame_id: u16,
+        timestamp: u64,
+        points: usize,
+        frames_written: usize,
+    },
+    Warning {
+        message: &'a str,
+    },
+    RunFinished {
+        frames_written: usize,
+        seconds: f64,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+struct Emitter {
+    writer: Box<dyn Write + Send>,
+    start: Instant,
+    last_frame_event: Option<Instant>,
+    frames_written: usize,
+}
+
+/// Progress of a `Legacy` parser, given to its progress callback.
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressUpdate {
+    /// Lidar payload bytes put so far
+    pub bytes_processed: usize,
+    /// Frames written by every sink
+    pub frames_written: usize,
+}
+
+/// Newline-delimited json progress events for wrapping programs.
+///
+/// Like the profiler, a disabled handle holds nothing and clones share
+/// one output, so events from both threads come out whole and in order.
+#[derive(Clone)]
+pub struct Progress {
+    emitter: Option<Arc<Mutex<Emitter>>>,
+}
+
+impl Progress {
+    /// Emits to `path`, or to stderr if it is `-`.
+    pub fn new(path: Option<&Path>) -> Self {
+        let emitter = path.map(|path| {
+            let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+                Box::new(io::stderr())
+            } else {
+                Box::new(LineWriter::new(File::create(path).unwrap()))
+            };
+
+            Arc::new(Mutex::new(Emitter {
+                writer,
+                start: Instant::now(),
+                last_frame_event: None,
+                frames_written: 0,
+            }))
+        });
+
+        Self { emitter }
+    }
+
+    pub fn run_started(&self, total_bytes: usize) {
+        self.emit(|_| Some(Event::RunStarted { total_bytes }));
+    }
+
+    pub fn warning(&self, message: &str) {
+        self.emit(|_| Some(Event::Warning { message }));
+    }
+
+    pub fn run_finished(&self) {
+        self.emit(|emitter| {
+            Some(Event::RunFinished {
+                frames_written: emitter.frames_written,
+                seconds: emitter.start.elapsed().as_secs_f64(),
+            })
+        });
+    }
+
+    /// Counts a written frame, emitting an event unless one went out recently.
+    fn frame_written(&self, frame: &Frame) {
+        self.emit(|emitter| {
+            emitter.frames_written += 1;
+
+            let now = Instant::now();
+            if emitter
+                .last_frame_event
+                .is_some_and(|last| now - last < FRAME_EVENT_INTERVAL)
+            {
+                return None;
+            }
+            emitter.last_frame_event = Some(now);
+
+            Some(Event::FrameWritten {
+                index: frame.id,
+                frame_id: frame.frame_id,
+                timestamp: frame.timestamp,
+                points: frame.num_points(),
+                frames_written: emitter.frames_written,
+            })
+        });
+    }
+
+    fn emit<'a, F>(&self, make_event: F)
+    where
+        F: FnOnce(&mut Emitter) -> Option<Event<'a>>,
+    {
+        if let Some(emitter) = &self.emitter {
+            let mut emitter = emitter.lock().unwrap();
+
+            if let Some(event) = make_event(&mut emitter) {
+                let envelope = Envelope {
+                    version: SCHEMA_VERSION,
+                    event,
+                };
+
+                let mut line = serde_json::to_vec(&envelope).unwrap();
+                line.push(b'\n');
+                let _ = emitter.writer.write_all(&line);
+            }
+        }
+    }
+}
+
+/// Reports frames as written; placed after the file sinks so an event
+/// is only emitted once the files of its frame exist.
+pub struct ProgressSink {
+    progress: Progress,
+}
+
+impl ProgressSink {
+    pub fn new(progress: Progress) -> Self {
+        Self { progress }
+    }
+}
+
+impl FrameSink for ProgressSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.progress.frame_written(frame);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use packet::{ether, ip, udp, Packet};
+
+use crate::{
+    capture::CapturedPacket,
+    defrag::{DatagramHeader, DefragWriter},
+    sequence::{FragmentError, IPV4Seq},
+};
+
+/// Lidar udp payloads out of the ethernet frames of a capture.
+///
+/// Frames are checked against their ipv4 and udp length fields, with
+/// any ethernet padding trimmed, and fragmented datagrams are put back
+/// together unless reassembly is turned off.
+pub struct Receiver {
+    seq: IPV4Seq,
+    /// Destination ports of the lidar streams
+    ports: Vec<u16>,
+    reassemble: bool,
+    num_non_udp: usize,
+    /// Packets left to trace
+    num_traced: usize,
+    /// Decisions taken on the current packet, only kept while tracing
+    trail: Option<Vec<String>>,
+    defragmented: Option<DefragWriter>,
+    /// Packets whose ipv4 or udp length disagreed with the bytes received
+    /// beyond ethernet padding
+    num_length_mismatches: usize,
+    /// Lidar payload prefixes of datagrams given up on, by port
+    partials: Vec<(u16, Vec<u8>)>,
+    /// The malformed fragment that stopped a strict run
+    error: Option<FragmentError>,
+    fragments: FragmentCounts,
+    /// Set to stop receiving
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+/// Ipv4 fragments reassembled by a `Receiver`, and the datagrams they
+/// started and completed.
+#[derive(Clone, Copy, Default)]
+pub struct FragmentCounts {
+    pub fragments: usize,
+    /// Datagrams whose first fragment was received
+    pub started: usize,
+    pub completed: usize,
+}
+
+/// Ethernet frames shorter than this are padded, not counting the FCS.
+const MIN_ETHERNET_LEN: usize = 60;
+
+impl Receiver {
+    /// Receive the datagrams sent to `ports`, reassembled by `seq`.
+    pub fn new(ports: Vec<u16>, seq: IPV4Seq) -> Self {
+        Self {
+            seq,
+            ports,
+            reassemble: true,
+            num_non_udp: 0,
+            num_traced: 0,
+            trail: None,
+            defragmented: None,
+            num_length_mismatches: 0,
+            partials: Vec::new(),
+            error: None,
+            fragments: FragmentCounts::default(),
+            cancel: None,
+        }
+    }
+
+    /// Skip fragments instead of reassembling them.
+    pub fn reassemble(mut self, reassemble: bool) -> Self {
+        self.reassemble = reassemble;
+        self
+    }
+
+    /// Keep the decisions taken on the first `num_packets` packets.
+    pub fn trace(mut self, num_packets: usize) -> Self {
+        self.num_traced = num_packets;
+        self
+    }
+
+    /// Write the lidar datagrams received, reassembled, to a capture.
+    pub fn defragmented(mut self, defragmented: Option<DefragWriter>) -> Self {
+        self.defragmented = defragmented;
+        self
+    }
+
+    /// Receive nothing more once `cancel` is set.
+    pub fn cancel_on(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Start on the next packet, which is traced if any are left to.
+    pub fn start_packet(&mut self) {
+        if self.num_traced > 0 {
+            self.num_traced -= 1;
+            self.trail = Some(Vec::new());
+        }
+    }
+
+    /// The decisions taken on the current packet, if it is traced.
+    pub fn take_trail(&mut self) -> Option<Vec<String>> {
+        self.trail.take()
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trail.is_some()
+    }
+
+    /// Note a decision on the current packet while tracing it.
+    pub fn step<F: FnOnce() -> String>(&mut self, step: F) {
+        if let Some(trail) = &mut self.trail {
+            trail.push(step());
+        }
+    }
+
+    /// The destination port and lidar payload of an ethernet frame
+    /// received some other way than from a capture.
+    pub fn parse_packet(&mut self, frame: &[u8]) -> Option<(u16, Vec<u8>)> {
+        let packet = CapturedPacket {
+            data: frame,
+            caplen: frame.len() as u32,
+            origlen: frame.len() as u32,
+            timestamp: None,
+            number: 0,
+        };
+
+        self.receive(&packet)
+    }
+
+    /// Lidar payload prefixes of the datagrams given up on since the last
+    /// call, by port, oldest first.
+    pub fn take_partials(&mut self) -> Vec<(u16, Vec<u8>)> {
+        std::mem::take(&mut self.partials)
+    }
+
+    /// The malformed fragment that stopped a strict run, after which no
+    /// more packets are received.
+    pub fn error(&self) -> Option<&FragmentError> {
+        self.error.as_ref()
+    }
+
+    pub fn num_non_udp(&self) -> usize {
+        self.num_non_udp
+    }
+
+    /// Packets whose ipv4 or udp length disagreed with the bytes received
+    /// beyond ethernet padding.
+    pub fn num_length_mismatches(&self) -> usize {
+        self.num_length_mismatches
+    }
+
+    /// Fragments reaching past the largest possible datagram.
+    pub fn num_rejected(&self) -> usize {
+        self.seq.num_rejected()
+    }
+
+    pub fn fragment_counts(&self) -> FragmentCounts {
+        self.fragments
+    }
+
+    /// Whether a frame, which may be truncated, is sent to a lidar port.
+    pub fn is_lidar_packet(&self, data: &[u8]) -> bool {
+        is_lidar_packet(data, &self.ports)
+    }
+
+    /// Finish the capture of defragmented datagrams, if any.
+    pub fn finish(self) -> io::Result<()> {
+        match self.defragmented {
+            Some(defragmented) => defragmented.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// The destination port and payload of a lidar udp packet.
+    pub fn receive(&mut self, packet: &CapturedPacket) -> Option<(u16, Vec<u8>)> {
+        if self.error.is_some() || self.is_cancelled() {
+            return None;
+        }
+
+        let ether = match ether::Packet::new(packet.data) {
+            Ok(ether) => ether,
+            _ => {
+                self.step(|| "not ethernet, skipped".to_string());
+                return None;
+            }
+        };
+
+        let v4 = match ip::v4::Packet::new(ether.payload()) {
+            Ok(v4) => v4,
+            _ => {
+                self.step(|| "ether ok, not ipv4, skipped".to_string());
+                return None;
+            }
+        };
+
+        // minimum-size frames are padded, and the padding must not end up
+        // in the payload, so the ipv4 total length bounds it
+        let total_length = v4.length() as usize;
+        let ip_data = ether.payload();
+
+        if total_length > ip_data.len() {
+            self.num_length_mismatches += 1;
+            self.step(|| {
+                format!(
+                    "ipv4 total length {} > {} B captured, skipped",
+                    total_length,
+                    ip_data.len()
+                )
+            });
+            return None;
+        }
+
+        if total_length < ip_data.len() && packet.data.len() > MIN_ETHERNET_LEN {
+            self.num_length_mismatches += 1;
+        }
+
+        let v4 = match ip::v4::Packet::new(&ip_data[..total_length]) {
+            Ok(v4) => v4,
+            _ => {
+                self.step(|| format!("ipv4 total length {} too short, skipped", total_length));
+                return None;
+            }
+        };
+
+        self.step(|| "ether ok, ipv4 ok".to_string());
+
+        // every fragment carries the protocol, so other traffic never
+        // reaches the reassembler
+        if v4.protocol() != ip::Protocol::Udp {
+            self.num_non_udp += 1;
+            self.step(|| format!("protocol {} not udp, skipped", u8::from(v4.protocol())));
+            return None;
+        }
+
+        let offset = v4.offset() as usize * 8;
+        let fragmented = v4.flags().contains(ip::v4::Flags::MORE_FRAGMENTS) || offset != 0;
+
+        let header = DatagramHeader {
+            source: v4.source(),
+            destination: v4.destination(),
+            id: v4.id(),
+            ttl: v4.ttl(),
+        };
+
+        let data = if self.reassemble {
+            if fragmented {
+                self.fragments.fragments += 1;
+                if offset == 0 {
+                    self.fragments.started += 1;
+                }
+            }
+
+            match self.seq.put_and_get(v4) {
+                Ok(Some(data)) => {
+                    if fragmented {
+                        self.fragments.completed += 1;
+                        self.step(|| format!("datagram of {} B reassembled", data.len()));
+                    }
+
+                    // the udp length of a prefix covers bytes never received
+                    for partial in self.seq.take_partials() {
+                        if partial.len() < 8 {
+                            continue;
+                        }
+
+                        let port = u16::from_be_bytes([partial[2], partial[3]]);
+                        if self.ports.contains(&port) {
+                            self.partials.push((port, partial[8..].to_vec()));
+                        }
+                    }
+
+                    data
+                }
+                Ok(None) => {
+                    self.step(|| format!("fragment at offset {} buffered or dropped", offset));
+                    return None;
+                }
+                Err(err) => {
+                    self.step(|| format!("fragment at offset {} malformed, aborted", offset));
+                    self.error = Some(err);
+                    return None;
+                }
+            }
+        } else {
+            if fragmented {
+                self.step(|| "fragment skipped without reassembly".to_string());
+                return None;
+            }
+
+            v4.payload().to_vec()
+        };
+
+        let udp = match udp::Packet::new(&data[..]) {
+            Ok(udp) => udp,
+            _ => {
+                self.step(|| "bad udp header, skipped".to_string());
+                return None;
+            }
+        };
+
+        let port = udp.destination();
+
+        if self.ports.contains(&port) {
+            self.step(|| format!("udp dst {} ok", port));
+
+            let udp_length = udp.length() as usize;
+
+            if udp_length < 8 || udp_length > data.len() {
+                self.num_length_mismatches += 1;
+                self.step(|| {
+                    format!(
+                        "udp length {} disagrees with {} B datagram, skipped",
+                        udp_length,
+                        data.len()
+                    )
+                });
+                return None;
+            }
+
+            // the ipv4 length already dropped any padding
+            if udp_length < data.len() {
+                self.num_length_mismatches += 1;
+            }
+
+            if let Some(defragmented) = &mut self.defragmented {
+                let link_header = &packet.data[..packet.data.len() - ether.payload().len()];
+                defragmented
+                    .write(link_header, &header, &data[..udp_length], packet.timestamp)
+                    .unwrap_or_else(|err| panic!("Failed to write defragmented packet. ({})", err));
+            }
+
+            Some((port, data[8..udp_length].to_vec()))
+        } else {
+            self.step(|| format!("udp dst {} not a lidar port, skipped", port));
+            None
+        }
+    }
+}
+
+fn is_lidar_packet(data: &[u8], ports: &[u16]) -> bool {
+    let ether = match ether::Packet::new(data) {
+        Ok(ether) => ether,
+        _ => return false,
+    };
+
+    // the ipv4 header is read by hand since the truncated packet
+    // is shorter than its total length field
+    let v4 = ether.payload();
+    if v4.len() < 20 || v4[0] >> 4 != 4 {
+        return false;
+    }
+
+    let header_len = (v4[0] & 0x0f) as usize * 4;
+    let offset = u16::from_be_bytes([v4[6], v4[7]]) & 0x1fff;
+    let proto = v4[9];
+
+    // only the first fragment carries the udp header
+    if offset != 0 || proto != 17 || v4.len() < header_len + 4 {
+        return false;
+    }
+
+    ports.contains(&u16::from_be_bytes([
+        v4[header_len + 2],
+        v4[header_len + 3],
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ethernet frame of an ipv4 fragment of udp datagram `id`,
+    /// starting at byte `offset`.
+    fn frame(id: u16, offset: usize, mf: bool, data: &[u8]) -> Vec<u8> {
+        let flags = if mf { 0x2000 } else { 0 };
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0]);
+        frame.extend_from_slice(&((20 + data.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&(flags | (offset / 8) as u16).to_be_bytes());
+        frame.extend_from_slice(&[64, 17, 0, 0, 10, 5, 5, 1, 10, 5, 5, 2]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    /// A udp datagram to `port` carrying `payload`.
+    fn datagram(port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = 7502u16.to_be_bytes().to_vec();
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    #[test]
+    fn receives_nothing_once_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false)).cancel_on(cancel.clone());
+
+        let lidar = frame(1, 0, false, &datagram(7502, &[1; 64]));
+        assert_eq!(receiver.parse_packet(&lidar), Some((7502, vec![1; 64])));
+
+        cancel.store(true, Ordering::Relaxed);
+        assert!(receiver.is_cancelled());
+        assert_eq!(receiver.parse_packet(&lidar), None);
+    }
+
+    #[test]
+    fn receives_lidar_ports_only() {
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false));
+
+        let lidar = frame(1, 0, false, &datagram(7502, &[1; 64]));
+        let imu = frame(2, 0, false, &datagram(7503, &[2; 48]));
+
+        assert_eq!(receiver.parse_packet(&lidar), Some((7502, vec![1; 64])));
+        assert_eq!(receiver.parse_packet(&imu), None);
+        assert!(receiver.is_lidar_packet(&lidar[..40]));
+        assert!(!receiver.is_lidar_packet(&imu[..40]));
+    }
+
+    #[test]
+    fn reassembles_fragmented_datagrams() {
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false));
+        let data = datagram(7502, &[1; 1600]);
+
+        assert_eq!(
+            receiver.parse_packet(&frame(1, 0, true, &data[..1480])),
+            None
+        );
+        assert_eq!(
+            receiver.parse_packet(&frame(1, 1480, false, &data[1480..])),
+            Some((7502, vec![1; 1600]))
+        );
+
+        // a datagram left without its last fragment is started only
+        receiver.parse_packet(&frame(3, 0, true, &data[..1480]));
+
+        let counts = receiver.fragment_counts();
+        assert_eq!(
+            (counts.fragments, counts.started, counts.completed),
+            (3, 2, 1)
+        );
+
+        // without reassembly fragments are skipped
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false)).reassemble(false);
+
+        assert_eq!(
+            receiver.parse_packet(&frame(2, 0, true, &data[..1480])),
+            None
+        );
+        assert_eq!(
+            receiver.parse_packet(&frame(2, 1480, false, &data[1480..])),
+            None
+        );
+    }
+
+    #[test]
+    fn stops_at_malformed_fragment_in_strict_mode() {
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(true));
+
+        assert_eq!(receiver.parse_packet(&frame(1, 0, true, &[0; 12])), None);
+        assert!(receiver.error().is_some());
+
+        let lidar = frame(2, 0, false, &datagram(7502, &[1; 64]));
+        assert_eq!(receiver.parse_packet(&lidar), None);
+    }
+
+    #[test]
+    fn trims_ethernet_padding() {
+        let pad = |mut frame: Vec<u8>, len: usize| {
+            frame.resize(len, 0xee);
+            frame
+        };
+        let small = datagram(7502, &[1; 8]);
+        let large = datagram(7502, &[2; 1484]);
+
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false));
+        let unpadded = [
+            receiver.parse_packet(&frame(1, 0, false, &small)),
+            receiver.parse_packet(&frame(2, 0, true, &large[..1480])),
+            receiver.parse_packet(&frame(2, 1480, false, &large[1480..])),
+        ];
+
+        let mut receiver = Receiver::new(vec![7502], IPV4Seq::new(false));
+        let padded = [
+            receiver.parse_packet(&pad(frame(1, 0, false, &small), 60)),
+            receiver.parse_packet(&frame(2, 0, true, &large[..1480])),
+            receiver.parse_packet(&pad(frame(2, 1480, false, &large[1480..]), 60)),
+        ];
+
+        assert_eq!(padded, unpadded);
+        assert_eq!(padded[0], Some((7502, vec![1; 8])));
+        assert_eq!(receiver.num_length_mismatches(), 0);
+
+        // trailing bytes past the minimum frame size aren't padding
+        receiver.parse_packet(&pad(frame(3, 0, false, &small), 64));
+        assert_eq!(receiver.num_length_mismatches(), 1);
+    }
+}
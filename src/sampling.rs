@@ -0,0 +1,127 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use clap::ValueEnum;
+
+/// How a frame over the point cap is subsampled.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Sampling {
+    Random,
+    Farthest,
+}
+
+impl Sampling {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sampling::Random => "random",
+            Sampling::Farthest => "farthest",
+        }
+    }
+}
+
+/// splitmix64, enough for picking points and stable across platforms.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Keep `max_points` of the points, of `stride` values each, in their
+/// original order.
+///
+/// Random sampling draws without replacement; farthest point sampling
+/// starts at a random point and repeatedly adds the point farthest from
+/// all kept ones, covering the frame evenly at O(N * K) cost.
+pub fn sample(
+    points: &[f32],
+    stride: usize,
+    max_points: usize,
+    sampling: Sampling,
+    seed: u64,
+) -> Vec<f32> {
+    let num_points = points.len() / stride;
+    if num_points <= max_points {
+        return points.to_vec();
+    }
+
+    let mut rng = Rng(seed);
+
+    let mut indices = match sampling {
+        Sampling::Random => {
+            // partial Fisher-Yates shuffle
+            let mut indices: Vec<usize> = (0..num_points).collect();
+            for i in 0..max_points {
+                let j = i + rng.below(num_points - i);
+                indices.swap(i, j);
+            }
+            indices.truncate(max_points);
+            indices
+        }
+        Sampling::Farthest => farthest_points(points, stride, max_points, rng.below(num_points)),
+    };
+
+    indices.sort_unstable();
+
+    indices
+        .iter()
+        .flat_map(|&i| &points[i * stride..(i + 1) * stride])
+        .copied()
+        .collect()
+}
+
+fn farthest_points(points: &[f32], stride: usize, max_points: usize, first: usize) -> Vec<usize> {
+    let position = |i: usize| &points[i * stride..i * stride + 3];
+
+    let mut distances = vec![f32::MAX; points.len() / stride];
+    let mut indices = Vec::with_capacity(max_points);
+    let mut next = first;
+
+    while indices.len() < max_points {
+        indices.push(next);
+        let kept = position(next);
+
+        // kept points are marked negative, so duplicates of them at
+        // distance 0 can still be picked
+        distances[next] = -1.0;
+        let mut farthest = (-1.0, next);
+        for (i, distance) in distances.iter_mut().enumerate() {
+            let point = position(i);
+            let d = (0..3).map(|k| (point[k] - kept[k]).powi(2)).sum::<f32>();
+            *distance = distance.min(d);
+
+            if *distance > farthest.0 {
+                farthest = (*distance, i);
+            }
+        }
+
+        next = farthest.1;
+    }
+
+    indices
+}
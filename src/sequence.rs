@@ -18,7 +18,7 @@
  */
 
 use core::net::Ipv4Addr;
-use std::{usize, vec::Vec};
+use std::{fmt, usize, vec::Vec};
 
 use hashbrown::HashMap;
 use packet::{ip, Packet};
@@ -42,6 +42,10 @@ struct IPV4Chunk {
     data: [u8; PACKET_MAX_SIZE],
     holes: Vec<IPV4Hole>,
     len: u16,
+    /// Whether the last fragment has arrived and `len` is known
+    has_last: bool,
+    /// End of the furthest reaching fragment received
+    max_end: u16,
 }
 
 impl IPV4Chunk {
@@ -58,22 +62,103 @@ impl IPV4Chunk {
             data,
             holes,
             len: PACKET_MAX_SIZE as u16,
+            has_last: false,
+            max_end: 0,
         }
     }
 }
 
+/// What is wrong with a malformed fragment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FragmentErrorKind {
+    /// Followed by more fragments but not a multiple of 8 bytes long
+    Misaligned,
+    /// Reaching past the largest possible datagram
+    Oversized,
+    /// Disagreeing with the other fragments on where the datagram ends
+    InconsistentEnd,
+    /// Overlapping data received before
+    Overlap,
+}
+
+/// A malformed fragment, returned in strict mode instead of being dropped.
+#[derive(Debug)]
+pub struct FragmentError {
+    pub kind: FragmentErrorKind,
+    pub id: u16,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    /// Byte offset of the fragment in its datagram
+    pub offset: u32,
+    pub length: u16,
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FragmentErrorKind::Misaligned => "misaligned",
+            FragmentErrorKind::Oversized => "oversized",
+            FragmentErrorKind::InconsistentEnd => "inconsistently ending",
+            FragmentErrorKind::Overlap => "overlapping",
+        };
+
+        write!(
+            f,
+            "{} ipv4 fragment, id {}, {} -> {}, offset {}, length {}",
+            kind, self.id, self.source, self.destination, self.offset, self.length
+        )
+    }
+}
+
+impl std::error::Error for FragmentError {}
+
 pub struct IPV4Seq {
     buffer: HashMap<IPV4Key, IPV4Chunk>,
+    strict: bool,
+    /// Fragments reaching past the largest possible datagram
+    num_rejected: usize,
+    recover_partial: bool,
+    /// Filled prefixes of datagrams given up on, oldest first
+    partials: Vec<Vec<u8>>,
 }
 
 impl IPV4Seq {
-    pub fn new() -> Self {
+    /// In strict mode a malformed fragment is returned as an error
+    /// instead of being dropped.
+    pub fn new(strict: bool) -> Self {
         let buffer = HashMap::new();
 
-        Self { buffer }
+        Self {
+            buffer,
+            strict,
+            num_rejected: 0,
+            recover_partial: false,
+            partials: Vec::new(),
+        }
+    }
+
+    /// Give up on the incomplete datagrams of a flow once a later one of
+    /// it completes, keeping what was received from their start.
+    pub fn recover_partial(mut self, recover_partial: bool) -> Self {
+        self.recover_partial = recover_partial;
+        self
+    }
+
+    /// Prefixes of the datagrams given up on since the last call, from
+    /// offset 0 up to their first hole.
+    pub fn take_partials(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.partials)
+    }
+
+    pub fn num_rejected(&self) -> usize {
+        self.num_rejected
     }
 
-    pub fn put_and_get(&mut self, pkt: ip::v4::Packet<&[u8]>) -> Option<Vec<u8>> {
+    /// Add a fragment, returning the datagram it completes, if any.
+    pub fn put_and_get(
+        &mut self,
+        pkt: ip::v4::Packet<&[u8]>,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
         let offset = pkt.offset();
         let length = pkt.payload().len() as u16;
         let flags = pkt.flags();
@@ -82,20 +167,52 @@ impl IPV4Seq {
         let payload = pkt.payload();
 
         if df {
-            return Some(pkt.payload().to_vec());
+            return Ok(Some(pkt.payload().to_vec()));
         }
 
+        // in u32, as neither the offset nor the end of a fragment may
+        // wrap around; the datagram must fit a packet with its header
+        let data_first = offset as u32 * 8;
+        let data_end = data_first + pkt.payload().len() as u32;
+        let max_len = PACKET_MAX_SIZE as u32 - pkt.header() as u32 * 4;
+
+        let strict = self.strict;
+        let reject = |kind| {
+            if strict {
+                Err(FragmentError {
+                    kind,
+                    id: pkt.id(),
+                    source: pkt.source(),
+                    destination: pkt.destination(),
+                    offset: data_first,
+                    length,
+                })
+            } else {
+                Ok(None)
+            }
+        };
+
         if mf && (length % 8) != 0 {
-            return None;
+            return reject(FragmentErrorKind::Misaligned);
         }
 
-        let data_first = offset * 8;
-        let data_last = data_first + length;
+        if offset > 0x1fff || data_end > max_len {
+            self.num_rejected += 1;
 
-        if data_last < data_first {
-            return None;
+            eprintln!(
+                "Drop oversized ipv4 fragment. (id {}, {} -> {}, offset {}, length {})",
+                pkt.id(),
+                pkt.source(),
+                pkt.destination(),
+                data_first,
+                length
+            );
+
+            return reject(FragmentErrorKind::Oversized);
         }
 
+        let (data_first, data_last) = (data_first as u16, data_end as u16);
+
         let key = IPV4Key {
             source: pkt.source(),
             dest: pkt.destination(),
@@ -113,15 +230,31 @@ impl IPV4Seq {
             let mut append_list = Vec::new();
             let mut remove_index = usize::MAX;
 
+            // fragments disagreeing on the end of the datagram can't be
+            // put together without truncating or over-reading it
+            let inconsistent = if mf {
+                chunk.has_last && data_last > chunk.len
+            } else {
+                (chunk.has_last && data_last != chunk.len) || data_last < chunk.max_end
+            };
+
+            if inconsistent {
+                self.buffer.remove(&key);
+                return reject(FragmentErrorKind::InconsistentEnd);
+            }
+
             if !mf {
                 chunk.len = data_last;
+                chunk.has_last = true;
             }
 
+            chunk.max_end = chunk.max_end.max(data_last);
+
             for (index, hole) in chunk.holes.iter().enumerate() {
                 if data_first < hole.last && data_last > hole.first {
                     if data_first < hole.first || data_last > hole.last {
                         self.buffer.clear();
-                        return None;
+                        return reject(FragmentErrorKind::Overlap);
                     }
 
                     if data_first > hole.first {
@@ -155,6 +288,16 @@ impl IPV4Seq {
                 chunk.holes.append(&mut append_list);
             }
 
+            // the last fragment may arrive before earlier ones, which left
+            // holes open up to the maximum size
+            if chunk.has_last {
+                let len = chunk.len;
+                chunk.holes.retain(|hole| hole.first < len);
+                for hole in chunk.holes.iter_mut() {
+                    hole.last = hole.last.min(len);
+                }
+            }
+
             chunk.data[data_first as usize..][..payload.len()].copy_from_slice(payload);
         }
 
@@ -176,10 +319,186 @@ impl IPV4Seq {
         }
 
         if vec_data.is_empty() {
-            None
+            Ok(None)
         } else {
             self.buffer.remove(&remove_key);
-            Some(vec_data)
+
+            if self.recover_partial {
+                self.evict_preceding(&remove_key);
+            }
+
+            Ok(Some(vec_data))
         }
     }
+
+    /// Evict the chains of the flow of `done` with earlier ids, as a
+    /// sensor sends its datagrams in order and their fragments are lost.
+    fn evict_preceding(&mut self, done: &IPV4Key) {
+        // ids wrap around, so earlier means up to half the id space back
+        let age = |key: &IPV4Key| done.id.wrapping_sub(key.id);
+
+        let mut stale: Vec<IPV4Key> = self
+            .buffer
+            .keys()
+            .filter(|key| {
+                key.source == done.source
+                    && key.dest == done.dest
+                    && key.proto == done.proto
+                    && age(key) != 0
+                    && age(key) < 0x8000
+            })
+            .copied()
+            .collect();
+        stale.sort_by_key(|key| std::cmp::Reverse(age(key)));
+
+        for key in stale {
+            let chunk = self.buffer.remove(&key).unwrap();
+            let prefix_len = chunk
+                .holes
+                .iter()
+                .map(|hole| hole.first)
+                .min()
+                .unwrap_or(chunk.len);
+
+            if prefix_len > 0 {
+                self.partials
+                    .push(chunk.data[..prefix_len as usize].to_vec());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ipv4 udp fragment of datagram `id` starting at byte `offset`.
+    fn fragment(id: u16, offset: usize, mf: bool, payload: &[u8]) -> Vec<u8> {
+        let flags = if mf { 0x2000 } else { 0 };
+        let mut data = vec![0x45, 0];
+        data.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(&(flags | (offset / 8) as u16).to_be_bytes());
+        data.extend_from_slice(&[64, 17, 0, 0, 10, 5, 5, 1, 10, 5, 5, 2]);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn put(seq: &mut IPV4Seq, data: &[u8]) -> Option<Vec<u8>> {
+        seq.put_and_get(ip::v4::Packet::new(data).unwrap()).unwrap()
+    }
+
+    fn put_strict(seq: &mut IPV4Seq, data: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        seq.put_and_get(ip::v4::Packet::new(data).unwrap())
+    }
+
+    #[test]
+    fn rejects_fragment_at_maximum_offset() {
+        let mut seq = IPV4Seq::new(false);
+
+        assert_eq!(
+            put(&mut seq, &fragment(1, 0x1fff * 8, false, &[1; 8])),
+            None
+        );
+        assert_eq!(seq.num_rejected(), 1);
+    }
+
+    #[test]
+    fn rejects_fragment_ending_past_largest_datagram() {
+        let mut seq = IPV4Seq::new(false);
+
+        assert_eq!(put(&mut seq, &fragment(1, 64800, false, &[1; 1480])), None);
+        assert_eq!(seq.num_rejected(), 1);
+
+        // ending exactly at the largest datagram is fine
+        assert_eq!(put(&mut seq, &fragment(2, 65512, false, &[1; 3])), None);
+        assert_eq!(seq.num_rejected(), 1);
+    }
+
+    #[test]
+    fn rejects_overflowing_fragment_in_strict_mode() {
+        let mut seq = IPV4Seq::new(true);
+
+        // offset * 8 + length is past 65535, which wrapped in u16
+        let err = put_strict(&mut seq, &fragment(7, 0x1fff * 8, false, &[1; 100])).unwrap_err();
+        assert_eq!(err.kind, FragmentErrorKind::Oversized);
+        assert_eq!(err.id, 7);
+        assert_eq!(err.offset, 0x1fff * 8);
+
+        // nothing was written into a chunk, so a valid datagram still passes
+        put_strict(&mut seq, &fragment(8, 0, true, &[2; 8])).unwrap();
+        let data = put_strict(&mut seq, &fragment(8, 8, false, &[3; 4])).unwrap();
+        assert_eq!(data, Some([vec![2; 8], vec![3; 4]].concat()));
+    }
+
+    #[test]
+    fn rejects_every_malformed_fragment_in_strict_mode() {
+        let kind = |fragments: &[Vec<u8>]| {
+            let mut seq = IPV4Seq::new(true);
+            fragments
+                .iter()
+                .find_map(|data| put_strict(&mut seq, data).err())
+                .map(|err| err.kind)
+        };
+
+        assert_eq!(
+            kind(&[fragment(1, 0, true, &[0; 12])]),
+            Some(FragmentErrorKind::Misaligned)
+        );
+        assert_eq!(
+            kind(&[
+                fragment(1, 16, false, &[0; 8]),
+                fragment(1, 16, false, &[0; 16])
+            ]),
+            Some(FragmentErrorKind::InconsistentEnd)
+        );
+        assert_eq!(
+            kind(&[
+                fragment(1, 0, true, &[0; 16]),
+                fragment(1, 8, true, &[0; 16])
+            ]),
+            Some(FragmentErrorKind::Overlap)
+        );
+    }
+
+    #[test]
+    fn reassembles_last_fragment_first() {
+        let mut seq = IPV4Seq::new(false);
+
+        assert_eq!(put(&mut seq, &fragment(1, 16, false, &[3; 4])), None);
+        assert_eq!(put(&mut seq, &fragment(1, 8, true, &[2; 8])), None);
+        assert_eq!(
+            put(&mut seq, &fragment(1, 0, true, &[1; 8])),
+            Some([vec![1; 8], vec![2; 8], vec![3; 4]].concat())
+        );
+    }
+
+    #[test]
+    fn reassembles_middle_fragment_last() {
+        let mut seq = IPV4Seq::new(false);
+
+        assert_eq!(put(&mut seq, &fragment(1, 0, true, &[1; 8])), None);
+        assert_eq!(put(&mut seq, &fragment(1, 16, false, &[3; 4])), None);
+        assert_eq!(
+            put(&mut seq, &fragment(1, 8, true, &[2; 8])),
+            Some([vec![1; 8], vec![2; 8], vec![3; 4]].concat())
+        );
+    }
+
+    #[test]
+    fn drops_last_fragment_ending_before_received_data() {
+        let mut seq = IPV4Seq::new(false);
+
+        // the final fragment would cut the datagram to 16 of 1480 bytes
+        assert_eq!(put(&mut seq, &fragment(1, 0, true, &[1; 1480])), None);
+        assert_eq!(put(&mut seq, &fragment(1, 8, false, &[2; 8])), None);
+
+        // and the datagram is gone, not completed by later fragments
+        assert_eq!(put(&mut seq, &fragment(1, 1480, false, &[3; 8])), None);
+
+        let mut seq = IPV4Seq::new(true);
+        put_strict(&mut seq, &fragment(1, 0, true, &[1; 1480])).unwrap();
+        let err = put_strict(&mut seq, &fragment(1, 8, false, &[2; 8])).unwrap_err();
+        assert_eq!(err.kind, FragmentErrorKind::InconsistentEnd);
+    }
 }
@@ -0,0 +1,66 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+use std::{fs, io, path::Path};
+
+use serde::Serialize;
+
+use crate::calibration::Calibration;
+
+/// Bumped whenever a field changes meaning.
+const SCHEMA_VERSION: u32 = 1;
+
+/// What produced the files of one output directory, written there as
+/// `session.json` at the end of every run so they can be reproduced.
+#[derive(Serialize)]
+pub struct Session<'a> {
+    version: u32,
+    /// Command line of the run, program included
+    args: Vec<String>,
+    /// Lidar port of the stream, 0 for raw payloads
+    port: u16,
+    /// Beam angle overrides applied, with their values
+    calibration: &'a [&'a Calibration],
+    frames: usize,
+    /// Interrupted before the end of its input
+    cancelled: bool,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(
+        port: u16,
+        calibration: &'a [&'a Calibration],
+        frames: usize,
+        cancelled: bool,
+    ) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            args: std::env::args().collect(),
+            port,
+            calibration,
+            frames,
+            cancelled,
+        }
+    }
+
+    /// Write `session.json` into `output_path`, replacing any earlier one.
+    pub fn save(&self, output_path: &Path) -> io::Result<()> {
+        let path = output_path.join("session.json");
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+}
@@ -0,0 +1,644 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+//! Output side of the parser.
+//!
+//! Every completed frame is handed to each configured `FrameSink` on the
+//! writer thread. The first error a sink returns stops the writer, and
+//! `Legacy::finish` returns it. A custom format only needs to implement
+//! the trait:
+//!
+//! ```
+//! use std::io;
+//!
+//! use ouster_parser::{Frame, FrameSink, Legacy};
+//!
+//! struct PointCounter {
+//!     total: usize,
+//! }
+//!
+//! impl FrameSink for PointCounter {
+//!     fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+//!         self.total += frame.num_points();
+//!         Ok(())
+//!     }
+//!
+//!     fn finish(&mut self) -> io::Result<()> {
+//!         println!("{} points", self.total);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let builder = Legacy::builder().sink(Box::new(PointCounter { total: 0 }));
+//! # drop(builder);
+//! ```
+
+use std::{
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use hashbrown::HashMap;
+use serde::Serialize;
+
+use crate::{ouster::CoordinateFrame, sampling::Sampling, trajectory::Trajectory};
+
+/// A completed point cloud, stored as interleaved `x y z intensity` values,
+/// followed by a packed `rgb` value per point if `rgb` is set.
+pub struct Frame {
+    pub id: usize,
+    pub frame_id: u16,
+    /// Earliest column timestamp of the frame
+    pub timestamp: u64,
+    /// Latest column timestamp of the frame
+    pub end_timestamp: u64,
+    pub coordinate_frame: CoordinateFrame,
+    /// Output name relative to the sink's root, without extension
+    pub name: PathBuf,
+    pub rgb: bool,
+    /// Whether points carry their beam's elevation and encoder azimuth
+    pub beam_angles: bool,
+    /// Whether points carry the distance from their first to second return
+    pub range_difference: bool,
+    /// Share of the expected points received in valid columns
+    pub completeness: f32,
+    /// Number of the continuous stretch of frame timestamps the frame is in
+    pub segment: usize,
+    /// Only every this many points were kept, 1 for full resolution
+    pub point_stride: usize,
+    /// Method and seed the frame was subsampled with to the point cap
+    pub sampling: Option<(Sampling, u64)>,
+    /// Sensor timestamp of each column by measure_id, 0 if not received,
+    /// or empty if not kept
+    pub column_timestamps: Vec<u64>,
+    /// Returns dropped by the validity criteria for their range or
+    /// reflectivity
+    pub excluded_range: usize,
+    pub excluded_reflectivity: usize,
+    /// Why the frame was dropped, if this is an empty placeholder
+    pub discarded: Option<String>,
+    pub points: Vec<f32>,
+}
+
+impl Frame {
+    pub fn stride(&self) -> usize {
+        4 + self.rgb as usize + 2 * self.beam_angles as usize + self.range_difference as usize
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.points.len() / self.stride()
+    }
+}
+
+pub trait FrameSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// How PCD files are written: a write syscall after another, or
+/// submitted through io_uring with the `uring` feature on Linux.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum IoBackend {
+    Std,
+    Uring,
+}
+
+/// Writes one binary PCD file per frame.
+///
+/// Each file appears atomically under its final name once complete.
+///
+/// With several shards, frames are spread round-robin by id over
+/// `shard0/`, `shard1/`, ... subdirectories.
+pub struct PcdSink {
+    output_path: PathBuf,
+    shards: usize,
+    format: PcdFormat,
+}
+
+impl PcdSink {
+    pub fn new(output_path: &Path, shards: usize, format: PcdFormat) -> Self {
+        Self {
+            output_path: output_path.to_path_buf(),
+            shards,
+            format,
+        }
+    }
+}
+
+/// Path of the PCD file of a frame relative to the output directory.
+pub fn pcd_path(frame: &Frame, shards: usize) -> PathBuf {
+    let path = if shards > 1 {
+        Path::new(&format!("shard{}", frame.id % shards)).join(&frame.name)
+    } else {
+        frame.name.clone()
+    };
+
+    // appended, as names from a template may contain dots themselves
+    let mut path = path.into_os_string();
+    path.push(".pcd");
+    PathBuf::from(path)
+}
+
+impl FrameSink for PcdSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let file_path = self.output_path.join(pcd_path(frame, self.shards));
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // written aside and renamed into place, so a process watching the
+        // output directory never picks up a partial file
+        let tmp_path = file_path.with_extension("pcd.tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        write_pcd(&mut file, frame, &self.format)?;
+        drop(file);
+
+        fs::rename(tmp_path, file_path)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the column timestamps of each frame next to its PCD file, as
+/// little-endian u64 nanoseconds indexed by measure_id with 0 for columns
+/// that weren't received.
+pub struct ColumnTimestampSink {
+    output_path: PathBuf,
+    shards: usize,
+}
+
+impl ColumnTimestampSink {
+    pub fn new(output_path: &Path, shards: usize) -> Self {
+        Self {
+            output_path: output_path.to_path_buf(),
+            shards,
+        }
+    }
+}
+
+impl FrameSink for ColumnTimestampSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.discarded.is_some() {
+            return Ok(());
+        }
+
+        let file_path = self
+            .output_path
+            .join(pcd_path(frame, self.shards))
+            .with_extension("ts");
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = BufWriter::new(File::create(&file_path)?);
+        for timestamp in &frame.column_timestamps {
+            file.write_all(&timestamp.to_le_bytes())?;
+        }
+
+        file.flush()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Longest comment line written into a PCD header, in characters.
+const MAX_COMMENT_LEN: usize = 120;
+
+/// Header layout shared by the PCD writers.
+#[derive(Clone)]
+pub struct PcdFormat {
+    /// Name of the intensity field, as some tools expect `i` or `reflectivity`
+    pub intensity_name: String,
+    /// Whether to write `#` comment lines at all
+    pub comments: bool,
+    /// Extra comment lines for every file, without the leading `#`; when
+    /// given, the frame_id and fill ratio of each frame are added as well
+    pub metadata: Vec<String>,
+    /// Whether to write `DATA ascii` rows of text instead of a binary body
+    pub ascii: bool,
+}
+
+/// Writes a frame as one binary or ascii PCD file into `writer`.
+pub(crate) fn write_pcd<W: Write>(
+    writer: &mut W,
+    frame: &Frame,
+    format: &PcdFormat,
+) -> io::Result<()> {
+    let intensity_name = &format.intensity_name;
+
+    let mut fields = format!("x y z {}", intensity_name);
+    if frame.rgb {
+        fields.push_str(" rgb");
+    }
+    if frame.beam_angles {
+        fields.push_str(" elevation azimuth");
+    }
+    if frame.range_difference {
+        fields.push_str(" range_difference");
+    }
+
+    // every field is a single 4-byte float
+    let stride = frame.stride();
+    let size = vec!["4"; stride].join(" ");
+    let kind = vec!["F"; stride].join(" ");
+    let count = vec!["1"; stride].join(" ");
+
+    let mut comments = Vec::new();
+
+    if format.comments {
+        comments.push(".PCD v.7 - Point Cloud Data file format".to_string());
+        comments.push(format!("timestamp: {}", frame.timestamp));
+
+        if let Some(reason) = &frame.discarded {
+            comments.push(format!("discarded: {}", reason));
+        }
+
+        if frame.point_stride > 1 {
+            comments.push(format!("point_stride: {}", frame.point_stride));
+        }
+
+        if let Some((sampling, seed)) = frame.sampling {
+            comments.push(format!("sampling: {} seed {}", sampling.name(), seed));
+        }
+
+        if !format.metadata.is_empty() {
+            comments.extend(format.metadata.iter().cloned());
+            comments.push(format!("frame_id: {}", frame.frame_id));
+            comments.push(format!("completeness: {:.4}", frame.completeness));
+        }
+    }
+
+    // bounded to one line each, so a long value can't break the header
+    let comments: String = comments
+        .iter()
+        .map(|comment| {
+            let line: String = comment
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .take(MAX_COMMENT_LEN)
+                .collect();
+            format!("# {}\n", line)
+        })
+        .collect();
+
+    let pcd_header = format!(
+        "{}\
+         VERSION .7\n\
+         FIELDS {}\n\
+         SIZE {}\n\
+         TYPE {}\n\
+         COUNT {}\n\
+         WIDTH {}\n\
+         HEIGHT 1\n\
+         VIEWPOINT 0 0 0 1 0 0 0\n\
+         POINTS {}\n\
+         DATA {}\n",
+        comments,
+        fields,
+        size,
+        kind,
+        count,
+        frame.num_points(),
+        frame.num_points(),
+        if format.ascii { "ascii" } else { "binary" }
+    );
+
+    writer.write_all(pcd_header.as_bytes())?;
+
+    if format.ascii {
+        return write_ascii_points(writer, frame);
+    }
+
+    //// safe but slow
+    // let buffer: Vec<u8> = frame
+    //     .points
+    //     .iter()
+    //     .flat_map(|x| x.to_le_bytes().to_vec())
+    //     .collect();
+
+    // unsafe little endian in x86
+    let buffer = unsafe {
+        std::slice::from_raw_parts(
+            frame.points.as_ptr() as *const u8,
+            frame.points.len() * std::mem::size_of::<f32>(),
+        )
+    };
+
+    writer.write_all(buffer)
+}
+
+/// Writes one row of space-separated fields per point, with 6 decimals.
+fn write_ascii_points<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    use std::fmt::Write as _;
+
+    let mut text = String::new();
+
+    for point in frame.points.chunks_exact(frame.stride()) {
+        for (index, value) in point.iter().enumerate() {
+            if index > 0 {
+                text.push(' ');
+            }
+
+            // rgb is packed into the bits of a float, which fixed
+            // decimals would round to zero
+            if frame.rgb && index == 4 {
+                write!(text, "{:e}", value).unwrap();
+            } else {
+                write!(text, "{:.6}", value).unwrap();
+            }
+        }
+        text.push('\n');
+    }
+
+    writer.write_all(text.as_bytes())
+}
+
+/// Writes every frame as a PCD file into one stream, one after another.
+///
+/// Useful to get the output without touching the disk, e.g. into a
+/// `SharedBuffer` which can be read back once the parser has finished.
+pub struct PcdStreamSink<W: Write> {
+    writer: W,
+    format: PcdFormat,
+}
+
+impl<W: Write> PcdStreamSink<W> {
+    pub fn new(writer: W, format: PcdFormat) -> Self {
+        Self { writer, format }
+    }
+}
+
+impl<W: Write> FrameSink for PcdStreamSink<W> {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        write_pcd(&mut self.writer, frame, &self.format)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// In-memory `Write` target shared between a sink and its owner.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Write` target keeping only a BLAKE3 hash of the bytes written, shared
+/// between a sink and its owner.
+#[derive(Clone, Default)]
+pub struct SharedHash(Arc<Mutex<blake3::Hasher>>);
+
+impl SharedHash {
+    pub fn value(&self) -> blake3::Hash {
+        self.0.lock().unwrap().finalize()
+    }
+}
+
+impl Write for SharedHash {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Voxel {
+    x: f32,
+    y: f32,
+    z: f32,
+    intensity: f32,
+    hits: u32,
+}
+
+/// Merges every frame into one voxelized scene cloud written at the end.
+///
+/// Points falling into the same voxel are averaged and their hits counted.
+/// With a trajectory, frames are first moved into the map frame by their
+/// pose, and frames without a pose are left out.
+pub struct SceneSink {
+    path: PathBuf,
+    coordinate_frame: CoordinateFrame,
+    voxel_size: f32,
+    intensity_name: String,
+    voxels: HashMap<(i32, i32, i32), Voxel>,
+    trajectory: Option<Trajectory>,
+    /// Frames left out for lack of a pose
+    unposed: usize,
+}
+
+impl SceneSink {
+    pub fn new(
+        path: &Path,
+        coordinate_frame: CoordinateFrame,
+        voxel_size: f32,
+        intensity_name: &str,
+    ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            coordinate_frame,
+            voxel_size,
+            intensity_name: intensity_name.to_string(),
+            voxels: HashMap::new(),
+            trajectory: None,
+            unposed: 0,
+        }
+    }
+
+    /// Register frames by the sensor poses of a trajectory.
+    pub fn trajectory(mut self, trajectory: Trajectory) -> Self {
+        self.trajectory = Some(trajectory);
+        self
+    }
+}
+
+impl FrameSink for SceneSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        if frame.coordinate_frame != self.coordinate_frame || frame.points.is_empty() {
+            return Ok(());
+        }
+
+        let transform = match &self.trajectory {
+            Some(trajectory) => {
+                match trajectory.transformer(frame.id, frame.timestamp as f64 * 1e-9) {
+                    Some(transform) => Some(transform),
+                    None => {
+                        self.unposed += 1;
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+
+        for point in frame.points.chunks_exact(frame.stride()) {
+            let [x, y, z] = match &transform {
+                Some(transform) => transform(point),
+                None => [point[0], point[1], point[2]],
+            };
+
+            let key = (
+                (x / self.voxel_size).floor() as i32,
+                (y / self.voxel_size).floor() as i32,
+                (z / self.voxel_size).floor() as i32,
+            );
+
+            let voxel = self.voxels.entry(key).or_default();
+            voxel.x += x;
+            voxel.y += y;
+            voxel.z += z;
+            voxel.intensity += point[3];
+            voxel.hits += 1;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.unposed > 0 {
+            eprintln!(
+                "Leave frames without a pose out of the map. ({} frames, {})",
+                self.unposed,
+                self.path.display()
+            );
+        }
+
+        let pcd_header = format!(
+            "# .PCD v.7 - Point Cloud Data file format\n\
+             VERSION .7\n\
+             FIELDS x y z {} hits\n\
+             SIZE 4 4 4 4 4\n\
+             TYPE F F F F U\n\
+             COUNT 1 1 1 1 1\n\
+             WIDTH {}\n\
+             HEIGHT 1\n\
+             VIEWPOINT 0 0 0 1 0 0 0\n\
+             POINTS {}\n\
+             DATA binary\n",
+            self.intensity_name,
+            self.voxels.len(),
+            self.voxels.len()
+        );
+
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        file.write_all(pcd_header.as_bytes())?;
+
+        for voxel in self.voxels.values() {
+            let hits = voxel.hits as f32;
+            file.write_all(&(voxel.x / hits).to_le_bytes())?;
+            file.write_all(&(voxel.y / hits).to_le_bytes())?;
+            file.write_all(&(voxel.z / hits).to_le_bytes())?;
+            file.write_all(&(voxel.intensity / hits).to_le_bytes())?;
+            file.write_all(&voxel.hits.to_le_bytes())?;
+        }
+
+        file.flush()
+    }
+}
+
+#[derive(Serialize)]
+struct IndexEntry {
+    start: u64,
+    end: u64,
+    completeness: f32,
+    segment: usize,
+    point_stride: usize,
+    excluded_range: usize,
+    excluded_reflectivity: usize,
+    path: PathBuf,
+}
+
+/// Writes a json index of the PCD files sorted by timestamp at the end.
+///
+/// Entries hold the `start`/`end` column timestamps of each file, its
+/// completeness, its timestamp segment, its point stride, the returns it
+/// lost to the validity criteria and its path relative to the output
+/// directory, so a reader can binary search
+/// `start` to find the frame covering a given time.
+pub struct TimeIndexSink {
+    path: PathBuf,
+    shards: usize,
+    entries: Vec<IndexEntry>,
+}
+
+impl TimeIndexSink {
+    pub fn new(path: &Path, shards: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            shards,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl FrameSink for TimeIndexSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.entries.push(IndexEntry {
+            start: frame.timestamp,
+            end: frame.end_timestamp,
+            completeness: frame.completeness,
+            segment: frame.segment,
+            point_stride: frame.point_stride,
+            excluded_range: frame.excluded_range,
+            excluded_reflectivity: frame.excluded_reflectivity,
+            path: pcd_path(frame, self.shards),
+        });
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.entries
+            .sort_by(|a, b| (a.start, &a.path).cmp(&(b.start, &b.path)));
+
+        let file = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer_pretty(file, &self.entries)?;
+
+        Ok(())
+    }
+}
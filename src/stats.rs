@@ -0,0 +1,240 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::verify::{parse_pcd_header, pcd_files};
+
+/// Intensity histogram bins over the normalized range 0 to 1
+const INTENSITY_BINS: usize = 10;
+
+/// Intervals longer than this many median frame intervals count as gaps
+const GAP_FACTOR: f64 = 1.5;
+
+/// Points and received columns of one frame, which may span several files
+/// when it was split by intensity or into parts.
+#[derive(Default)]
+struct FrameStats {
+    points: usize,
+    /// Received and total columns, from the `.ts` sidecar if there's one
+    columns: Option<(usize, usize)>,
+}
+
+#[derive(Default)]
+struct Totals {
+    num_files: usize,
+    num_placeholders: usize,
+    num_broken: usize,
+    /// Frames by timestamp
+    frames: BTreeMap<u64, FrameStats>,
+    /// Frames of files without a timestamp comment
+    untimed: Vec<FrameStats>,
+    bounds: Option<([f32; 3], [f32; 3])>,
+    histogram: [usize; INTENSITY_BINS],
+}
+
+/// Print aggregate statistics of the PCD files this tool wrote under
+/// `path`, returning whether there were frames and none was broken.
+pub fn run(path: &Path) -> bool {
+    let mut totals = Totals::default();
+
+    for file in pcd_files(path) {
+        totals.num_files += 1;
+
+        if let Err(err) = totals.add_file(&file) {
+            totals.num_broken += 1;
+            eprintln!("Broken output file. ({}: {})", file.display(), err);
+        }
+    }
+
+    totals.print();
+
+    totals.num_broken == 0 && totals.num_frames() > 0
+}
+
+impl Totals {
+    fn add_file(&mut self, path: &Path) -> Result<(), String> {
+        let data = fs::read(path).map_err(|err| err.to_string())?;
+        let header = parse_pcd_header(&data)?;
+
+        if header.discarded {
+            self.num_placeholders += 1;
+            return Ok(());
+        }
+
+        // x y z intensity, then optional fields, all 4-byte floats
+        if header.fields.len() < 4
+            || header.fields[..3] != ["x", "y", "z"]
+            || header.sizes.iter().any(|&size| size != 4)
+            || header.counts.iter().any(|&count| count != 1)
+        {
+            return Err("fields are not x y z intensity floats".to_string());
+        }
+
+        let point_size = header.point_size();
+        let body = &data[header.body_offset..];
+
+        let values = if header.ascii {
+            header.ascii_values(body)?
+        } else {
+            if body.len() != header.num_points * point_size {
+                return Err(format!(
+                    "body is {} bytes, {} points of {} bytes expected",
+                    body.len(),
+                    header.num_points,
+                    point_size
+                ));
+            }
+
+            body.chunks_exact(4)
+                .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+                .collect()
+        };
+
+        for point in values.chunks_exact(header.fields.len()) {
+            let xyz = [point[0], point[1], point[2]];
+
+            if xyz.iter().all(|v| v.is_finite()) {
+                let (min, max) = self.bounds.get_or_insert((xyz, xyz));
+                for i in 0..3 {
+                    min[i] = min[i].min(xyz[i]);
+                    max[i] = max[i].max(xyz[i]);
+                }
+            }
+
+            let bin = (point[3].clamp(0.0, 1.0) * INTENSITY_BINS as f32) as usize;
+            self.histogram[bin.min(INTENSITY_BINS - 1)] += 1;
+        }
+
+        let frame = match header.timestamp {
+            Some(timestamp) => self.frames.entry(timestamp).or_default(),
+            None => {
+                self.untimed.push(FrameStats::default());
+                self.untimed.last_mut().unwrap()
+            }
+        };
+
+        frame.points += header.num_points;
+
+        // split files of a frame share their column timestamps
+        if frame.columns.is_none() {
+            if let Ok(timestamps) = fs::read(path.with_extension("ts")) {
+                let timestamps: Vec<&[u8]> = timestamps.chunks_exact(8).collect();
+                let received = timestamps
+                    .iter()
+                    .filter(|timestamp| timestamp.iter().any(|&byte| byte != 0))
+                    .count();
+                frame.columns = Some((received, timestamps.len()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn num_frames(&self) -> usize {
+        self.frames.len() + self.untimed.len()
+    }
+
+    fn print(&self) {
+        println!(
+            "Files: {} ({} placeholders, {} broken)",
+            self.num_files, self.num_placeholders, self.num_broken
+        );
+        println!("Frames: {}", self.num_frames());
+
+        let mut points: Vec<usize> = self
+            .frames
+            .values()
+            .chain(&self.untimed)
+            .map(|frame| frame.points)
+            .collect();
+        points.sort_unstable();
+
+        if !points.is_empty() {
+            let total: usize = points.iter().sum();
+            println!(
+                "Points: {} (per frame min {}, median {}, mean {:.0}, max {})",
+                total,
+                points[0],
+                points[points.len() / 2],
+                total as f64 / points.len() as f64,
+                points[points.len() - 1]
+            );
+        }
+
+        if let Some((min, max)) = self.bounds {
+            println!(
+                "Bounding box: [{:.3}, {:.3}, {:.3}] to [{:.3}, {:.3}, {:.3}] m",
+                min[0], min[1], min[2], max[0], max[1], max[2]
+            );
+        }
+
+        let num_points: usize = self.histogram.iter().sum();
+        if num_points > 0 {
+            println!("Intensity histogram:");
+            for (i, count) in self.histogram.iter().enumerate() {
+                println!(
+                    "  {:.1}-{:.1}: {} ({:.2}%)",
+                    i as f32 / INTENSITY_BINS as f32,
+                    (i + 1) as f32 / INTENSITY_BINS as f32,
+                    count,
+                    *count as f64 / num_points as f64 * 100.0
+                );
+            }
+        }
+
+        let timestamps: Vec<u64> = self.frames.keys().copied().collect();
+        let mut intervals: Vec<f64> = timestamps
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as f64 / 1e9)
+            .collect();
+
+        if !intervals.is_empty() {
+            let span = (timestamps[timestamps.len() - 1] - timestamps[0]) as f64 / 1e9;
+            println!("Frame rate: {:.2} Hz", intervals.len() as f64 / span);
+
+            let largest = intervals.iter().copied().fold(0.0, f64::max);
+            intervals.sort_by(f64::total_cmp);
+            let median = intervals[intervals.len() / 2];
+            let gaps = intervals
+                .iter()
+                .filter(|&&interval| interval > median * GAP_FACTOR)
+                .count();
+
+            println!("Gaps: {} (largest interval {:.3} s)", gaps, largest);
+        }
+
+        let (received, total, sidecars) = self
+            .frames
+            .values()
+            .chain(&self.untimed)
+            .filter_map(|frame| frame.columns)
+            .fold((0, 0, 0), |(received, total, sidecars), columns| {
+                (received + columns.0, total + columns.1, sidecars + 1)
+            });
+
+        if total > 0 {
+            println!(
+                "Columns received: {:.2}% ({} frames with timestamps)",
+                received as f64 / total as f64 * 100.0,
+                sidecars
+            );
+        }
+    }
+}
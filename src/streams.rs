@@ -0,0 +1,65 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct StreamConfig {
+    meta: PathBuf,
+    output: PathBuf,
+}
+
+/// A lidar stream of the capture, parsed into its own output directory.
+pub struct Stream {
+    /// Destination port, or the metadata's udp_port_lidar if not given
+    pub port: Option<u16>,
+    pub meta: PathBuf,
+    pub output: PathBuf,
+}
+
+/// Read the streams of a json file mapping ports to metadata and output
+/// directories, e.g. `{"7502": {"meta": "a.json", "output": "a"}}`.
+///
+/// Relative paths are taken from the directory of the file.
+pub fn load(path: &Path) -> Result<Vec<Stream>, String> {
+    let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let config: BTreeMap<u16, StreamConfig> =
+        serde_json::from_str(&text).map_err(|err| err.to_string())?;
+
+    if config.is_empty() {
+        return Err("no streams given".to_string());
+    }
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    Ok(config
+        .into_iter()
+        .map(|(port, stream)| Stream {
+            port: Some(port),
+            meta: dir.join(stream.meta),
+            output: dir.join(stream.output),
+        })
+        .collect())
+}
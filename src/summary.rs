@@ -0,0 +1,155 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{fs, path::Path};
+
+use serde::Serialize;
+
+use crate::{calibration::Calibration, ouster::Stats};
+
+/// Bumped whenever a field changes meaning; new fields don't bump it, as
+/// comparisons only look at fields both summaries have.
+const SCHEMA_VERSION: u32 = 1;
+
+/// Health of a whole run, summed over its streams.
+#[derive(Default, Serialize)]
+pub struct Summary {
+    version: u32,
+    /// Interrupted before the end of its input
+    cancelled: bool,
+    frames: usize,
+    /// Frame_ids missing between the first and last frame written
+    missing_frames: usize,
+    discarded_frames: usize,
+    /// Missing frames as a percentage of all frames in the run's span
+    loss_percent: f64,
+    packets: usize,
+    broken_packets: usize,
+    invalid_columns: usize,
+    non_udp_packets: usize,
+    rejected_fragments: usize,
+    length_mismatches: usize,
+    time_discontinuities: usize,
+    reconfigurations: usize,
+    /// Returns parsed from datagrams that lost a fragment
+    recovered_points: usize,
+    /// Beam angle overrides applied, with their values
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    calibration: Vec<Calibration>,
+}
+
+impl Summary {
+    pub fn new(
+        non_udp_packets: usize,
+        rejected_fragments: usize,
+        length_mismatches: usize,
+    ) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            non_udp_packets,
+            rejected_fragments,
+            length_mismatches,
+            ..Self::default()
+        }
+    }
+
+    /// Add the counts of one stream.
+    pub fn add_stream(&mut self, stats: &Stats, missing_frames: &[(u16, u16)]) {
+        self.frames += stats.frames;
+        self.discarded_frames += stats.discarded_frames;
+        self.packets += stats.packets;
+        self.broken_packets += stats.broken_packets;
+        self.invalid_columns += stats.invalid_columns;
+        self.time_discontinuities += stats.time_discontinuities;
+        self.reconfigurations += stats.reconfigurations;
+        self.recovered_points += stats.recovered_points;
+        self.missing_frames += missing_frames
+            .iter()
+            .map(|&(first, last)| last.wrapping_sub(first) as usize + 1)
+            .sum::<usize>();
+
+        let span = self.frames + self.missing_frames;
+        self.loss_percent = if span > 0 {
+            self.missing_frames as f64 / span as f64 * 100.0
+        } else {
+            0.0
+        };
+    }
+
+    /// Record that the run was interrupted, its counts covering only
+    /// the input read until then.
+    pub fn set_cancelled(&mut self) {
+        self.cancelled = true;
+    }
+
+    /// Record a calibration applied to every stream.
+    pub fn add_calibration(&mut self, calibration: &Calibration) {
+        self.calibration.push(calibration.clone());
+    }
+
+    pub fn save(&self, path: &Path) {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+
+    /// Print how every field both summaries have changed since the
+    /// baseline, returning false if loss grew by more than
+    /// `max_loss_increase` percentage points.
+    pub fn compare(&self, baseline: &Path, max_loss_increase: f64) -> bool {
+        let baseline: serde_json::Value = match fs::read(baseline)
+            .map_err(|err| err.to_string())
+            .and_then(|data| serde_json::from_slice(&data).map_err(|err| err.to_string()))
+        {
+            Ok(serde_json::Value::Object(baseline)) => serde_json::Value::Object(baseline),
+            Ok(_) => {
+                eprintln!("Invalid baseline summary. (not a json object)");
+                return false;
+            }
+            Err(err) => {
+                eprintln!("Invalid baseline summary. ({})", err);
+                return false;
+            }
+        };
+
+        let current = serde_json::to_value(self).unwrap();
+        let current = current.as_object().unwrap();
+
+        println!("Compared to baseline summary:");
+
+        for (key, value) in current {
+            if key == "version" {
+                continue;
+            }
+
+            if let (Some(new), Some(old)) = (value.as_f64(), baseline[key].as_f64()) {
+                println!("  {}: {} -> {} ({:+})", key, old, new, new - old);
+            }
+        }
+
+        match baseline["loss_percent"].as_f64() {
+            Some(old) if self.loss_percent - old > max_loss_increase => {
+                eprintln!(
+                    "Frame loss worsened beyond the threshold. ({:.2}% -> {:.2}%, at most {:+.2} points allowed)",
+                    old, self.loss_percent, max_loss_increase
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+}
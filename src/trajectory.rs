@@ -0,0 +1,265 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{fs, path::Path};
+
+use crate::ouster::OusterError;
+
+/// A sensor pose, rotating then translating points into the map frame.
+#[derive(Clone, Copy)]
+struct Pose {
+    translation: [f64; 3],
+    /// Unit quaternion as `x y z w`
+    rotation: [f64; 4],
+}
+
+/// Keys poses are looked up by.
+enum Stamps {
+    /// TUM poses by time in seconds, ascending
+    Time(Vec<f64>),
+    /// KITTI poses by output frame number
+    Index,
+}
+
+/// Sensor poses of a recording, as written by SLAM tools.
+///
+/// TUM files have `time tx ty tz qx qy qz qw` rows and are interpolated at
+/// the frame timestamp; KITTI files have a row-major 3x4 matrix per row and
+/// give the pose of the frame with the row's number.
+pub struct Trajectory {
+    stamps: Stamps,
+    poses: Vec<Pose>,
+    /// Farthest a frame may be from its nearest pose, in seconds
+    tolerance: f64,
+}
+
+impl Trajectory {
+    pub fn load(path: &Path, tolerance: f64) -> Result<Self, OusterError> {
+        let error = |err: String| OusterError::Config(format!("{}: {}", path.display(), err));
+        let text = fs::read_to_string(path).map_err(|err| error(err.to_string()))?;
+
+        let mut times = Vec::new();
+        let mut poses = Vec::new();
+        let mut kitti = None;
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values: Vec<f64> = line
+                .split_whitespace()
+                .map(|value| value.parse::<f64>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| error(format!("malformed trajectory row {:?}", line)))?;
+
+            let is_kitti = match values.len() {
+                8 => false,
+                12 => true,
+                _ => return Err(error(format!("malformed trajectory row {:?}", line))),
+            };
+
+            if *kitti.get_or_insert(is_kitti) != is_kitti {
+                return Err(error(format!("mixed TUM and KITTI rows at {:?}", line)));
+            }
+
+            if is_kitti {
+                poses.push(Pose::from_matrix(&values));
+            } else {
+                times.push(values[0]);
+                poses.push(Pose {
+                    translation: [values[1], values[2], values[3]],
+                    rotation: normalize([values[4], values[5], values[6], values[7]]),
+                });
+            }
+        }
+
+        if kitti == Some(false) && times.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(error("timestamps are not ascending".to_string()));
+        }
+
+        Ok(Self {
+            stamps: if kitti == Some(true) {
+                Stamps::Index
+            } else {
+                Stamps::Time(times)
+            },
+            poses,
+            tolerance,
+        })
+    }
+
+    /// Move a point of the frame with number `id` starting at `timestamp`
+    /// into the map frame, or `None` if there is no pose close enough.
+    pub fn transformer(&self, id: usize, timestamp: f64) -> Option<impl Fn(&[f32]) -> [f32; 3]> {
+        let pose = match &self.stamps {
+            Stamps::Index => *self.poses.get(id)?,
+            Stamps::Time(times) => self.interpolate(times, timestamp)?,
+        };
+
+        Some(move |point: &[f32]| pose.apply(point))
+    }
+
+    fn interpolate(&self, times: &[f64], timestamp: f64) -> Option<Pose> {
+        let next = times.partition_point(|&time| time < timestamp);
+
+        let nearest = [next.checked_sub(1), Some(next)]
+            .into_iter()
+            .flatten()
+            .filter(|&index| index < times.len())
+            .map(|index| (timestamp - times[index]).abs())
+            .fold(f64::INFINITY, f64::min);
+
+        if nearest > self.tolerance {
+            return None;
+        }
+
+        // before the first or after the last pose, within the tolerance
+        if next == 0 || next == times.len() {
+            return Some(self.poses[next.min(times.len() - 1)]);
+        }
+
+        let (t0, t1) = (times[next - 1], times[next]);
+        let ratio = if t1 > t0 {
+            (timestamp - t0) / (t1 - t0)
+        } else {
+            0.0
+        };
+
+        Some(self.poses[next - 1].interpolate(&self.poses[next], ratio))
+    }
+}
+
+impl Pose {
+    fn from_matrix(m: &[f64]) -> Self {
+        // rotation matrix to quaternion, picking the best-conditioned term
+        let trace = m[0] + m[5] + m[10];
+
+        let rotation = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            [
+                (m[9] - m[6]) / s,
+                (m[2] - m[8]) / s,
+                (m[4] - m[1]) / s,
+                0.25 * s,
+            ]
+        } else if m[0] > m[5] && m[0] > m[10] {
+            let s = (1.0 + m[0] - m[5] - m[10]).sqrt() * 2.0;
+            [
+                0.25 * s,
+                (m[1] + m[4]) / s,
+                (m[2] + m[8]) / s,
+                (m[9] - m[6]) / s,
+            ]
+        } else if m[5] > m[10] {
+            let s = (1.0 + m[5] - m[0] - m[10]).sqrt() * 2.0;
+            [
+                (m[1] + m[4]) / s,
+                0.25 * s,
+                (m[6] + m[9]) / s,
+                (m[2] - m[8]) / s,
+            ]
+        } else {
+            let s = (1.0 + m[10] - m[0] - m[5]).sqrt() * 2.0;
+            [
+                (m[2] + m[8]) / s,
+                (m[6] + m[9]) / s,
+                0.25 * s,
+                (m[4] - m[1]) / s,
+            ]
+        };
+
+        Self {
+            translation: [m[3], m[7], m[11]],
+            rotation: normalize(rotation),
+        }
+    }
+
+    /// Linear in translation, spherical in rotation.
+    fn interpolate(&self, other: &Pose, ratio: f64) -> Pose {
+        let mut translation = [0.0; 3];
+        for (i, value) in translation.iter_mut().enumerate() {
+            *value = self.translation[i] + (other.translation[i] - self.translation[i]) * ratio;
+        }
+
+        let a = self.rotation;
+        let mut b = other.rotation;
+        let mut dot: f64 = a.iter().zip(&b).map(|(a, b)| a * b).sum();
+
+        // take the short way around
+        if dot < 0.0 {
+            b = b.map(|value| -value);
+            dot = -dot;
+        }
+
+        let (wa, wb) = if dot > 0.9995 {
+            (1.0 - ratio, ratio)
+        } else {
+            let theta = dot.acos();
+            (
+                ((1.0 - ratio) * theta).sin() / theta.sin(),
+                (ratio * theta).sin() / theta.sin(),
+            )
+        };
+
+        let mut rotation = [0.0; 4];
+        for (i, value) in rotation.iter_mut().enumerate() {
+            *value = wa * a[i] + wb * b[i];
+        }
+
+        Pose {
+            translation,
+            rotation: normalize(rotation),
+        }
+    }
+
+    fn apply(&self, point: &[f32]) -> [f32; 3] {
+        let [x, y, z, w] = self.rotation;
+        let p = [point[0] as f64, point[1] as f64, point[2] as f64];
+
+        // p + 2w (q x p) + 2 q x (q x p)
+        let c = [
+            y * p[2] - z * p[1],
+            z * p[0] - x * p[2],
+            x * p[1] - y * p[0],
+        ];
+        let cc = [
+            y * c[2] - z * c[1],
+            z * c[0] - x * c[2],
+            x * c[1] - y * c[0],
+        ];
+
+        let mut out = [0.0; 3];
+        for (i, value) in out.iter_mut().enumerate() {
+            *value = (p[i] + 2.0 * w * c[i] + 2.0 * cc[i] + self.translation[i]) as f32;
+        }
+
+        out
+    }
+}
+
+fn normalize(q: [f64; 4]) -> [f64; 4] {
+    let norm = q.iter().map(|value| value * value).sum::<f64>().sqrt();
+
+    if norm > 0.0 {
+        q.map(|value| value / norm)
+    } else {
+        [0.0, 0.0, 0.0, 1.0]
+    }
+}
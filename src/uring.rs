@@ -0,0 +1,284 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs::{self, File},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::sink::{pcd_path, write_pcd, Frame, FrameSink, PcdFormat};
+
+/// Writes one binary PCD file per frame like `PcdSink`, submitting the
+/// writes through io_uring so that up to `queue_depth` files are in
+/// flight at once instead of one write syscall after another.
+///
+/// Each file is still written aside and renamed into place once its
+/// write completes.
+pub struct UringPcdSink {
+    output_path: PathBuf,
+    shards: usize,
+    format: PcdFormat,
+    ring: IoUring,
+    /// Files being written, indexed by the user_data of their write
+    slots: Vec<Option<PendingFile>>,
+    num_in_flight: usize,
+    /// Buffers of completed writes, to serialize the next frames into
+    buffers: Vec<Vec<u8>>,
+}
+
+/// A PCD file whose write hasn't completed yet.
+struct PendingFile {
+    /// Frame name, to attribute a failed write to
+    name: PathBuf,
+    file: File,
+    data: Vec<u8>,
+    written: usize,
+    tmp_path: PathBuf,
+    file_path: PathBuf,
+}
+
+impl UringPcdSink {
+    /// Fails where the kernel lacks io_uring support.
+    pub fn new(
+        output_path: &Path,
+        shards: usize,
+        format: PcdFormat,
+        queue_depth: u32,
+    ) -> io::Result<Self> {
+        let ring = IoUring::new(queue_depth)?;
+
+        Ok(Self {
+            output_path: output_path.to_path_buf(),
+            shards,
+            format,
+            ring,
+            slots: (0..queue_depth).map(|_| None).collect(),
+            num_in_flight: 0,
+            buffers: Vec::new(),
+        })
+    }
+
+    /// Queue the write of what's left of the file in `slot`.
+    fn push_write(&mut self, slot: usize) -> io::Result<()> {
+        let pending = self.slots[slot].as_ref().unwrap();
+        let rest = &pending.data[pending.written..];
+
+        let entry = opcode::Write::new(
+            types::Fd(pending.file.as_raw_fd()),
+            rest.as_ptr(),
+            rest.len() as u32,
+        )
+        .offset(pending.written as u64)
+        .build()
+        .user_data(slot as u64);
+
+        // the slot keeps the file and buffer alive until the write completes
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+
+        Ok(())
+    }
+
+    /// Submit the writes queued, wait for at least `wait` of them to
+    /// complete and put the complete files into place.
+    fn complete(&mut self, wait: usize) -> io::Result<()> {
+        self.ring.submit_and_wait(wait)?;
+        self.reap()
+    }
+
+    fn reap(&mut self) -> io::Result<()> {
+        let completions: Vec<(usize, i32)> = self
+            .ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+
+        // every completion is handled, so that no slot waits on a write
+        // that already came back, and the first error returned
+        let mut first_err = None;
+
+        for (slot, result) in completions {
+            let name = self.slots[slot].as_ref().unwrap().name.clone();
+
+            if let Err(err) = self.complete_write(slot, result) {
+                if self.slots[slot].take().is_some() {
+                    self.num_in_flight -= 1;
+                }
+
+                // completions come back out of order, so errors name the
+                // frame they belong to rather than the one being written
+                first_err.get_or_insert(io::Error::new(
+                    err.kind(),
+                    format!("write of {} failed: {}", name.display(), err),
+                ));
+            }
+        }
+
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Account for a write of the file in `slot` returning `result`,
+    /// writing the rest or renaming the file into place.
+    fn complete_write(&mut self, slot: usize, result: i32) -> io::Result<()> {
+        let pending = self.slots[slot].as_mut().unwrap();
+
+        match result {
+            ..=-1 => return Err(io::Error::from_raw_os_error(-result)),
+            0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            written => pending.written += written as usize,
+        }
+
+        if pending.written < pending.data.len() {
+            return self.push_write(slot);
+        }
+
+        let pending = self.slots[slot].take().unwrap();
+        self.num_in_flight -= 1;
+        self.buffers.push(pending.data);
+        drop(pending.file);
+        fs::rename(pending.tmp_path, pending.file_path)
+    }
+}
+
+impl FrameSink for UringPcdSink {
+    fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        let file_path = self.output_path.join(pcd_path(frame, self.shards));
+
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp_path = file_path.with_extension("pcd.tmp");
+
+        let mut data = self.buffers.pop().unwrap_or_default();
+        data.clear();
+        write_pcd(&mut data, frame, &self.format)?;
+
+        while self.num_in_flight == self.slots.len() {
+            self.complete(1)?;
+        }
+
+        let slot = self.slots.iter().position(Option::is_none).unwrap();
+        self.slots[slot] = Some(PendingFile {
+            name: frame.name.clone(),
+            file: File::create(&tmp_path)?,
+            data,
+            written: 0,
+            tmp_path,
+            file_path,
+        });
+        self.num_in_flight += 1;
+
+        self.push_write(slot)?;
+        self.complete(0)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        while self.num_in_flight > 0 {
+            self.complete(1)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UringPcdSink {
+    fn drop(&mut self) {
+        // the kernel may still read the buffers of the writes in flight,
+        // e.g. when the writer stopped at an error
+        while self.num_in_flight > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                break;
+            }
+            let _ = self.reap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ouster::CoordinateFrame, sink::PcdSink};
+
+    fn frame(id: usize, num_points: usize) -> Frame {
+        Frame {
+            id,
+            frame_id: id as u16,
+            timestamp: id as u64 * 100_000_000,
+            end_timestamp: id as u64 * 100_000_000 + 99_000_000,
+            coordinate_frame: CoordinateFrame::Lidar,
+            name: PathBuf::from(format!("{:04}", id)),
+            rgb: false,
+            beam_angles: false,
+            range_difference: false,
+            completeness: 1.0,
+            segment: 0,
+            point_stride: 1,
+            sampling: None,
+            column_timestamps: Vec::new(),
+            excluded_range: 0,
+            excluded_reflectivity: 0,
+            discarded: None,
+            points: (0..num_points * 4).map(|value| value as f32).collect(),
+        }
+    }
+
+    #[test]
+    fn writes_the_same_files_as_the_std_sink() {
+        let root = std::env::temp_dir().join(format!("ouster-parser-uring-{}", std::process::id()));
+        let format = PcdFormat {
+            intensity_name: "intensity".to_string(),
+            comments: true,
+            metadata: Vec::new(),
+            ascii: false,
+        };
+
+        // more frames than the queue holds, over two shards
+        let mut uring = match UringPcdSink::new(&root.join("uring"), 2, format.clone(), 4) {
+            Ok(uring) => uring,
+            Err(err) => {
+                eprintln!("Skip without io_uring. ({})", err);
+                return;
+            }
+        };
+        let mut std = PcdSink::new(&root.join("std"), 2, format);
+
+        for id in 0..10 {
+            let frame = frame(id, 1000 * id);
+            uring.write_frame(&frame).unwrap();
+            std.write_frame(&frame).unwrap();
+        }
+        uring.finish().unwrap();
+
+        for id in 0..10 {
+            let path = Path::new(&format!("shard{}", id % 2)).join(format!("{:04}.pcd", id));
+            let written = fs::read(root.join("uring").join(&path)).unwrap();
+            assert_eq!(written, fs::read(root.join("std").join(&path)).unwrap());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
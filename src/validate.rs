@@ -0,0 +1,165 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{fs::File, path::Path, process, time::Instant};
+
+use crate::{
+    capture::{for_each_packet, CaptureRange},
+    cli::exit,
+    ouster::{Legacy, UdpProfile},
+    receiver::{FragmentCounts, Receiver},
+    sequence::IPV4Seq,
+};
+
+/// Bytes of the capture read by --validate-only without --limit-bytes.
+pub const VALIDATE_BYTES: usize = 64 << 20;
+
+/// Parse the start of a capture without writing anything, and report
+/// whether its lidar packets make up frames.
+pub fn run(
+    meta: &Path,
+    port: Option<u16>,
+    udp_profile: Option<UdpProfile>,
+    data: &[u8],
+    range: &CaptureRange,
+    strict: bool,
+) -> bool {
+    let json_file = File::open(meta).unwrap();
+
+    let mut parser = match Legacy::builder()
+        .metadata(json_file)
+        .udp_profile(udp_profile)
+        .build()
+    {
+        Ok(parser) => parser,
+        Err(err) => {
+            println!("Metadata: FAIL ({})", err);
+            println!("Result: FAIL");
+            return false;
+        }
+    };
+
+    println!("Metadata: ok");
+    println!("Profile: {}", parser.udp_profile().name());
+
+    let port = match port.or(parser.udp_port_lidar()) {
+        Some(port) => port,
+        None => {
+            println!("Port: FAIL (neither --port nor udp_port_lidar in metadata)");
+            println!("Result: FAIL");
+            return false;
+        }
+    };
+
+    println!("Port: {}", port);
+
+    let mut receiver = Receiver::new(vec![port], IPV4Seq::new(strict));
+
+    for_each_packet(data, range, |packet| {
+        if packet.is_truncated() {
+            return;
+        }
+
+        if let Some((_, data)) = receiver.receive(packet) {
+            parser.put(&data);
+        }
+    });
+
+    let stats = parser.stats();
+    if let Err(err) = parser.finish() {
+        eprintln!("Failed to write frames. ({})", err);
+        exit(1);
+    }
+
+    let broken_rate = if stats.packets > 0 {
+        stats.broken_packets as f64 / stats.packets as f64 * 100.0
+    } else {
+        0.0
+    };
+
+    println!("Lidar packets: {}", stats.packets);
+    println!(
+        "Broken packets: {} ({:.2}%, {} invalid columns)",
+        stats.broken_packets, broken_rate, stats.invalid_columns
+    );
+    println!("Complete frames: {}", stats.frames);
+    println!(
+        "Excluded returns: {} by range, {} by reflectivity",
+        stats.excluded_range, stats.excluded_reflectivity
+    );
+    println!("Timestamp discontinuities: {}", stats.time_discontinuities);
+    println!(
+        "Peak frame buffers: {:.1} MiB",
+        stats.peak_frame_bytes as f64 / (1 << 20) as f64
+    );
+
+    if let Some(err) = receiver.error() {
+        println!("Reassembly: FAIL ({})", err);
+    }
+
+    let ok = stats.packets > 0 && stats.frames > 0 && receiver.error().is_none();
+    println!("Result: {}", if ok { "PASS" } else { "FAIL" });
+
+    ok
+}
+
+/// Time the reassembly of every fragment of a capture.
+pub fn benchmark_reassembly(data: &[u8], strict: bool) {
+    // no lidar ports, as only reassembly is timed
+    let mut receiver = Receiver::new(Vec::new(), IPV4Seq::new(strict));
+
+    let start = Instant::now();
+
+    for_each_packet(data, &CaptureRange::default(), |packet| {
+        if packet.is_truncated() {
+            return;
+        }
+
+        receiver.receive(packet);
+    });
+
+    if let Some(err) = receiver.error() {
+        eprintln!("Abort on malformed ipv4 fragment. ({})", err);
+        process::exit(1);
+    }
+
+    let FragmentCounts {
+        fragments: num_fragments,
+        started: num_started,
+        completed: num_completed,
+    } = receiver.fragment_counts();
+
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = if num_started > 0 {
+        num_completed as f64 / num_started as f64 * 100.0
+    } else {
+        0.0
+    };
+
+    println!("Elapsed: {:.3} s", elapsed);
+    println!(
+        "Fragments: {} ({:.0} fragments/sec)",
+        num_fragments,
+        num_fragments as f64 / elapsed
+    );
+    println!(
+        "Datagrams: {} started, {} completed ({:.2}%)",
+        num_started, num_completed, rate
+    );
+}
@@ -0,0 +1,221 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The parts of a PCD header written by this tool that are read back.
+pub struct PcdHeader {
+    pub fields: Vec<String>,
+    /// Bytes of each field of a point
+    pub sizes: Vec<usize>,
+    pub counts: Vec<usize>,
+    pub num_points: usize,
+    /// Sensor timestamp in nanoseconds, from the `# timestamp:` comment
+    pub timestamp: Option<u64>,
+    /// Whether the file is a placeholder of a discarded frame
+    pub discarded: bool,
+    /// Whether the body is rows of text rather than binary
+    pub ascii: bool,
+    /// Offset of the body in the file
+    pub body_offset: usize,
+}
+
+impl PcdHeader {
+    pub fn point_size(&self) -> usize {
+        self.sizes
+            .iter()
+            .zip(&self.counts)
+            .map(|(size, count)| size * count)
+            .sum()
+    }
+
+    /// Values of an ascii body, checked to hold a row of every field for
+    /// each point.
+    pub fn ascii_values(&self, body: &[u8]) -> Result<Vec<f32>, String> {
+        let text = std::str::from_utf8(body).map_err(|_| "body is not text".to_string())?;
+        let row_len: usize = self.counts.iter().sum();
+
+        let mut values = Vec::with_capacity(self.num_points * row_len);
+        let mut num_rows = 0;
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let len = values.len();
+            for word in line.split_whitespace() {
+                values.push(
+                    word.parse()
+                        .map_err(|_| format!("bad value {} in row {}", word, num_rows))?,
+                );
+            }
+
+            if values.len() - len != row_len {
+                return Err(format!(
+                    "row {} has {} values, {} expected",
+                    num_rows,
+                    values.len() - len,
+                    row_len
+                ));
+            }
+            num_rows += 1;
+        }
+
+        if num_rows != self.num_points {
+            return Err(format!(
+                "body has {} rows, {} points expected",
+                num_rows, self.num_points
+            ));
+        }
+
+        Ok(values)
+    }
+}
+
+/// Every PCD file under `path`, or `path` itself if it's a file, in path order.
+pub fn pcd_files(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    let mut files = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            files.append(&mut pcd_files(&entry));
+        } else if entry.extension().is_some_and(|ext| ext == "pcd") {
+            files.push(entry);
+        }
+    }
+
+    files
+}
+
+/// Re-reads every PCD file under `path` and returns the broken ones.
+///
+/// A file is broken if its header doesn't parse or its body doesn't hold
+/// exactly `POINTS` points of the declared fields.
+pub fn verify_pcd(path: &Path) -> Vec<(PathBuf, String)> {
+    pcd_files(path)
+        .into_iter()
+        .filter_map(|file| verify_pcd_file(&file).err().map(|err| (file, err)))
+        .collect()
+}
+
+fn verify_pcd_file(path: &Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|err| err.to_string())?;
+    let header = parse_pcd_header(&data)?;
+
+    if header.ascii {
+        return header.ascii_values(&data[header.body_offset..]).map(|_| ());
+    }
+
+    let body_len = data.len() - header.body_offset;
+
+    if body_len != header.num_points * header.point_size() {
+        return Err(format!(
+            "body is {} bytes, {} points of {} bytes expected",
+            body_len,
+            header.num_points,
+            header.point_size()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parses the header of a binary or ascii PCD file.
+pub fn parse_pcd_header(data: &[u8]) -> Result<PcdHeader, String> {
+    let mut fields: Option<Vec<String>> = None;
+    let mut point_size = None;
+    let mut counts = None;
+    let mut num_points = None;
+    let mut timestamp = None;
+    let mut discarded = false;
+    let mut ascii = false;
+    let mut offset = 0;
+
+    loop {
+        let line_len = match data[offset..].iter().position(|&byte| byte == b'\n') {
+            Some(line_len) => line_len,
+            None => return Err("header has no DATA line".to_string()),
+        };
+
+        let line = String::from_utf8_lossy(&data[offset..offset + line_len]).into_owned();
+        offset += line_len + 1;
+
+        let mut words = line.split_whitespace();
+        let values = |words: std::str::SplitWhitespace| -> Result<Vec<usize>, String> {
+            words
+                .map(|word| {
+                    word.parse()
+                        .map_err(|_| format!("bad header line {}", line))
+                })
+                .collect()
+        };
+
+        match words.next() {
+            Some("#") => match words.next() {
+                Some("timestamp:") => timestamp = words.next().and_then(|word| word.parse().ok()),
+                Some("discarded:") => discarded = true,
+                _ => (),
+            },
+            Some("FIELDS") => fields = Some(words.map(str::to_string).collect()),
+            Some("SIZE") => point_size = Some(values(words)?),
+            Some("COUNT") => counts = Some(values(words)?),
+            Some("POINTS") => num_points = values(words)?.first().copied(),
+            Some("DATA") => {
+                match words.next() {
+                    Some("binary") => (),
+                    Some("ascii") => ascii = true,
+                    _ => return Err("body is neither binary nor ascii".to_string()),
+                }
+                break;
+            }
+            _ => (),
+        }
+    }
+
+    let (fields, sizes, counts, num_points) = match (fields, point_size, counts, num_points) {
+        (Some(fields), Some(sizes), Some(counts), Some(num_points)) => {
+            (fields, sizes, counts, num_points)
+        }
+        _ => return Err("header lacks FIELDS, SIZE, COUNT or POINTS".to_string()),
+    };
+
+    if sizes.len() != counts.len() || sizes.len() != fields.len() {
+        return Err("FIELDS, SIZE and COUNT differ in length".to_string());
+    }
+
+    Ok(PcdHeader {
+        fields,
+        sizes,
+        counts,
+        num_points,
+        timestamp,
+        discarded,
+        ascii,
+        body_offset: offset,
+    })
+}
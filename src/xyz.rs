@@ -0,0 +1,285 @@
+/*  This file is part of ouster-parser.
+ *
+ *  assfonts is free software: you can redistribute it and/or
+ *  modify it under the terms of the GNU General Public License
+ *  as published by the Free Software Foundation,
+ *  either version 3 of the License,
+ *  or (at your option) any later version.
+ *
+ *  assfonts is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty
+ *  of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+ *  See the GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public
+ *  License along with assfonts. If not, see <https://www.gnu.org/licenses/>.
+ *
+ *  written by wyzdwdz (https://github.com/wyzdwdz)
+ */
+
+use std::f32::consts::PI;
+
+/// Trig tables of the beams of a sensor, turning ranges into points.
+///
+/// The beams of a column share its encoder angle, so with the angle sum
+/// formulas each point only takes multiply-adds. With the `simd` feature
+/// a column is computed four beams at a time in vector lanes on x86_64.
+pub struct BeamGeometry {
+    /// Distance from the lidar origin to the beam origin in millimeters
+    n: f32,
+    /// Offsets of the beam origin along x and z in millimeters
+    beam_x: f32,
+    beam_z: f32,
+    cos_azimuths: Vec<f32>,
+    sin_azimuths: Vec<f32>,
+    cos_altitudes: Vec<f32>,
+    sin_altitudes: Vec<f32>,
+    /// Point subtracted from every point, in meters in the lidar frame
+    origin: [f32; 3],
+}
+
+/// Coordinates in meters of the points of a column, by beam.
+#[derive(Default)]
+pub struct ColumnXyz {
+    pub x: Vec<f32>,
+    pub y: Vec<f32>,
+    pub z: Vec<f32>,
+}
+
+impl BeamGeometry {
+    /// Tables of beams at angles in degrees, with `beam_to_lidar` the
+    /// row-major transform of the metadata.
+    pub fn new(
+        beam_azimuth_angles: &[f32],
+        beam_altitude_angles: &[f32],
+        beam_to_lidar: &[f32],
+        origin: [f32; 3],
+    ) -> Self {
+        let radians = |degrees: &f32| 2.0 * PI * (degrees / 360.0);
+
+        // azimuth angles turn clockwise, against the encoder
+        let azimuths: Vec<f32> = beam_azimuth_angles.iter().map(|x| -radians(x)).collect();
+
+        Self {
+            n: (beam_to_lidar[3].powi(2) + beam_to_lidar[11].powi(2)).sqrt(),
+            beam_x: beam_to_lidar[3],
+            beam_z: beam_to_lidar[11],
+            cos_azimuths: azimuths.iter().map(|x| x.cos()).collect(),
+            sin_azimuths: azimuths.iter().map(|x| x.sin()).collect(),
+            cos_altitudes: beam_altitude_angles
+                .iter()
+                .map(|x| radians(x).cos())
+                .collect(),
+            sin_altitudes: beam_altitude_angles
+                .iter()
+                .map(|x| radians(x).sin())
+                .collect(),
+            origin,
+        }
+    }
+
+    /// The point of a return of `range` millimeters of `beam`, at the
+    /// `encoder` angle in radians.
+    pub fn point(&self, encoder: f32, beam: usize, range: f32) -> [f32; 3] {
+        let (sin_encoder, cos_encoder) = encoder.sin_cos();
+        self.beam_point(cos_encoder, sin_encoder, beam, range)
+    }
+
+    /// The points of returns of `ranges` millimeters, of the beams from
+    /// the first on, at the `encoder` angle in radians.
+    pub fn column(&self, encoder: f32, ranges: &[f32], xyz: &mut ColumnXyz) {
+        xyz.resize(ranges.len());
+
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        {
+            let lanes = self.column_sse(encoder, ranges, xyz);
+            self.beams(encoder, lanes, &ranges[lanes..], xyz);
+        }
+
+        #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+        self.beams(encoder, 0, ranges, xyz);
+    }
+
+    /// `column` one beam at a time, which the vector lanes are checked
+    /// and benchmarked against.
+    pub fn column_scalar(&self, encoder: f32, ranges: &[f32], xyz: &mut ColumnXyz) {
+        xyz.resize(ranges.len());
+        self.beams(encoder, 0, ranges, xyz);
+    }
+
+    /// Points of the beams from `first` on, into the same places of `xyz`.
+    fn beams(&self, encoder: f32, first: usize, ranges: &[f32], xyz: &mut ColumnXyz) {
+        let (sin_encoder, cos_encoder) = encoder.sin_cos();
+
+        for (beam, &range) in (first..).zip(ranges) {
+            let [x, y, z] = self.beam_point(cos_encoder, sin_encoder, beam, range);
+            xyz.x[beam] = x;
+            xyz.y[beam] = y;
+            xyz.z[beam] = z;
+        }
+    }
+
+    #[inline]
+    fn beam_point(&self, cos_encoder: f32, sin_encoder: f32, beam: usize, range: f32) -> [f32; 3] {
+        // cos and sin of the encoder plus the beam azimuth
+        let cos = cos_encoder * self.cos_azimuths[beam] - sin_encoder * self.sin_azimuths[beam];
+        let sin = sin_encoder * self.cos_azimuths[beam] + cos_encoder * self.sin_azimuths[beam];
+        let r = range - self.n;
+
+        [
+            (r * cos * self.cos_altitudes[beam] + self.beam_x * cos_encoder) * 0.001
+                - self.origin[0],
+            (r * sin * self.cos_altitudes[beam] + self.beam_x * sin_encoder) * 0.001
+                - self.origin[1],
+            (r * self.sin_altitudes[beam] + self.beam_z) * 0.001 - self.origin[2],
+        ]
+    }
+
+    /// `beams` over the lanes of sse registers, part of x86_64, in the
+    /// same order of operations. Returns the number of beams computed,
+    /// leaving fewer than four.
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    fn column_sse(&self, encoder: f32, ranges: &[f32], xyz: &mut ColumnXyz) -> usize {
+        use std::arch::x86_64::*;
+
+        let (sin_encoder, cos_encoder) = encoder.sin_cos();
+        let lanes = ranges.len() / 4 * 4;
+
+        // every slice read or written holds at least `lanes` values
+        assert!(self.cos_azimuths.len() >= lanes && xyz.x.len() >= lanes);
+
+        unsafe {
+            let cos_encoder_4 = _mm_set1_ps(cos_encoder);
+            let sin_encoder_4 = _mm_set1_ps(sin_encoder);
+            let offset_x = _mm_set1_ps(self.beam_x * cos_encoder);
+            let offset_y = _mm_set1_ps(self.beam_x * sin_encoder);
+            let beam_z = _mm_set1_ps(self.beam_z);
+            let n = _mm_set1_ps(self.n);
+            let milli = _mm_set1_ps(0.001);
+            let origin = self.origin.map(|origin| _mm_set1_ps(origin));
+
+            for beam in (0..lanes).step_by(4) {
+                let load = |values: &[f32]| _mm_loadu_ps(values.as_ptr().add(beam));
+
+                let cos_azimuth = load(&self.cos_azimuths);
+                let sin_azimuth = load(&self.sin_azimuths);
+                let cos_altitude = load(&self.cos_altitudes);
+                let sin_altitude = load(&self.sin_altitudes);
+
+                let cos = _mm_sub_ps(
+                    _mm_mul_ps(cos_encoder_4, cos_azimuth),
+                    _mm_mul_ps(sin_encoder_4, sin_azimuth),
+                );
+                let sin = _mm_add_ps(
+                    _mm_mul_ps(sin_encoder_4, cos_azimuth),
+                    _mm_mul_ps(cos_encoder_4, sin_azimuth),
+                );
+                let r = _mm_sub_ps(load(ranges), n);
+
+                let planar = |trig, offset| {
+                    let beam = _mm_mul_ps(_mm_mul_ps(r, trig), cos_altitude);
+                    _mm_mul_ps(_mm_add_ps(beam, offset), milli)
+                };
+                let x = _mm_sub_ps(planar(cos, offset_x), origin[0]);
+                let y = _mm_sub_ps(planar(sin, offset_y), origin[1]);
+                let z = _mm_mul_ps(_mm_add_ps(_mm_mul_ps(r, sin_altitude), beam_z), milli);
+                let z = _mm_sub_ps(z, origin[2]);
+
+                _mm_storeu_ps(xyz.x.as_mut_ptr().add(beam), x);
+                _mm_storeu_ps(xyz.y.as_mut_ptr().add(beam), y);
+                _mm_storeu_ps(xyz.z.as_mut_ptr().add(beam), z);
+            }
+        }
+
+        lanes
+    }
+}
+
+impl ColumnXyz {
+    fn resize(&mut self, len: usize) {
+        self.x.resize(len, 0.0);
+        self.y.resize(len, 0.0);
+        self.z.resize(len, 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Beams of an OS1-64 like sensor, fanned out in azimuth and altitude.
+    fn geometry(origin: [f32; 3]) -> BeamGeometry {
+        let azimuths: Vec<f32> = (0..64)
+            .map(|beam| [4.2, 1.4, -1.4, -4.2][beam % 4])
+            .collect();
+        let altitudes: Vec<f32> = (0..64).map(|beam| 16.6 - 0.53 * beam as f32).collect();
+        let mut beam_to_lidar = [0.0; 16];
+        beam_to_lidar[3] = 15.806;
+
+        BeamGeometry::new(&azimuths, &altitudes, &beam_to_lidar, origin)
+    }
+
+    #[test]
+    fn matches_direct_trig_of_each_beam() {
+        let geometry = geometry([0.5, -0.25, 1.0]);
+        let azimuth = |beam: usize| -[4.2f32, 1.4, -1.4, -4.2][beam % 4].to_radians();
+        let altitude = |beam: usize| (16.6 - 0.53 * beam as f32).to_radians();
+
+        // an odd number of beams leaves a tail past the vector lanes
+        let ranges: Vec<f32> = (0..63).map(|beam| 1000.0 + 731.0 * beam as f32).collect();
+        let encoder = 2.0 * PI * (1.0 - 300.0 / 1024.0);
+        let mut xyz = ColumnXyz::default();
+        geometry.column(encoder, &ranges, &mut xyz);
+
+        for (beam, &range) in ranges.iter().enumerate() {
+            let r = range - 15.806;
+            let x = (r * (encoder + azimuth(beam)).cos() * altitude(beam).cos()
+                + 15.806 * encoder.cos())
+                / 1000.0
+                - 0.5;
+            let y = (r * (encoder + azimuth(beam)).sin() * altitude(beam).cos()
+                + 15.806 * encoder.sin())
+                / 1000.0
+                + 0.25;
+            let z = r * altitude(beam).sin() / 1000.0 - 1.0;
+
+            assert!(
+                (xyz.x[beam] - x).abs() < 1e-4,
+                "{beam}: {} != {x}",
+                xyz.x[beam]
+            );
+            assert!(
+                (xyz.y[beam] - y).abs() < 1e-4,
+                "{beam}: {} != {y}",
+                xyz.y[beam]
+            );
+            assert!(
+                (xyz.z[beam] - z).abs() < 1e-4,
+                "{beam}: {} != {z}",
+                xyz.z[beam]
+            );
+            assert_eq!(
+                geometry.point(encoder, beam, range),
+                [xyz.x[beam], xyz.y[beam], xyz.z[beam]]
+            );
+        }
+    }
+
+    #[test]
+    fn vector_lanes_match_scalar_path() {
+        let geometry = geometry([0.0; 3]);
+        let ranges: Vec<f32> = (0..64).map(|beam| 500.0 + 997.0 * beam as f32).collect();
+
+        for measure_id in [0, 1, 511, 1023] {
+            let encoder = 2.0 * PI * (1.0 - measure_id as f32 / 1024.0);
+            let (mut lanes, mut scalar) = (ColumnXyz::default(), ColumnXyz::default());
+            geometry.column(encoder, &ranges, &mut lanes);
+            geometry.column_scalar(encoder, &ranges, &mut scalar);
+
+            // the same operations in the same order, so the same bits
+            assert_eq!(lanes.x, scalar.x);
+            assert_eq!(lanes.y, scalar.y);
+            assert_eq!(lanes.z, scalar.z);
+        }
+    }
+}